@@ -16,8 +16,20 @@ pub enum ApiError {
     NotFound, // 404
     #[error("not acceptable")]
     NotAcceptable, // 406
+    #[error("request timeout")]
+    RequestTimeout, // 408
+    #[error("payload too large")]
+    PayloadTooLarge, // 413
+    #[error("conflict")]
+    Conflict, // 409
+    #[error("too many requests")]
+    TooManyRequests, // 429
     #[error("internal server error")]
     Internal, // 500
+    #[error("service unavailable")]
+    ServiceUnavailable, // 503
+    #[error("insufficient storage")]
+    InsufficientStorage, // 507
 }
 
 impl IntoResponse for ApiError {
@@ -28,7 +40,13 @@ impl IntoResponse for ApiError {
             ApiError::Forbidden=> (StatusCode::FORBIDDEN, "forbidden").into_response(),
             ApiError::NotAcceptable=> (StatusCode::NOT_ACCEPTABLE, "not acceptable").into_response(),
             ApiError::NotFound => (StatusCode::NOT_FOUND, "not found").into_response(),
+            ApiError::RequestTimeout => (StatusCode::REQUEST_TIMEOUT, "request timeout").into_response(),
+            ApiError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "payload too large").into_response(),
+            ApiError::Conflict => (StatusCode::CONFLICT, "conflict").into_response(),
+            ApiError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "too many requests").into_response(),
             ApiError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response(),
+            ApiError::ServiceUnavailable => (StatusCode::SERVICE_UNAVAILABLE, "service unavailable").into_response(),
+            ApiError::InsufficientStorage => (StatusCode::INSUFFICIENT_STORAGE, "insufficient storage").into_response(),
         }
     }
 }
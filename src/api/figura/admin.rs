@@ -0,0 +1,17 @@
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+
+use crate::{auth::RequireAdmin, AppState};
+
+/// Players connected to the WebSocket
+pub async fn online(RequireAdmin(admin): RequireAdmin, State(state): State<AppState>) -> Json<Value> {
+    tracing::info!("{} ({}) requested the online list", admin.uuid, admin.nickname);
+    let online: Vec<Value> = state.session.iter()
+        .map(|session| *session.key())
+        .map(|uuid| {
+            let nickname = state.user_manager.get_by_uuid(&uuid).map(|user| user.nickname.clone());
+            json!({ "uuid": uuid, "nickname": nickname })
+        })
+        .collect();
+    Json(json!({ "count": online.len(), "players": online }))
+}
@@ -1,9 +1,11 @@
+use std::time::Duration;
+
 use axum::{debug_handler, extract::{Query, State}, response::{IntoResponse, Response}, routing::get, Router};
 use reqwest::StatusCode;
 use ring::digest::{self, digest};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{auth::{has_joined, Userinfo}, utils::rand, AppState};
+use crate::{api::figura::{profile::send_event, SessionMessage}, auth::{has_joined, AuthProvider, ProvidersUnreachable, Token, Userinfo}, state::{DuplicateUsernamePolicy, SecondSessionPolicy}, utils::rand, AppState};
 use super::types::auth::*;
 
 pub fn router() -> Router<AppState> {
@@ -17,60 +19,300 @@ async fn id(
     // First stage of authentication
     Query(query): Query<Id>,
     State(state): State<AppState>,
-) -> String {
+) -> Response {
+    let (max, valid) = {
+        let config = state.config.read().await;
+        (config.pending_auth.max_entries, config.usernames.is_valid(&query.username))
+    };
+    if !valid {
+        info!("[Authentication] rejected invalid username {:?}", query.username);
+        return (StatusCode::BAD_REQUEST, "invalid username".to_string()).into_response();
+    }
     let server_id =
         faster_hex::hex_string(&digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &rand()).as_ref()[0..20]);
-    let state = state.user_manager;
-    state.pending_insert(server_id.clone(), query.username);
-    server_id
+    state.user_manager.pending_insert(server_id.clone(), query.username, max);
+    server_id.into_response()
 }
 
 #[debug_handler]
 async fn verify(
     // Second stage of authentication
     Query(query): Query<Verify>,
+    token: Option<Token>,
     State(state): State<AppState>,
 ) -> Response {
     let server_id = query.id.clone();
-    let nickname = state.user_manager.pending_remove(&server_id).unwrap().1; // TODO: Add error check
+    let ttl = Duration::from_secs(state.config.read().await.pending_auth.ttl);
+    let nickname = if let Some(nickname) = state.user_manager.pending_remove(&server_id, ttl) { nickname } else {
+        info!("[Authentication] unknown or expired server id {server_id}");
+        return (StatusCode::BAD_REQUEST, "unknown server id".to_string()).into_response();
+    };
+    let offline_fallback = state.config.read().await.offline_fallback;
     let userinfo = match has_joined(
         State(state.clone()),
         &server_id,
         &nickname
     ).await {
         Ok(d) => d,
-        Err(_e) => {
+        Err(e) => match known_user(&state, &nickname, token.as_ref().map(|Token(token)| token.as_str()), &e, offline_fallback) {
+            Some(known) => Some(known),
             // error!("[Authentication] {e}"); // In auth error log already defined
-            return (StatusCode::INTERNAL_SERVER_ERROR, "internal verify error".to_string()).into_response();
+            None => return (StatusCode::INTERNAL_SERVER_ERROR, "internal verify error".to_string()).into_response(),
         },
     };
     if let Some((uuid, auth_provider)) = userinfo {
-        let umanager = state.user_manager;
-        if umanager.is_banned(&uuid) {
+        let umanager = &state.user_manager;
+        if state.is_banned(&uuid) {
             info!("[Authentication] {nickname} tried to log in, but was banned");
             return (StatusCode::BAD_REQUEST, "You're banned!".to_string()).into_response();
         }
+        let duplicates = state.config.read().await.duplicate_username_policy;
+        if duplicates != DuplicateUsernamePolicy::Allow {
+            if let Some(owner) = umanager.nickname_owner(&uuid, &nickname) {
+                warn!("[Authentication] {nickname} ({uuid}) uses the username of {owner}, policy: {duplicates:?}");
+                if duplicates == DuplicateUsernamePolicy::Reject {
+                    return (StatusCode::BAD_REQUEST, "username is used by another account".to_string()).into_response();
+                }
+            }
+        }
         info!("[Authentication] {nickname} logged in using {}", auth_provider.name);
+        let renamed = umanager.renamed(&uuid, &nickname);
+        if let Some(old_nickname) = &renamed {
+            info!("[Authentication] {old_nickname} changed username to {nickname}");
+        }
+        let rank = initial_rank(&state, &uuid).await;
         let userinfo = Userinfo {
             nickname,
             uuid,
+            rank,
             token: Some(server_id.clone()),
             auth_provider,
             ..Default::default()
         };
-        match umanager.insert(uuid, server_id.clone(), userinfo.clone()) {
-            Ok(_) => {},
-            Err(_) => {
-                umanager.remove(&uuid);
-                if umanager.insert(uuid, server_id.clone(), userinfo).is_err() {
-                    error!("Old token error after attempting to remove it! Unexpected behavior!");
-                    return (StatusCode::BAD_REQUEST, "second session detected".to_string()).into_response();
-                };
-            }
+        let policy = state.config.read().await.second_session_policy;
+        if let Err(reason) = start_session(&state, userinfo, policy).await {
+            return (StatusCode::BAD_REQUEST, reason.to_string()).into_response();
+        }
+        if renamed.is_some() {
+            // Observers should refresh the displayed name
+            send_event(&state, &uuid).await;
         }
         (StatusCode::OK, server_id.to_string()).into_response()
     } else {
         info!("[Authentication] failed to verify {nickname}");
+        state.webhooks.auth_failed();
         (StatusCode::BAD_REQUEST, "failed to verify".to_string()).into_response()
     }
+}
+
+/// User who logged in before, if the providers are unreachable, the fallback is enabled
+/// and the client sent the token issued on their last login
+fn known_user(state: &AppState, nickname: &str, token: Option<&str>, err: &anyhow::Error, fallback: bool) -> Option<(uuid::Uuid, AuthProvider)> {
+    if !fallback || !err.is::<ProvidersUnreachable>() {
+        return None;
+    }
+    let user = state.user_manager.find_by_nickname(nickname);
+    match &user {
+        Some(user) if token.is_some() && user.token.as_deref() == token => {
+            warn!("[Authentication] providers are unreachable, letting {nickname} in as the known user {} by their last token", user.uuid);
+            Some((user.uuid, user.auth_provider.clone()))
+        },
+        Some(user) => {
+            info!("[Authentication] providers are unreachable and {nickname} didn't prove to be {}", user.uuid);
+            None
+        },
+        None => {
+            info!("[Authentication] providers are unreachable and {nickname} is unknown");
+            None
+        },
+    }
+}
+
+/// Rank given to the user on login, known users keep their rank
+async fn initial_rank(state: &AppState, uuid: &uuid::Uuid) -> String {
+    if state.user_manager.get_by_uuid(uuid).is_none() {
+        state.config.read().await.default_rank.clone()
+    } else {
+        Userinfo::default().rank
+    }
+}
+
+/// Registers the token of the user, resolving a second session with the policy
+async fn start_session(state: &AppState, userinfo: Userinfo, policy: SecondSessionPolicy) -> Result<(), &'static str> {
+    let umanager = &state.user_manager;
+    let uuid = userinfo.uuid;
+    let token = userinfo.token.clone().unwrap_or_default();
+    if umanager.insert(uuid, token.clone(), userinfo.clone()).is_ok() {
+        return Ok(());
+    }
+    info!("[Authentication] second session of {} detected, policy: {:?}", userinfo.nickname, policy);
+    if policy == SecondSessionPolicy::Reject {
+        return Err("second session detected");
+    }
+    umanager.remove(&uuid);
+    if umanager.insert(uuid, token, userinfo).is_err() {
+        error!("Old token error after attempting to remove it! Unexpected behavior!");
+        return Err("second session detected");
+    };
+    if policy == SecondSessionPolicy::KickOld {
+        let session = state.session.get(&uuid).map(|session| session.clone());
+        if let Some(session) = session {
+            let _ = session.send(SessionMessage::Kick("Logged in from another location".to_string())).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn usernames_validated() {
+        let state = AppState::for_tests();
+        let request = |username: &str| id(Query(Id { username: username.to_string() }), State(state.clone()));
+
+        for username in ["Steve", "a_b_c", "Notch1234567890_"] {
+            assert_eq!(request(username).await.status(), StatusCode::OK, "{username}");
+        }
+        for username in ["ab", "Notch1234567890_x", "bad name", "name\nline", "<script>", "Стив"] {
+            assert_eq!(request(username).await.status(), StatusCode::BAD_REQUEST, "{username}");
+        }
+        assert_eq!(state.user_manager.count_pending(), 3);
+
+        // Other providers may allow other names
+        let mut config = state.config.write().await;
+        config.usernames = toml::from_str("maxLength = 32\npattern = '^[\\w.]+$'").unwrap();
+        drop(config);
+        assert_eq!(request("Стив.other_provider").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn duplicate_usernames_handled() {
+        use crate::auth::AuthProviders;
+        let state = AppState::for_tests();
+        let (original, alt) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        state.user_manager.insert_user(original, Userinfo { uuid: original, nickname: "Tester".to_string(), ..Default::default() });
+        // The provider knows the username under another uuid
+        let app = axum::Router::new().route("/hasJoined", get(move || async move { axum::Json(serde_json::json!({ "id": alt.simple().to_string() })) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hasJoined", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        state.config.write().await.auth_providers = AuthProviders(vec![AuthProvider { name: "Quirky".to_string(), url }]);
+        let login = |policy: DuplicateUsernamePolicy| {
+            let state = state.clone();
+            async move {
+                state.config.write().await.duplicate_username_policy = policy;
+                let server_id = format!("server-{policy:?}");
+                state.user_manager.pending_insert(server_id.clone(), "tester".to_string(), 16);
+                verify(Query(Verify { id: server_id }), None, State(state.clone())).await.status()
+            }
+        };
+
+        assert_eq!(login(DuplicateUsernamePolicy::Allow).await, StatusCode::OK);
+        assert_eq!(login(DuplicateUsernamePolicy::Warn).await, StatusCode::OK);
+        assert_eq!(state.user_manager.get(&"server-Warn".to_string()).unwrap().uuid, alt);
+        assert_eq!(login(DuplicateUsernamePolicy::Reject).await, StatusCode::BAD_REQUEST);
+        assert!(state.user_manager.get(&"server-Reject".to_string()).is_none());
+        // Both accounts are registered under the name now, each sees the other one
+        assert_eq!(state.user_manager.nickname_owner(&original, "TESTER"), Some(alt));
+        assert_eq!(state.user_manager.nickname_owner(&alt, "Tester"), Some(original));
+    }
+
+    #[tokio::test]
+    async fn known_users_during_outage() {
+        use crate::auth::AuthProviders;
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        let provider = AuthProvider { name: "Mojang".to_string(), url: "http://127.0.0.1:1/hasJoined".to_string() };
+        let known = Userinfo { uuid, nickname: "Tester".to_string(), auth_provider: provider.clone(), token: Some("last".to_string()), ..Default::default() };
+        state.user_manager.insert_user(uuid, known);
+        state.config.write().await.auth_providers = AuthProviders(vec![provider]);
+        let login = |username: &str, token: Option<&str>| {
+            let server_id = format!("server-{username}-{token:?}");
+            state.user_manager.pending_insert(server_id.clone(), username.to_string(), 16);
+            verify(Query(Verify { id: server_id }), token.map(|token| Token(token.to_string())), State(state.clone()))
+        };
+
+        assert_eq!(login("tester", Some("last")).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        state.config.write().await.offline_fallback = true;
+        // The nickname alone or with another token isn't enough
+        assert_eq!(login("tester", None).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(login("tester", Some("guess")).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let res = login("tester", Some("last")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let token = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert_eq!(state.user_manager.get(&token).unwrap().uuid, uuid);
+        // New users can't be verified
+        assert_eq!(login("Stranger", Some("last")).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Server errors of the provider are an outage too
+        let app = axum::Router::new().route("/hasJoined", get(|| async { StatusCode::BAD_GATEWAY }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hasJoined", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        state.config.write().await.auth_providers = AuthProviders(vec![AuthProvider { name: "Mojang".to_string(), url }]);
+        assert_eq!(login("Tester", Some(&token)).await.status(), StatusCode::OK);
+
+        // A provider answering that the user didn't join isn't an outage
+        let app = axum::Router::new().route("/hasJoined", get(|| async { StatusCode::NO_CONTENT }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hasJoined", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        state.config.write().await.auth_providers = AuthProviders(vec![AuthProvider { name: "Mojang".to_string(), url }]);
+        assert_eq!(login("Tester", Some("last")).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn default_rank_applied() {
+        let state = AppState::for_tests();
+        state.config.write().await.default_rank = "member".to_string();
+        let uuid = Uuid::from_u128(1);
+        let user = |token: &str, rank: String| Userinfo { uuid, nickname: "Tester".to_string(), rank, token: Some(token.to_string()), ..Default::default() };
+
+        let rank = initial_rank(&state, &uuid).await;
+        start_session(&state, user("first", rank), SecondSessionPolicy::Replace).await.unwrap();
+        assert_eq!(state.user_manager.get_by_uuid(&uuid).unwrap().rank, "member");
+
+        // Ranks changed later aren't reset on the next login
+        state.user_manager.insert_user(uuid, Userinfo { rank: "donor".to_string(), ..Default::default() });
+        let rank = initial_rank(&state, &uuid).await;
+        start_session(&state, user("second", rank), SecondSessionPolicy::Replace).await.unwrap();
+        assert_eq!(state.user_manager.get_by_uuid(&uuid).unwrap().rank, "donor");
+    }
+
+    #[tokio::test]
+    async fn second_session_policies() {
+        let uuid = Uuid::from_u128(1);
+        let user = |token: &str| Userinfo { uuid, nickname: "Tester".to_string(), token: Some(token.to_string()), ..Default::default() };
+
+        for policy in [SecondSessionPolicy::Replace, SecondSessionPolicy::Reject, SecondSessionPolicy::KickOld] {
+            let state = AppState::for_tests();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            start_session(&state, user("old"), policy).await.unwrap();
+            state.session.insert(uuid, tx);
+
+            let res = start_session(&state, user("new"), policy).await;
+            let is_authenticated = |token: &str| state.user_manager.get(&token.to_string()).is_some();
+            match policy {
+                SecondSessionPolicy::Replace => {
+                    assert!(res.is_ok());
+                    assert!(!is_authenticated("old") && is_authenticated("new"));
+                    assert!(rx.try_recv().is_err());
+                },
+                SecondSessionPolicy::Reject => {
+                    assert!(res.is_err());
+                    assert!(is_authenticated("old") && !is_authenticated("new"));
+                    assert!(rx.try_recv().is_err());
+                },
+                SecondSessionPolicy::KickOld => {
+                    assert!(res.is_ok());
+                    assert!(!is_authenticated("old") && is_authenticated("new"));
+                    assert!(matches!(rx.try_recv(), Ok(SessionMessage::Kick(_))));
+                },
+            }
+        }
+    }
 }
\ No newline at end of file
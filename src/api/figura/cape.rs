@@ -0,0 +1,119 @@
+use axum::{body::Bytes, extract::{Path, State}, http::header, response::IntoResponse};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    api::errors::internal_and_log,
+    auth::Token,
+    ApiError, ApiResult, AppState
+};
+use super::profile::send_event;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Content type of the cape image, None if it's not PNG or WebP
+pub fn cape_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(PNG_MAGIC) {
+        Some("image/png")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+pub async fn upload_cape(
+    Token(token): Token,
+    State(state): State<AppState>,
+    body: Bytes,
+) -> ApiResult<String> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    tracing::info!("{} ({}) trying to upload a cape", user_info.uuid, user_info.nickname);
+    let def = state.config.read().await.limitations.can_upload;
+    if !state.user_manager.upload_state(user_info.uuid, def) {
+        return Err(ApiError::Forbidden);
+    }
+    if cape_type(&body).is_none() {
+        return Err(ApiError::BadRequest);
+    }
+    let _permit = state.upload_permit().await?;
+    state.avatars.put(&state.avatars.cape_path(&user_info.uuid), &body).await.map_err(internal_and_log)?;
+    send_event(&state, &user_info.uuid).await;
+    Ok("ok".to_string())
+}
+
+pub async fn own_cape(
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> ApiResult<impl IntoResponse> {
+    let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
+    download_cape(Path(uuid), State(state)).await
+}
+
+pub async fn download_cape(
+    Path(uuid): Path<Uuid>,
+    State(state): State<AppState>,
+) -> ApiResult<impl IntoResponse> {
+    tracing::info!("Requesting a cape: {}", uuid);
+    let _permit = state.download_permit().await?;
+    let cape = match state.avatars.get(&state.avatars.cape_path(&uuid)).await {
+        Ok(cape) => cape,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(err) => return Err(internal_and_log(err)),
+    };
+    let content_type = cape_type(&cape).unwrap_or("application/octet-stream");
+    Ok(([(header::CONTENT_TYPE, content_type)], cape))
+}
+
+pub async fn delete_cape(Token(token): Token, State(state): State<AppState>) -> ApiResult<String> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    tracing::info!("{} ({}) is trying to delete the cape", user_info.uuid, user_info.nickname);
+    match fs::remove_file(state.avatars.cape_path(&user_info.uuid)).await {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(err) => return Err(internal_and_log(err)),
+    }
+    send_event(&state, &user_info.uuid).await;
+    Ok("ok".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::Userinfo;
+    use super::*;
+
+    fn png() -> Bytes {
+        Bytes::from([PNG_MAGIC, &[0u8; 64]].concat())
+    }
+
+    #[tokio::test]
+    async fn cape_upload_and_download() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        let user = Userinfo { uuid, nickname: "Tester".to_string(), token: Some("token".to_string()), ..Default::default() };
+        state.user_manager.insert(uuid, "token".to_string(), user).unwrap();
+        let token = || Token("token".to_string());
+
+        assert!(matches!(download_cape(Path(uuid), State(state.clone())).await, Err(ApiError::NotFound)));
+        // Only images
+        let res = upload_cape(token(), State(state.clone()), Bytes::from_static(b"<html>")).await;
+        assert!(matches!(res, Err(ApiError::BadRequest)));
+
+        upload_cape(token(), State(state.clone()), png()).await.unwrap();
+        assert!(state.avatars.cape_path(&uuid).to_string_lossy().ends_with(".cape"));
+        let res = download_cape(Path(uuid), State(state.clone())).await.unwrap().into_response();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), png());
+        assert!(own_cape(token(), State(state.clone())).await.is_ok());
+
+        delete_cape(token(), State(state.clone())).await.unwrap();
+        assert!(matches!(download_cape(Path(uuid), State(state)).await, Err(ApiError::NotFound)));
+    }
+
+    #[test]
+    fn cape_types() {
+        assert_eq!(cape_type(&png()), Some("image/png"));
+        assert_eq!(cape_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(cape_type(b"RIFF\0\0\0\0WAVE"), None);
+    }
+}
@@ -1,9 +1,9 @@
-use axum::{extract::State, Json};
+use axum::{extract::{Path, State}, http::header, response::{IntoResponse, Redirect, Response}, Json};
 use serde_json::{json, Value};
 use tracing::error;
 
 use crate::{
-    utils::{get_figura_versions, get_motd, FiguraVersions}, AppState, FIGURA_DEFAULT_VERSION
+    state::{Badges, Config, Rank, PRIDE_BADGES, SPECIAL_BADGES}, utils::{get_figura_versions, get_motd, FiguraVersions}, ApiError, ApiResult, AppState, FIGURA_DEFAULT_VERSION, REPOSITORY, SCULPTOR_VERSION
 };
 use crate::auth::Token;
 
@@ -12,7 +12,7 @@ pub async fn version(State(state): State<AppState>) -> Json<FiguraVersions> {
     if let Some(res) = res {
         Json(res)
     } else {
-        let actual = get_figura_versions().await;
+        let actual = get_figura_versions(&state.http).await;
         if let Ok(res) = actual {
             let mut stored = state.figura_versions.write().await;
             *stored = Some(res);
@@ -27,6 +27,43 @@ pub async fn version(State(state): State<AppState>) -> Json<FiguraVersions> {
     }
 }
 
+/// Names and descriptions of the badge slots from `allowedBadges`
+pub async fn badges(State(state): State<AppState>) -> Json<Badges> {
+    Json(state.config.read().await.badges.clone())
+}
+
+/// Liveness check for load balancers, tells which instance answered
+pub async fn health(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "status": "ok", "instance": state.config.read().await.instance_id() }))
+}
+
+/// Landing page for people opening the server in a browser
+pub async fn root(State(state): State<AppState>) -> Response {
+    match state.config.read().await.root_redirect.clone() {
+        Some(url) => Redirect::temporary(&url).into_response(),
+        None => Json(json!({
+            "name": "Sculptor",
+            "version": SCULPTOR_VERSION,
+            "repository": format!("https://github.com/{REPOSITORY}"),
+        })).into_response(),
+    }
+}
+
+/// Tells the crawlers what they can index, see `robots` in the config
+pub async fn robots(State(state): State<AppState>) -> impl IntoResponse {
+    let content = state.config.read().await.robots.content.clone();
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], content)
+}
+
+/// Server clock for the clients compensating their clock skew
+pub async fn time() -> Json<Value> {
+    let now = chrono::Local::now();
+    Json(json!({
+        "time": now.timestamp_millis(),
+        "offset": now.offset().local_minus_utc(), // Seconds east of UTC
+    }))
+}
+
 pub async fn motd(State(state): State<AppState>) -> Json<Vec<crate::utils::Motd>> {
     Json(get_motd(state).await)
 }
@@ -35,16 +72,33 @@ pub async fn limits(
     Token(token): Token,
     State(state): State<AppState>
 ) -> Json<Value> {
-    let limits = &state.config.read().await.limitations;
-    let can_upload = if let Some(user_info) = state.user_manager.get(&token) {
-        state.user_manager.upload_state(user_info.uuid, limits.can_upload)
+    let config = state.config.read().await;
+    let limits = &config.limitations;
+    let (can_upload, ping_size) = if let Some(user_info) = state.user_manager.get(&token) {
+        (state.user_manager.upload_state(user_info.uuid, limits.can_upload), config.ping_size(&user_info.uuid, &user_info.rank))
     } else {
-        limits.can_upload
+        (limits.can_upload, config.websocket.ping_size)
     };
-    Json(json!({
+    Json(limits_response(&config, can_upload, ping_size))
+}
+
+/// Limits of the players with the rank, without their own overrides. Doesn't need a token
+pub async fn rank_limits(Path(rank): Path<String>, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let config = state.config.read().await;
+    let Some(settings) = config.ranks.get(&rank).cloned().or_else(|| (rank == config.default_rank).then(Rank::default)) else {
+        return Err(ApiError::NotFound);
+    };
+    let mut response = limits_response(&config, config.limitations.can_upload, settings.ping_size.unwrap_or(config.websocket.ping_size));
+    response["rank"] = json!(rank);
+    Ok(Json(response))
+}
+
+fn limits_response(config: &Config, can_upload: bool, ping_size: usize) -> Value {
+    let limits = &config.limitations;
+    json!({
         "rate": {
-            "pingSize": 1024,
-            "pingRate": 32,
+            "pingSize": ping_size,
+            "pingRate": config.websocket.ping_rate.unwrap_or(32),
             "equip": 1,
             "download": 50,
             "upload": 1
@@ -54,9 +108,77 @@ pub async fn limits(
             "maxAvatars": limits.max_avatars,
             "canUpload": can_upload,
             "allowedBadges": {
-                "special": [0,0,0,0,0,0],
-                "pride": [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
+                "special": vec![0; SPECIAL_BADGES],
+                "pride": vec![0; PRIDE_BADGES],
             }
         }
-    }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn badges_match_slots() {
+        let state = AppState::for_tests();
+        state.user_manager.insert(uuid::Uuid::from_u128(1), "token".to_string(), Default::default()).unwrap();
+        let Json(limits) = limits(Token("token".to_string()), State(state.clone())).await;
+        let Json(badges) = badges(State(state)).await;
+        let slots = |kind: &str| limits["limits"]["allowedBadges"][kind].as_array().unwrap().len();
+        assert_eq!(badges.special.len(), slots("special"));
+        assert_eq!(badges.pride.len(), slots("pride"));
+        let icons: Vec<u32> = badges.pride.iter().map(|badge| badge.icon).collect();
+        assert_eq!(icons, (0..PRIDE_BADGES as u32).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn rank_limits_listed() {
+        let state = AppState::for_tests();
+        state.config.write().await.ranks.insert("donor".to_string(), Rank { ping_size: Some(4096), ..Default::default() });
+        let Json(donor) = rank_limits(Path("donor".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(donor["rank"], "donor");
+        assert_eq!(donor["rate"]["pingSize"], 4096);
+        let Json(default) = rank_limits(Path("default".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(default["rate"]["pingSize"], state.config.read().await.websocket.ping_size);
+        assert_eq!(default["limits"], donor["limits"]);
+
+        assert!(matches!(rank_limits(Path("unknown".to_string()), State(state)).await, Err(ApiError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn root_info_or_redirect() {
+        let state = AppState::for_tests();
+        let res = root(State(state.clone())).await;
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["name"], "Sculptor");
+        assert_eq!(body["version"], SCULPTOR_VERSION);
+
+        state.config.write().await.root_redirect = Some("https://example.com/".to_string());
+        let res = root(State(state)).await;
+        assert_eq!(res.status(), axum::http::StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()[axum::http::header::LOCATION], "https://example.com/");
+    }
+
+    #[tokio::test]
+    async fn robots_txt_configured() {
+        let state = AppState::for_tests();
+        let body = || async {
+            let res = robots(State(state.clone())).await.into_response();
+            assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+            String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+        };
+        assert_eq!(body().await, "User-agent: *\nDisallow: /\n");
+
+        state.config.write().await.robots.content = "User-agent: *\nAllow: /\n".to_string();
+        assert_eq!(body().await, "User-agent: *\nAllow: /\n");
+    }
+
+    #[tokio::test]
+    async fn time_is_now() {
+        let Json(res) = time().await;
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!((now - res["time"].as_i64().unwrap()).abs() < 1000);
+        assert!(res["offset"].is_i64());
+    }
 }
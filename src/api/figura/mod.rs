@@ -4,5 +4,8 @@ pub mod auth;
 pub mod profile;
 pub mod info;
 pub mod assets;
+pub mod report;
+pub mod cape;
+pub mod admin;
 
-pub use websocket::{initial as ws, SessionMessage};
\ No newline at end of file
+pub use websocket::{initial as ws, Frame, S2CMessage, SessionMessage};
\ No newline at end of file
@@ -1,23 +1,21 @@
 use std::ops::Add;
-use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 use axum::{
-    body::Bytes, extract::{Path, State}, Json
+    body::Body, extract::{Multipart, Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json
 };
-use tracing::debug;
+use futures_util::{future, stream, StreamExt as _};
+use tracing::{debug, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tokio::{
-    fs,
-    io::{self, AsyncReadExt, BufWriter},
-};
+use tokio::fs;
 use uuid::Uuid;
 
 use crate::{
-    api::errors::internal_and_log,
-    auth::Token, utils::{calculate_file_sha256, format_uuid},
-    ApiError, ApiResult, AppState, AVATARS_VAR
+    api::errors::{error_and_log, internal_and_log},
+    auth::{Token, Userinfo}, state::{Config, PRIDE_BADGES, SPECIAL_BADGES}, utils::{calculate_sha256, format_uuid, get_limit_as_bytes, ChunkedUpload, is_denied_content, ShareError, throttle, WebhookEvent},
+    ApiError, ApiResult, AppState, ACCESS_LOG_TARGET
 };
-use super::websocket::S2CMessage;
+use super::websocket::{Frame, S2CMessage, SessionMessage};
 
 pub fn is_requesting_self(uuid: Uuid, state: &AppState, token: &String) -> bool {
     return if let Some(user_info) = state.user_manager.get(token) {
@@ -26,21 +24,40 @@ pub fn is_requesting_self(uuid: Uuid, state: &AppState, token: &String) -> bool
     } else { false };
 }
 
+/// Serializes as MessagePack if the client accepts it, otherwise as JSON
+fn negotiate(headers: &HeaderMap, value: &Value) -> ApiResult<Response> {
+    let accepts_msgpack = headers.get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/msgpack") || accept.contains("application/x-msgpack"));
+    if accepts_msgpack {
+        let body = rmp_serde::to_vec_named(value).map_err(internal_and_log)?;
+        Ok(([(header::CONTENT_TYPE, "application/msgpack")], body).into_response())
+    } else {
+        Ok(Json(value).into_response())
+    }
+}
+
 pub async fn user_info(
     Path(uuid): Path<Uuid>,
-    Token(token): Token,
+    token: Option<Token>,
+    headers: HeaderMap,
     State(state): State<AppState>,
-) -> ApiResult<Json<Value>> {
+) -> ApiResult<Response> {
     tracing::info!("Receiving profile information for {}", uuid);
 
+    let Some(Token(token)) = token else {
+        if !state.config.read().await.allow_anonymous_profiles {
+            return Err(ApiError::Unauthorized)
+        }
+        return negotiate(&headers, &anonymous_profile(&state, uuid).await?)
+    };
     let formatted_uuid = format_uuid(&uuid);
 
     let request_temp_state = state.user_manager.request_temp_state(uuid, false);
     let request_self_avatar = is_requesting_self(uuid, &state, &token);
-    let temp_avatar_file = format!("{}/temp/{}.moon", *AVATARS_VAR, formatted_uuid);
-    let path = PathBuf::from(&temp_avatar_file);
-    let outdated = if path.exists() {
-        let meta = path.metadata().unwrap();
+    let temp_avatar_file = state.avatars.temp_path(&uuid);
+    let outdated = if temp_avatar_file.exists() {
+        let meta = temp_avatar_file.metadata().unwrap();
         let last_modified = meta.modified().unwrap();
         SystemTime::now() > last_modified.add(Duration::from_secs(60))
     } else { false };
@@ -49,10 +66,10 @@ pub async fn user_info(
         state.user_manager.put_request_temp_state(uuid, true);
         temp_avatar_file
     } else {
-        format!("{}/{}.moon", *AVATARS_VAR, formatted_uuid)
+        state.avatars.avatar_path(&uuid)
     };
 
-    let userinfo = if let Some(info) = state.user_manager.get_by_uuid(&uuid) { info } else {
+    let userinfo = if let Some(info) = state.user_manager.get_by_uuid(&uuid) { info.clone() } else {
         return Err(ApiError::BadRequest) // NOTE: Not Found (404) shows badge
     };
 
@@ -61,27 +78,13 @@ pub async fn user_info(
         "rank": userinfo.rank,
         "equipped": [],
         "lastUsed": userinfo.last_used,
-        "equippedBadges": {
-            "special": [0,0,0,0,0,0],
-            "pride": [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-        },
+        "equippedBadges": equipped_badges(&*state.config.read().await, &uuid, &userinfo.rank),
         "version": userinfo.version,
         "banned": userinfo.banned
     });
 
-    if let Some(settings) = state.config.read().await.advanced_users.clone().get(&uuid) {
-        let badges = user_info_response
-            .get_mut("equippedBadges")
-            .and_then(Value::as_object_mut)
-            .unwrap();
-        badges.append(
-            json!({
-                "special": settings.special,
-                "pride": settings.pride
-            })
-            .as_object_mut()
-            .unwrap(),
-        )
+    if let Some(status) = state.statuses.get(&uuid) {
+        user_info_response["status"] = json!(*status);
     }
 
     if fs::metadata(&avatar_file).await.is_ok() {
@@ -89,76 +92,651 @@ pub async fn user_info(
             .get_mut("equipped")
             .and_then(Value::as_array_mut)
         {
-            match calculate_file_sha256(&avatar_file) {
-                Ok(hash) => equipped.push(json!({
-                    "id": "avatar",
-                    "owner": &formatted_uuid,
-                    "hash": hash
-                })),
+            match state.avatars.hash(&avatar_file).await {
+                Ok(hash) => {
+                    let mut avatar = json!({
+                        "id": AVATAR_ID,
+                        "owner": &formatted_uuid,
+                        "hash": &hash
+                    });
+                    if let Some(meta) = avatar_meta(&state, &uuid, AVATAR_ID).await {
+                        avatar["meta"] = json!(meta.visible(request_self_avatar));
+                    }
+                    if state.config.read().await.storage.previews {
+                        if let Some(preview) = state.avatars.preview(&uuid, &hash).await {
+                            avatar["preview"] = json!(preview);
+                        }
+                    }
+                    equipped.push(avatar)
+                },
                 Err(_e) => {}
             }
         }
     }
-    Ok(Json(user_info_response))
+    let cape_file = state.avatars.cape_path(&uuid);
+    if fs::metadata(&cape_file).await.is_ok() {
+        if let Ok(hash) = state.avatars.hash(&cape_file).await {
+            user_info_response["cape"] = json!({ "owner": &formatted_uuid, "hash": hash });
+        }
+    }
+    negotiate(&headers, &user_info_response)
+}
+
+/// Public view of a profile for requests without a token, only the committed avatar is shown
+async fn anonymous_profile(state: &AppState, uuid: Uuid) -> ApiResult<Value> {
+    let formatted_uuid = format_uuid(&uuid);
+    let Some(userinfo) = state.user_manager.get_by_uuid(&uuid) else {
+        return Err(ApiError::BadRequest)
+    };
+    let mut equipped = Vec::new();
+    if let Ok(hash) = state.avatars.hash(&state.avatars.avatar_path(&uuid)).await {
+        equipped.push(json!({ "id": AVATAR_ID, "owner": &formatted_uuid, "hash": hash }));
+    }
+    Ok(json!({
+        "uuid": &formatted_uuid,
+        "rank": userinfo.rank,
+        "equipped": equipped,
+        "version": userinfo.version
+    }))
+}
+
+/// Badges are taken from the first layer that sets them:
+/// `advancedUsers` of the user, then `ranks` of their rank, then no badges
+pub fn equipped_badges(config: &Config, uuid: &Uuid, rank: &str) -> Value {
+    let user = config.advanced_users.get(uuid);
+    let rank = config.ranks.get(rank);
+    let special = user.and_then(|user| user.special.as_deref())
+        .or(rank.and_then(|rank| rank.special.as_deref()))
+        .unwrap_or_default();
+    let pride = user.and_then(|user| user.pride.as_deref())
+        .or(rank.and_then(|rank| rank.pride.as_deref()))
+        .unwrap_or_default();
+    json!({
+        "special": normalize_badges(special, SPECIAL_BADGES, "special", uuid),
+        "pride": normalize_badges(pride, PRIDE_BADGES, "pride", uuid)
+    })
+}
+
+/// Clients reject badge arrays of other lengths, so misconfigured ones are padded with zeros or truncated
+fn normalize_badges(badges: &[u8], len: usize, kind: &str, uuid: &Uuid) -> Vec<u8> {
+    if !badges.is_empty() && badges.len() != len {
+        warn!("{kind} badges of {uuid} have {} entries instead of {len}, check the config", badges.len());
+    }
+    let mut badges = badges.to_vec();
+    badges.resize(len, 0);
+    badges
+}
+
+#[derive(Deserialize, Default)]
+pub struct Download {
+    /// Always serve the committed avatar, don't touch the temp one
+    #[serde(default)]
+    raw: bool,
 }
 
 pub async fn download_avatar(
     Path(uuid): Path<Uuid>,
+    Query(query): Query<Download>,
     Token(token): Token,
     State(state): State<AppState>
-) -> ApiResult<Vec<u8>> {
+) -> ApiResult<Response> {
     let str_uuid = format_uuid(&uuid);
-    tracing::info!("Requesting an avatar: {}", str_uuid);
-
-    let download_self_avatar = is_requesting_self(uuid, &state, &token);
-    let temp_avatar_file = format!("{}/temp/{}.moon", *AVATARS_VAR, str_uuid);
-    let path = PathBuf::from(temp_avatar_file);
-    let (avatar_file, delete_temp) = if download_self_avatar && path.exists() {
-        tracing::info!("Avatar of {} is temp avatar.", str_uuid);
-        (format!("{}/temp/{}.moon", *AVATARS_VAR, str_uuid), true)
-    } else {
-        (format!("{}/{}.moon", *AVATARS_VAR, str_uuid), false)
+    tracing::info!("Requesting an avatar: {} (raw: {})", str_uuid, query.raw);
+
+    let (avatar_file, delete_temp) = served_avatar(&state, uuid, &token, query.raw);
+
+    // Requests with the server token aren't limited
+    let per_user = {
+        let config = state.config.read().await;
+        config.limitations.max_concurrent_downloads_per_user.filter(|_| config.verify_token(&token).is_err())
+    };
+    let requester = state.user_manager.get(&token).map(|user| user.uuid);
+    let user_permit = match (per_user, requester) {
+        (Some(max), Some(requester)) => match state.user_download_permit(requester, max) {
+            Some(permit) => Some(permit),
+            None => {
+                tracing::debug!("Too many concurrent downloads of {}", requester);
+                return Ok((StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")], "too many requests").into_response());
+            },
+        },
+        _ => None,
+    };
+    let permit = state.download_permit().await?;
+    let (reader, len) = match state.avatars.open(&avatar_file).await {
+        Ok(opened) => opened,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(err) => return Err(internal_and_log(err)),
+    };
+    if state.config.read().await.access_log {
+        log_access(requester, uuid);
+    }
+    let rate = state.config.read().await.limitations.download_rate;
+    // The slots are taken until the whole avatar is sent, the temp avatar is deleted once it is
+    let completed = async move {
+        drop((permit, user_permit));
+        if delete_temp {
+            if let Err(err) = fs::remove_file(&avatar_file).await {
+                tracing::warn!("Can't delete the downloaded temp avatar {}: {}", avatar_file.display(), err);
+            }
+        }
+        None
     };
+    let body = throttle(reader, rate).chain(stream::once(completed).filter_map(future::ready));
+    Ok(([(header::CONTENT_LENGTH, len)], Body::from_stream(body)).into_response())
+}
 
-    let mut file = if let Ok(file1) = fs::File::open(avatar_file.clone()).await {
-        file1
+/// Path of the avatar served to the requester and whether it's the temp one.
+/// Users get their own temp avatar unless they ask for the raw one
+fn served_avatar(state: &AppState, uuid: Uuid, token: &String, raw: bool) -> (std::path::PathBuf, bool) {
+    let temp_avatar_file = state.avatars.temp_path(&uuid);
+    if !raw && is_requesting_self(uuid, state, token) && temp_avatar_file.exists() {
+        tracing::info!("Avatar of {} is temp avatar.", format_uuid(&uuid));
+        (temp_avatar_file, true)
     } else {
-        return Err(ApiError::NotFound)
+        (state.avatars.avatar_path(&uuid), false)
+    }
+}
+
+/// Whether the avatar `download_avatar` would serve exists, and its hash, without sending it.
+/// The temp avatar isn't deleted
+pub async fn avatar_exists(
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<Download>,
+    Token(token): Token,
+    State(state): State<AppState>
+) -> ApiResult<Json<Value>> {
+    let (avatar_file, _) = served_avatar(&state, uuid, &token, query.raw);
+    match state.avatars.hash(&avatar_file).await {
+        Ok(hash) => Ok(Json(json!({ "exists": true, "hash": hash }))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Json(json!({ "exists": false }))),
+        Err(err) => Err(internal_and_log(err)),
+    }
+}
+
+/// Allowed size of the form around the avatar
+pub const MULTIPART_OVERHEAD: usize = 4096;
+
+fn log_access(requester: Option<Uuid>, target: Uuid) {
+    tracing::info!(
+        target: ACCESS_LOG_TARGET,
+        requester = requester.map(tracing::field::display),
+        target = %target,
+        "Avatar downloaded"
+    );
+}
+
+/// Id of the only avatar of the user
+const AVATAR_ID: &str = "avatar";
+/// Maximum size of the metadata body
+pub const META_BODY_LIMIT: usize = 4096;
+/// Tags of an avatar
+const MAX_TAGS: usize = 16;
+/// Tags starting with it are shown only to the author
+const PRIVATE_TAG_PREFIX: char = '.';
+
+/// Details of the avatar set by its author, stored next to it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AvatarMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Lowercase letters, digits, `-` and `_`, private ones start with `.`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl AvatarMeta {
+    fn is_valid(&self) -> bool {
+        let fits = |field: &Option<String>, max: usize| field.as_ref().is_none_or(|value| value.chars().count() <= max);
+        fits(&self.name, 64) && fits(&self.author, 64) && fits(&self.description, 1024)
+            && self.tags.len() <= MAX_TAGS && self.tags.iter().all(|tag| is_valid_tag(tag))
+    }
+    /// Private tags are removed unless the author is asking
+    fn visible(mut self, author: bool) -> Self {
+        if !author {
+            self.tags.retain(|tag| !tag.starts_with(PRIVATE_TAG_PREFIX));
+        }
+        self
+    }
+}
+
+fn is_valid_tag(tag: &str) -> bool {
+    let name = tag.strip_prefix(PRIVATE_TAG_PREFIX).unwrap_or(tag);
+    (1..=32).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+async fn avatar_meta(state: &AppState, uuid: &Uuid, id: &str) -> Option<AvatarMeta> {
+    let data = state.avatars.get(&state.avatars.meta_path(uuid, id)).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Sets the metadata of the user's avatar. Clients aren't notified, the avatar itself is the same.
+pub async fn put_avatar_meta(
+    Path(id): Path<String>,
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(meta): Json<AvatarMeta>,
+) -> ApiResult<&'static str> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    if id != AVATAR_ID {
+        return Err(ApiError::NotFound);
+    }
+    if !meta.is_valid() {
+        return Err(ApiError::BadRequest);
+    }
+    tracing::info!("{} ({}) is setting the avatar metadata", user_info.uuid, user_info.nickname);
+    let data = serde_json::to_vec(&meta).map_err(internal_and_log)?;
+    state.avatars.put_meta(&user_info.uuid, &id, &data).await.map_err(internal_and_log)?;
+    Ok("ok")
+}
+
+/// Makes a link to the committed avatar of the user, valid for `sharing.ttl`
+pub async fn share_avatar(
+    Path(id): Path<String>,
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
+    if id != AVATAR_ID || fs::metadata(state.avatars.avatar_path(&uuid)).await.is_err() {
+        return Err(ApiError::NotFound);
+    }
+    let sharing = state.config.read().await.sharing.clone();
+    let issued = chrono::Utc::now().timestamp();
+    let expires = issued + sharing.ttl as i64;
+    let token = state.share_links.sign(sharing.key.as_deref(), &uuid, &id, issued, expires);
+    tracing::info!("{} shared the avatar until {}", uuid, expires);
+    Ok(Json(json!({ "url": format!("/api/shared/{token}"), "token": token, "expires": expires })))
+}
+
+/// Avatar of a share link, doesn't need the Figura authentication
+pub async fn shared_avatar(Path(token): Path<String>, State(state): State<AppState>) -> ApiResult<Response> {
+    let key = state.config.read().await.sharing.key.clone();
+    let uuid = match state.share_links.verify(key.as_deref(), &token, chrono::Utc::now().timestamp()) {
+        Ok((uuid, _)) => uuid,
+        Err(ShareError::Expired | ShareError::Revoked) => return Err(ApiError::NotFound),
+        Err(ShareError::Invalid) => {
+            debug!("Share token with a wrong signature: {token}");
+            return Err(ApiError::Forbidden)
+        },
+    };
+    let permit = state.download_permit().await?;
+    let (reader, len) = match state.avatars.open(&state.avatars.avatar_path(&uuid)).await {
+        Ok(opened) => opened,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(err) => return Err(internal_and_log(err)),
+    };
+    let rate = state.config.read().await.limitations.download_rate;
+    let completed = async move {
+        drop(permit);
+        None
     };
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).await.map_err(internal_and_log)?;
-    if delete_temp {
-        let to_delete = avatar_file;
-        fs::remove_file(to_delete).await.map_err(internal_and_log)?;
+    let body = throttle(reader, rate).chain(stream::once(completed).filter_map(future::ready));
+    Ok(([(header::CONTENT_LENGTH, len)], Body::from_stream(body)).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct AvatarsQuery {
+    tag: Option<String>,
+}
+
+/// Avatars of the user with their metadata, only the ones with the tag if it's given
+pub async fn list_avatars(
+    Path(uuid): Path<Uuid>,
+    Token(token): Token,
+    Query(query): Query<AvatarsQuery>,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Vec<Value>>> {
+    state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?;
+    let author = is_requesting_self(uuid, &state, &token);
+    let avatar_file = state.avatars.avatar_path(&uuid);
+    if fs::metadata(&avatar_file).await.is_err() {
+        return Ok(Json(Vec::new()));
+    }
+    let hash = state.avatars.hash(&avatar_file).await.map_err(internal_and_log)?;
+    let meta = avatar_meta(&state, &uuid, AVATAR_ID).await.unwrap_or_default().visible(author);
+    if query.tag.is_some_and(|tag| !meta.tags.contains(&tag)) {
+        return Ok(Json(Vec::new()));
+    }
+    Ok(Json(vec![json!({ "id": AVATAR_ID, "owner": format_uuid(&uuid), "hash": hash, "meta": meta })]))
+}
+
+/// Everything stored about the requesting user, at most once per `limitations.export_cooldown`
+pub async fn export(Token(token): Token, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let user = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    let (cooldown, can_upload) = {
+        let limits = &state.config.read().await.limitations;
+        (Duration::from_secs(limits.export_cooldown), limits.can_upload)
+    };
+    if !state.export_cooldowns.try_acquire(user.uuid, cooldown) {
+        return Err(ApiError::TooManyRequests);
     }
-    Ok(buffer)
+    tracing::info!("{} ({}) exports their data", user.uuid, user.nickname);
+    let uuid = user.uuid;
+
+    let mut avatars = Vec::new();
+    let avatar_file = state.avatars.avatar_path(&uuid);
+    if let Ok(hash) = state.avatars.hash(&avatar_file).await {
+        avatars.push(json!({ "id": AVATAR_ID, "hash": hash, "meta": avatar_meta(&state, &uuid, AVATAR_ID).await }));
+    }
+    let temp_avatar = state.avatars.hash(&state.avatars.temp_path(&uuid)).await.ok();
+    let cape = state.avatars.hash(&state.avatars.cape_path(&uuid)).await.ok();
+    let ban = state.user_manager.ban_info(&uuid);
+    let badges = equipped_badges(&*state.config.read().await, &uuid, &user.rank);
+    Ok(Json(json!({
+        "profile": {
+            "uuid": uuid,
+            "nickname": user.nickname,
+            "rank": user.rank,
+            "authProvider": user.auth_provider.name,
+            "version": user.version,
+            "lastUsed": user.last_used,
+            "status": state.statuses.get(&uuid).map(|status| status.clone()),
+        },
+        "avatars": avatars,
+        "tempAvatar": temp_avatar,
+        "cape": cape,
+        "badges": badges,
+        "ban": { "banned": ban.is_some(), "details": ban },
+        "equipped": {
+            "avatar": !avatars.is_empty(),
+            "connected": state.session.contains_key(&uuid),
+            "canUpload": state.user_manager.upload_state(uuid, can_upload),
+        },
+    })))
 }
 
+/// Hash of the avatar in the same format as in `user_info`
+pub const AVATAR_HASH_HEADER: &str = "x-avatar-sha256";
+
 pub async fn upload_avatar(
     Token(token): Token,
     State(state): State<AppState>,
-    body: Bytes,
+    headers: HeaderMap,
+    body: Body,
 ) -> ApiResult<String> {
-    let request_data = body;
-
-    if let Some(user_info) = state.user_manager.get(&token) {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "{} ({}) trying to upload an avatar",
             user_info.uuid,
             user_info.nickname
         );
-        let def = state.config.read().await.limitations.can_upload;
-        let can_upload = state.user_manager.upload_state(user_info.uuid, def);
-        if !can_upload {
-            return Err(ApiError::Forbidden);
+        let ttl = Duration::from_secs(state.config.read().await.limitations.idempotency_ttl);
+        let key = idempotency_key(&headers)?.filter(|_| !ttl.is_zero());
+        if let Some(key) = key {
+            if state.upload_keys.succeeded(&token, key, ttl) {
+                tracing::info!("{} ({}) retried the upload {key:?}, it's already stored", user_info.uuid, user_info.nickname);
+                return Ok("ok".to_string());
+            }
+        }
+        let request_data = receive_upload(&state, &user_info.uuid, &headers, body).await?;
+        store_avatar(&state, &user_info, &headers, &request_data).await?;
+        if let Some(key) = key {
+            state.upload_keys.remember(&token, key, ttl);
         }
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        let mut file = BufWriter::new(fs::File::create(&avatar_file).await.map_err(internal_and_log)?);
-        io::copy(&mut request_data.as_ref(), &mut file).await.map_err(internal_and_log)?;
     }
     Ok("ok".to_string())
 }
 
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Key identifying the retries of a request, printable ASCII up to 255 characters
+fn idempotency_key(headers: &HeaderMap) -> ApiResult<Option<&str>> {
+    let Some(key) = headers.get(IDEMPOTENCY_KEY_HEADER) else { return Ok(None) };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => Ok(Some(key)),
+        _ => Err(ApiError::BadRequest),
+    }
+}
+
+/// Reads the uploaded avatar, telling the session of the uploader how much is received
+async fn receive_upload(state: &AppState, uuid: &Uuid, headers: &HeaderMap, body: Body) -> ApiResult<Vec<u8>> {
+    let (limit, interval, idle) = {
+        let config = state.config.read().await;
+        let limitations = &config.limitations;
+        (get_limit_as_bytes(limitations.max_avatar_size as usize), limitations.upload_progress.map(Duration::from_millis), Duration::from_secs(config.timeouts.upload_idle))
+    };
+    let total: u64 = match headers.get(header::CONTENT_LENGTH) {
+        Some(length) => length.to_str().ok().and_then(|length| length.parse().ok()).ok_or(ApiError::BadRequest)?,
+        None => 0,
+    };
+    if total > limit as u64 {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let total = total as usize;
+    let session = interval.and_then(|_| state.session.get(uuid).map(|tx| tx.clone()));
+    // The upload isn't slowed down by a busy session
+    let notify = |received: usize| if let Some(tx) = &session {
+        let _ = tx.try_send(SessionMessage::Ping(S2CMessage::UploadProgress(received as u32, total as u32).into()));
+    };
+
+    let mut data = Vec::with_capacity(total);
+    let (mut last_notice, mut reported) = (tokio::time::Instant::now(), 0);
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = tokio::time::timeout(idle, stream.next()).await.map_err(|_| ApiError::RequestTimeout)? {
+        let chunk = chunk.map_err(|err| error_and_log(err, ApiError::BadRequest))?;
+        if data.len() + chunk.len() > limit {
+            return Err(ApiError::PayloadTooLarge);
+        }
+        data.extend_from_slice(&chunk);
+        if interval.is_some_and(|interval| last_notice.elapsed() >= interval) {
+            (last_notice, reported) = (tokio::time::Instant::now(), data.len());
+            notify(reported);
+        }
+    }
+    if reported != data.len() {
+        notify(data.len());
+    }
+    Ok(data)
+}
+
+/// Same as `upload_avatar`, but the avatar is the `file` field of multipart/form-data.
+/// Only the `avatar` slot is supported.
+pub async fn upload_avatar_multipart(
+    Token(token): Token,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> ApiResult<String> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    tracing::info!("{} ({}) trying to upload an avatar via form", user_info.uuid, user_info.nickname);
+    let limit = get_limit_as_bytes(state.config.read().await.limitations.max_avatar_size as usize);
+
+    let mut file = None;
+    while let Some(field) = multipart.next_field().await.map_err(|err| error_and_log(err, ApiError::BadRequest))? {
+        match field.name() {
+            Some("file") => {
+                let data = field.bytes().await.map_err(|err| error_and_log(err, ApiError::BadRequest))?;
+                if data.len() > limit {
+                    return Err(ApiError::PayloadTooLarge);
+                }
+                file = Some(data);
+            },
+            Some("slot") => {
+                let slot = field.text().await.map_err(|err| error_and_log(err, ApiError::BadRequest))?;
+                if slot != "avatar" {
+                    tracing::debug!("Unsupported avatar slot: {slot}");
+                    return Err(ApiError::BadRequest);
+                }
+            },
+            _ => (),
+        }
+    }
+    let file = file.ok_or(ApiError::BadRequest)?;
+    store_avatar(&state, &user_info, &headers, &file).await?;
+    Ok("ok".to_string())
+}
+
+#[derive(Deserialize)]
+pub struct ChunkedInit {
+    /// Total size of the avatar
+    pub size: u64,
+    /// Checked on completion, in the `user_info` format
+    pub hash: Option<String>,
+}
+
+/// Starts a resumable upload replacing the unfinished one of the user, returns its id
+pub async fn init_chunked_upload(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(init): Json<ChunkedInit>,
+) -> ApiResult<Json<Value>> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    let limit = get_limit_as_bytes(state.config.read().await.limitations.max_avatar_size as usize);
+    if init.size as usize > limit {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let (id, replaced) = state.chunked_uploads.start(user_info.uuid, init.size, init.hash);
+    tracing::info!("{} ({}) started the resumable upload {id} of {} bytes", user_info.uuid, user_info.nickname, init.size);
+    if let Some(replaced) = replaced {
+        state.avatars.remove_upload(&replaced).await.map_err(internal_and_log)?;
+    }
+    Ok(Json(json!({ "id": id })))
+}
+
+fn chunked_status(upload: &ChunkedUpload) -> Json<Value> {
+    Json(json!({ "chunks": upload.chunks, "received": upload.received, "size": upload.size }))
+}
+
+/// How much of the upload was received, to resume it
+pub async fn chunked_upload_status(
+    Path(id): Path<String>,
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
+    Ok(chunked_status(&state.chunked_uploads.get(&id, &uuid).ok_or(ApiError::NotFound)?))
+}
+
+/// Appends the chunk `n`, chunks start from 0 and must come in order.
+/// Chunks that were already received are skipped, so a chunk can be sent again if its answer was lost
+pub async fn upload_chunk(
+    Path((id, n)): Path<(String, u32)>,
+    Token(token): Token,
+    State(state): State<AppState>,
+    chunk: axum::body::Bytes,
+) -> ApiResult<Json<Value>> {
+    let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
+    let lock = state.chunked_uploads.get(&id, &uuid).ok_or(ApiError::NotFound)?.lock;
+    // The same chunk sent twice at once is appended only once
+    let _guard = lock.lock().await;
+    let upload = state.chunked_uploads.get(&id, &uuid).ok_or(ApiError::NotFound)?;
+    if n < upload.chunks {
+        return Ok(chunked_status(&upload));
+    }
+    if n > upload.chunks {
+        return Err(ApiError::Conflict);
+    }
+    if upload.received + chunk.len() as u64 > upload.size {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    if let Err(e) = state.avatars.append_upload(&id, &chunk).await {
+        // The chunk may be written partially, so the upload can't be resumed
+        if is_storage_full(&e) {
+            state.chunked_uploads.remove(&id);
+            let _ = state.avatars.remove_upload(&id).await;
+        }
+        return Err(write_error(&state, &uuid, e));
+    }
+    state.chunked_uploads.received(&id, chunk.len() as u64);
+    Ok(chunked_status(&state.chunked_uploads.get(&id, &uuid).ok_or(ApiError::NotFound)?))
+}
+
+/// Checks the size and hash of the received chunks and stores them as the avatar
+pub async fn complete_chunked_upload(
+    Path(id): Path<String>,
+    Token(token): Token,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> ApiResult<&'static str> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    let lock = state.chunked_uploads.get(&id, &user_info.uuid).ok_or(ApiError::NotFound)?.lock;
+    let _guard = lock.lock().await;
+    let upload = state.chunked_uploads.get(&id, &user_info.uuid).ok_or(ApiError::NotFound)?;
+    let data = state.avatars.read_upload(&id).await.map_err(internal_and_log)?;
+    if data.len() as u64 != upload.size {
+        tracing::warn!("{} ({}) completed the upload {id} with {} of {} bytes", user_info.uuid, user_info.nickname, data.len(), upload.size);
+        return Err(ApiError::BadRequest);
+    }
+    if upload.hash.as_ref().is_some_and(|hash| !hash.trim().eq_ignore_ascii_case(&calculate_sha256(&data))) {
+        tracing::warn!("{} ({}) completed the upload {id}, but the hash doesn't match", user_info.uuid, user_info.nickname);
+        return Err(ApiError::BadRequest);
+    }
+    store_avatar(&state, &user_info, &headers, &data).await?;
+    state.chunked_uploads.remove(&id);
+    state.avatars.remove_upload(&id).await.map_err(internal_and_log)?;
+    Ok("ok")
+}
+
+/// Checks and writes the avatar uploaded by the user
+async fn store_avatar(state: &AppState, user_info: &Userinfo, headers: &HeaderMap, request_data: &[u8]) -> ApiResult<()> {
+    let (def, deny_patterns, min_size, previews, backup_dir) = {
+        let config = state.config.read().await;
+        let storage = &config.storage;
+        (config.limitations.can_upload, storage.deny_patterns.clone(), config.limitations.min_avatar_size, storage.previews, storage.backup_dir.clone())
+    };
+    let can_upload = state.user_manager.upload_state(user_info.uuid, def);
+    if !can_upload {
+        return Err(ApiError::Forbidden);
+    }
+    // Nothing is written yet, so the previous avatar is kept
+    if (request_data.len() as u64) < min_size {
+        tracing::warn!("{} ({}) uploaded {} bytes, probably an interrupted upload", user_info.uuid, user_info.nickname, request_data.len());
+        return Err(ApiError::BadRequest);
+    }
+    if is_denied_content(request_data, &deny_patterns) {
+        tracing::warn!("{} ({}) tried to upload web content as an avatar", user_info.uuid, user_info.nickname);
+        return Err(ApiError::BadRequest);
+    }
+    // Checked before writing, so the previous avatar is kept
+    if let Some(expected) = headers.get(AVATAR_HASH_HEADER) {
+        let hash = calculate_sha256(request_data);
+        if !expected.to_str().is_ok_and(|expected| expected.trim().eq_ignore_ascii_case(&hash)) {
+            tracing::warn!("{} ({}) uploaded an avatar with hash {}, but expected {:?}", user_info.uuid, user_info.nickname, hash, expected);
+            return Err(ApiError::BadRequest);
+        }
+    }
+    let _permit = state.upload_permit().await?;
+    let avatar_file = state.avatars.avatar_path(&user_info.uuid);
+    state.avatars.put(&avatar_file, request_data).await.map_err(|e| write_error(state, &user_info.uuid, e))?;
+    // The avatar is already stored, it's just shown without a preview
+    if previews {
+        if let Err(e) = state.avatars.put_preview(&user_info.uuid, &calculate_sha256(request_data), request_data).await {
+            warn!("Can't store the preview of the avatar of {} due: {e:?}", user_info.uuid);
+        }
+    }
+    if let Some(dir) = backup_dir {
+        // Best-effort, the upload is done already
+        let (avatars, uuid) = (state.avatars.clone(), user_info.uuid);
+        tokio::spawn(async move {
+            if let Err(e) = avatars.backup(&avatar_file, &dir).await {
+                warn!("Can't back up the avatar of {uuid} into {} due: {e:?}", dir.display());
+            }
+        });
+    }
+    state.webhooks.notify(WebhookEvent::AvatarUploaded, json!({ "uuid": user_info.uuid, "nickname": user_info.nickname }));
+    Ok(())
+}
+
+fn is_storage_full(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
+}
+
+/// A full disk is answered with 507 and reported to the webhooks, other errors are internal
+fn write_error(state: &AppState, uuid: &Uuid, e: std::io::Error) -> ApiError {
+    if !is_storage_full(&e) {
+        return internal_and_log(e);
+    }
+    tracing::error!("Can't store the upload of {uuid}, the storage is full: {e}");
+    state.webhooks.notify(WebhookEvent::StorageFull, json!({ "uuid": uuid }));
+    ApiError::InsufficientStorage
+}
+
 pub async fn equip_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<&'static str> {
     debug!("[API] S2C : Equip");
     let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
@@ -167,34 +745,719 @@ pub async fn equip_avatar(Token(token): Token, State(state): State<AppState>) ->
 }
 
 pub async fn delete_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<String> {
-    if let Some(user_info) = state.user_manager.get(&token) {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "{} ({}) is trying to delete the avatar",
             user_info.uuid,
             user_info.nickname
         );
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        fs::remove_file(avatar_file).await.map_err(internal_and_log)?;
+        let soft = state.config.read().await.storage.soft_delete;
+        state.avatars.delete(&user_info.uuid, soft).await.map_err(internal_and_log)?;
         send_event(&state, &user_info.uuid).await;
     }
     Ok("ok".to_string())
 }
 
+/// Brings back the latest avatar deleted within `storage.trashRetention`
+pub async fn restore_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<&'static str> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    tracing::info!("{} ({}) is restoring the avatar", user_info.uuid, user_info.nickname);
+    let retention = Duration::from_secs(state.config.read().await.storage.trash_retention);
+    if !state.avatars.restore(&user_info.uuid, retention).await.map_err(internal_and_log)? {
+        return Err(ApiError::NotFound);
+    }
+    send_event(&state, &user_info.uuid).await;
+    Ok("ok")
+}
+
+/// Discards the pending temp avatar of the user
+pub async fn delete_temp_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<&'static str> {
+    let user_info = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    tracing::info!("{} ({}) is clearing the temp avatar", user_info.uuid, user_info.nickname);
+    match fs::remove_file(state.avatars.temp_path(&user_info.uuid)).await {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(internal_and_log(err)),
+    }
+    state.user_manager.put_request_temp_state(user_info.uuid, false);
+    Ok("ok")
+}
+
 pub async fn send_event(state: &AppState, uuid: &Uuid) {
     // To user subscribers
     if let Some(broadcast) = state.subscribes.get(uuid) {
-        if broadcast.send(S2CMessage::Event(*uuid).into()).is_err() {
+        if broadcast.send(Frame::new(S2CMessage::Event(*uuid).into(), &state.metrics)).is_err() {
             debug!("[WebSocket] Failed to send Event! There is no one to send. UUID: {uuid}")
         };
     } else {
         debug!("[WebSocket] Failed to send Event! Can't find UUID: {uuid}")
     };
     // To user
-    if let Some(session) = state.session.get(uuid) {
+    let session = state.session.get(uuid).map(|session| session.clone());
+    if let Some(session) = session {
         if session.send(super::SessionMessage::Ping(S2CMessage::Event(*uuid).into())).await.is_err() {
             debug!("[WebSocket] Failed to send Event! WS doesn't connected? UUID: {uuid}")
         };
     } else {
         debug!("[WebSocket] Failed to send Event! Can't find UUID: {uuid}")
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, extract::FromRequest};
+
+    use super::*;
+    use crate::utils::Captured;
+
+    fn authenticated(state: &AppState, uuid: Uuid, token: &str) {
+        let user = Userinfo { uuid, nickname: "Tester".to_string(), token: Some(token.to_string()), ..Default::default() };
+        state.user_manager.insert(uuid, token.to_string(), user).unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_info_content_negotiation() {
+        let value = json!({ "uuid": "test", "equipped": [{ "hash": "abc" }], "banned": false });
+
+        for (accept, msgpack) in [(None, false), (Some("application/json"), false), (Some("application/msgpack"), true)] {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            let res = negotiate(&headers, &value).unwrap();
+            let content_type = res.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let decoded: Value = if msgpack {
+                assert_eq!(content_type, "application/msgpack");
+                rmp_serde::from_slice(&bytes).unwrap()
+            } else {
+                assert_eq!(content_type, "application/json");
+                serde_json::from_slice(&bytes).unwrap()
+            };
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_uploads_throttled() {
+        let state = AppState::for_tests();
+        let limit = state.config.read().await.limitations.max_concurrent_uploads;
+        authenticated(&state, Uuid::from_u128(1), "token");
+
+        // Uploads in progress
+        let permits: Vec<_> = (0..limit).map(|_| state.upload_limit.clone().try_acquire_owned().unwrap()).collect();
+        let res = upload_avatar(Token("token".to_string()), State(state.clone()), HeaderMap::new(), Body::from("avatar")).await;
+        assert!(matches!(res, Err(ApiError::ServiceUnavailable)));
+
+        drop(permits);
+        assert!(upload_avatar(Token("token".to_string()), State(state), HeaderMap::new(), Body::from("avatar")).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_progress_sent() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.config.write().await.limitations.upload_progress = Some(100);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        state.session.insert(uuid, tx);
+
+        // 4 chunks of 10 bytes, one every 60ms
+        let chunks = futures_util::StreamExt::then(futures_util::stream::iter(0..4), |_| async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            Ok::<_, std::io::Error>(Bytes::from_static(&[1; 10]))
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, 40.into());
+        upload_avatar(Token("token".to_string()), State(state.clone()), headers, Body::from_stream(chunks)).await.unwrap();
+
+        let mut progress = Vec::new();
+        while let Ok(SessionMessage::Ping(frame)) = rx.try_recv() {
+            progress.push(S2CMessage::try_from(frame.as_slice()).unwrap());
+        }
+        assert_eq!(progress, vec![S2CMessage::UploadProgress(20, 40), S2CMessage::UploadProgress(40, 40)]);
+        assert_eq!(state.avatars.get(&state.avatars.avatar_path(&uuid)).await.unwrap(), vec![1; 40]);
+
+        // Declared lengths over the limit, even past u32, and broken ones
+        let declared = |length: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, length.parse().unwrap());
+            upload_avatar(Token("token".to_string()), State(state.clone()), headers, Body::from("avatar"))
+        };
+        assert!(matches!(declared("5000000000").await, Err(ApiError::PayloadTooLarge)));
+        assert!(matches!(declared("many").await, Err(ApiError::BadRequest)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_upload_rejected() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let first = futures_util::stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(&[1; 10]))]);
+        let body = Body::from_stream(futures_util::StreamExt::chain(first, futures_util::stream::pending()));
+        let result = upload_avatar(Token("token".to_string()), State(state.clone()), HeaderMap::new(), body).await;
+        assert!(matches!(result, Err(ApiError::RequestTimeout)));
+        assert!(!state.avatars.avatar_path(&uuid).exists());
+    }
+
+    #[tokio::test]
+    async fn multipart_upload() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let form = |fields: &[(&str, &[u8])]| {
+            let mut body = Vec::new();
+            for (name, data) in fields {
+                body.extend_from_slice(format!("--BOUNDARY\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n").as_bytes());
+                body.extend_from_slice(data);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(b"--BOUNDARY--\r\n");
+            let request = axum::http::Request::builder()
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+                .body(Body::from(body))
+                .unwrap();
+            let state = state.clone();
+            async move {
+                let multipart = Multipart::from_request(request, &()).await.unwrap();
+                upload_avatar_multipart(Token("token".to_string()), State(state), HeaderMap::new(), multipart).await
+            }
+        };
+
+        form(&[("slot", b"avatar"), ("file", b"form avatar")]).await.unwrap();
+        assert_eq!(state.avatars.get(&state.avatars.avatar_path(&uuid)).await.unwrap(), b"form avatar");
+
+        assert!(matches!(form(&[("slot", b"avatar")]).await, Err(ApiError::BadRequest)));
+        assert!(matches!(form(&[("slot", b"other"), ("file", b"x")]).await, Err(ApiError::BadRequest)));
+        // maxAvatarSize is 100 KB in tests
+        let large = vec![0u8; 200 * 1024];
+        assert!(matches!(form(&[("file", &large)]).await, Err(ApiError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn upload_hash_verified() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let upload = |data: &'static [u8], hash: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AVATAR_HASH_HEADER, hash.parse().unwrap());
+            upload_avatar(Token("token".to_string()), State(state.clone()), headers, Body::from(data))
+        };
+
+        upload(b"first", &calculate_sha256(b"first").to_uppercase()).await.unwrap();
+        assert_eq!(state.avatars.get(&state.avatars.avatar_path(&uuid)).await.unwrap(), b"first");
+
+        let res = upload(b"second", &calculate_sha256(b"corrupted")).await;
+        assert!(matches!(res, Err(ApiError::BadRequest)));
+        // The previous avatar is untouched
+        assert_eq!(state.avatars.get(&state.avatars.avatar_path(&uuid)).await.unwrap(), b"first");
+    }
+
+    #[test]
+    fn badge_layers() {
+        use crate::state::{AdvancedUsers, Rank};
+        let mut config = AppState::for_tests().config.blocking_read().clone();
+        let uuid = Uuid::from_u128(1);
+        let badges = |config: &Config, rank| equipped_badges(config, &uuid, rank);
+
+        // Base
+        assert_eq!(badges(&config, "donor"), json!({ "special": ([0u8; 6]), "pride": ([0u8; 25]) }));
+
+        // Rank defaults
+        config.ranks.insert("donor".to_string(), Rank { special: Some(vec![0, 0, 0, 1, 0, 0]), ..Default::default() });
+        assert_eq!(badges(&config, "donor")["special"], json!([0, 0, 0, 1, 0, 0]));
+        assert_eq!(badges(&config, "donor")["pride"], json!(([0u8; 25])));
+        assert_eq!(badges(&config, "default")["special"], json!(([0u8; 6])));
+
+        // User overrides
+        let mut pride = [0; 25];
+        pride[2] = 1;
+        let user = AdvancedUsers { username: String::new(), banned: false, special: None, pride: Some(pride.to_vec()), ping_size: None };
+        config.advanced_users.insert(uuid, user);
+        assert_eq!(badges(&config, "donor"), json!({ "special": [0, 0, 0, 1, 0, 0], "pride": pride }));
+        config.advanced_users.get_mut(&uuid).unwrap().special = Some(vec![1, 0, 0, 0, 0, 0]);
+        assert_eq!(badges(&config, "donor")["special"], json!([1, 0, 0, 0, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn rank_badges_shown() {
+        use crate::state::Rank;
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.user_manager.insert_user(uuid, Userinfo { rank: "donor".to_string(), ..Default::default() });
+        state.config.write().await.ranks.insert("donor".to_string(), Rank { special: Some(vec![0, 0, 0, 1, 0, 0]), ..Default::default() });
+        assert!(!state.config.read().await.advanced_users.contains_key(&uuid));
+
+        let res = user_info(Path(uuid), Some(Token("token".to_string())), HeaderMap::new(), State(state.clone())).await.unwrap();
+        let info: Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(info["equippedBadges"]["special"], json!([0, 0, 0, 1, 0, 0]));
+        assert_eq!(info["equippedBadges"]["pride"], json!(vec![0u8; PRIDE_BADGES]));
+    }
+
+    #[test]
+    fn badge_arrays_normalized() {
+        use crate::state::Rank;
+        let mut config = AppState::for_tests().config.blocking_read().clone();
+        let rank = toml::from_str::<Rank>("pride = [1, 1]\nspecial = [0, 0, 0, 1, 0, 0, 1, 1]").unwrap();
+        config.ranks.insert("donor".to_string(), rank);
+        let badges = equipped_badges(&config, &Uuid::from_u128(1), "donor");
+        let mut pride = [0u8; PRIDE_BADGES];
+        pride[..2].copy_from_slice(&[1, 1]);
+        assert_eq!(badges["pride"], json!(pride));
+        assert_eq!(badges["special"], json!([0, 0, 0, 1, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn temp_avatar_cleared() {
+        use crate::api::lambda::internal::{temp_avatar, Host};
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+
+        temp_avatar(Path(uuid), Host("lambda".to_string()), State(state.clone()), Bytes::from_static(b"temp")).await.unwrap();
+        state.user_manager.put_request_temp_state(uuid, true);
+        assert!(state.avatars.temp_path(&uuid).exists());
+
+        delete_temp_avatar(Token("token".to_string()), State(state.clone())).await.unwrap();
+        assert!(!state.avatars.temp_path(&uuid).exists());
+        assert!(!state.user_manager.request_temp_state(uuid, true));
+        // Nothing to clear
+        delete_temp_avatar(Token("token".to_string()), State(state)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn avatar_meta_returned() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"avatar").await.unwrap();
+        let put_meta = |id: &str, meta: AvatarMeta| put_avatar_meta(Path(id.to_string()), Token("token".to_string()), State(state.clone()), Json(meta));
+        let info = || async {
+            let res = user_info(Path(uuid), Some(Token("token".to_string())), HeaderMap::new(), State(state.clone())).await.unwrap();
+            serde_json::from_slice::<Value>(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        state.session.insert(uuid, tx);
+
+        assert!(info().await["equipped"][0].get("meta").is_none());
+        let meta = AvatarMeta { name: Some("Knight".to_string()), author: Some("Tester".to_string()), ..Default::default() };
+        put_meta("avatar", meta).await.unwrap();
+        assert_eq!(info().await["equipped"][0]["meta"], json!({ "name": "Knight", "author": "Tester" }));
+        // Metadata changes don't reload the avatar
+        assert!(rx.try_recv().is_err());
+
+        let long = AvatarMeta { name: Some("n".repeat(65)), ..Default::default() };
+        assert!(matches!(put_meta("avatar", long).await, Err(ApiError::BadRequest)));
+        assert!(matches!(put_meta("other", AvatarMeta::default()).await, Err(ApiError::NotFound)));
+        assert_eq!(info().await["equipped"][0]["meta"]["name"], "Knight");
+
+        assert!(info().await.get("status").is_none());
+        state.statuses.insert(uuid, "Mining".to_string());
+        assert_eq!(info().await["status"], "Mining");
+    }
+
+    #[tokio::test]
+    async fn avatars_listed_by_tag() {
+        let state = AppState::for_tests();
+        let (author, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        authenticated(&state, author, "author");
+        authenticated(&state, other, "other");
+        state.avatars.put(&state.avatars.avatar_path(&author), b"avatar").await.unwrap();
+        let tags = |tags: &[&str]| AvatarMeta { tags: tags.iter().map(|tag| tag.to_string()).collect(), ..Default::default() };
+        let put_meta = |meta: AvatarMeta| put_avatar_meta(Path("avatar".to_string()), Token("author".to_string()), State(state.clone()), Json(meta));
+        let list = |token: &str, tag: Option<&str>| list_avatars(
+            Path(author), Token(token.to_string()), Query(AvatarsQuery { tag: tag.map(str::to_string) }), State(state.clone())
+        );
+
+        for invalid in [tags(&["Knight"]), tags(&["with space"]), tags(&[""]), tags(&["."]), tags(&[&"a".repeat(33)]), tags(&["a"; 17])] {
+            assert!(matches!(put_meta(invalid).await, Err(ApiError::BadRequest)));
+        }
+        put_meta(tags(&["medieval", "armor-3d", ".wip"])).await.unwrap();
+
+        let Json(avatars) = list("other", Some("medieval")).await.unwrap();
+        assert_eq!(avatars[0]["meta"]["tags"], json!(["medieval", "armor-3d"]));
+        assert!(list("other", Some("modern")).await.unwrap().0.is_empty());
+        // Private tags are seen only by the author
+        assert!(list("other", Some(".wip")).await.unwrap().0.is_empty());
+        assert_eq!(list("author", Some(".wip")).await.unwrap().0[0]["meta"]["tags"], json!(["medieval", "armor-3d", ".wip"]));
+        assert_eq!(list("author", None).await.unwrap().0.len(), 1);
+        assert!(matches!(list("unknown", None).await, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_downloads_per_user() {
+        let state = AppState::for_tests();
+        state.config.write().await.limitations.max_concurrent_downloads_per_user = Some(2);
+        let (first, second) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        authenticated(&state, first, "first");
+        authenticated(&state, second, "second");
+        state.avatars.put(&state.avatars.avatar_path(&first), b"avatar").await.unwrap();
+        let download = |token: &str| download_avatar(Path(first), Query(Download { raw: true }), Token(token.to_string()), State(state.clone()));
+
+        // Two downloads of the first user are in progress
+        let permits = [state.user_download_permit(first, 2).unwrap(), state.user_download_permit(first, 2).unwrap()];
+        let res = download("first").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(download("second").await.unwrap().status(), StatusCode::OK);
+
+        drop(permits);
+        assert!(state.user_downloads.is_empty());
+        assert_eq!(download("first").await.unwrap().status(), StatusCode::OK);
+        assert!(state.user_downloads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn downloads_logged() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).with_max_level(tracing::Level::INFO).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState::for_tests();
+        let (requester, target) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        authenticated(&state, requester, "token");
+        state.avatars.put(&state.avatars.avatar_path(&target), b"avatar").await.unwrap();
+        let download = || download_avatar(Path(target), Query(Download { raw: true }), Token("token".to_string()), State(state.clone()));
+        let access_lines = || captured.output()
+            .lines().filter(|line| line.contains(ACCESS_LOG_TARGET)).map(str::to_string).collect::<Vec<_>>();
+
+        download().await.unwrap();
+        let lines = access_lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(&format!("requester={requester}")) && lines[0].contains(&format!("target={target}")));
+
+        state.config.write().await.access_log = false;
+        download().await.unwrap();
+        assert_eq!(access_lines().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn preview_made_on_upload() {
+        let state = AppState::for_tests();
+        let (uploader, viewer) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        authenticated(&state, uploader, "uploader");
+        authenticated(&state, viewer, "viewer");
+        let upload = |data: &'static str| upload_avatar(Token("uploader".to_string()), State(state.clone()), HeaderMap::new(), Body::from(data));
+        let equipped = || async {
+            let res = user_info(Path(uploader), Some(Token("viewer".to_string())), HeaderMap::new(), State(state.clone())).await.unwrap();
+            serde_json::from_slice::<Value>(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()["equipped"][0].clone()
+        };
+
+        // Opt-in
+        upload("first avatar").await.unwrap();
+        assert!(equipped().await.get("preview").is_none());
+        state.config.write().await.storage.previews = true;
+        upload("second avatar").await.unwrap();
+        assert_eq!(equipped().await["preview"], crate::utils::preview_signature(b"second avatar"));
+        assert_eq!(equipped().await["preview"].as_str().unwrap().len(), 128);
+
+        // Stale previews aren't shown
+        state.avatars.put(&state.avatars.avatar_path(&uploader), b"restored avatar").await.unwrap();
+        assert!(equipped().await.get("preview").is_none());
+    }
+
+    #[tokio::test]
+    async fn data_exported() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"avatar").await.unwrap();
+        state.statuses.insert(uuid, "AFK".to_string());
+
+        let Json(data) = export(Token("token".to_string()), State(state.clone())).await.unwrap();
+        for section in ["profile", "avatars", "tempAvatar", "cape", "badges", "ban", "equipped"] {
+            assert!(data.get(section).is_some(), "{section}");
+        }
+        assert_eq!(data["profile"]["nickname"], "Tester");
+        assert_eq!(data["profile"]["status"], "AFK");
+        assert_eq!(data["avatars"], json!([{ "id": AVATAR_ID, "hash": calculate_sha256(b"avatar"), "meta": null }]));
+        assert_eq!(data["ban"]["banned"], false);
+        assert_eq!(data["equipped"]["avatar"], true);
+        assert_eq!(data["badges"]["special"].as_array().unwrap().len(), SPECIAL_BADGES);
+
+        assert!(matches!(export(Token("token".to_string()), State(state)).await, Err(ApiError::TooManyRequests)));
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_completed() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let data = b"avatar uploaded in three chunks";
+        let init = ChunkedInit { size: data.len() as u64, hash: Some(calculate_sha256(data)) };
+        let Json(started) = init_chunked_upload(Token("token".to_string()), State(state.clone()), Json(init)).await.unwrap();
+        let id = started["id"].as_str().unwrap().to_string();
+        let chunk = |n: u32, part: &'static [u8]| upload_chunk(Path((id.clone(), n)), Token("token".to_string()), State(state.clone()), part.into());
+        let complete = || complete_chunked_upload(Path(id.clone()), Token("token".to_string()), State(state.clone()), HeaderMap::new());
+
+        assert!(chunk(0, &data[..10]).await.is_ok());
+        assert!(matches!(chunk(2, &data[20..]).await, Err(ApiError::Conflict)));
+        assert!(chunk(1, &data[10..20]).await.is_ok());
+        // A repeated chunk isn't appended again
+        let Json(status) = chunk(1, &data[10..20]).await.unwrap();
+        assert_eq!(status, json!({ "chunks": 2, "received": 20, "size": data.len() }));
+        assert!(matches!(complete().await, Err(ApiError::BadRequest)));
+        assert!(!state.avatars.avatar_path(&uuid).exists());
+
+        // Sent twice at once, like a retry racing the slow first attempt
+        let (first, second) = tokio::join!(chunk(2, &data[20..]), chunk(2, &data[20..]));
+        assert!(first.is_ok() && second.is_ok());
+        complete().await.unwrap();
+        assert_eq!(state.avatars.get(&state.avatars.avatar_path(&uuid)).await.unwrap(), data);
+        assert!(state.avatars.uploads().await.unwrap().is_empty());
+        assert!(matches!(complete().await, Err(ApiError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn abandoned_chunked_upload_purged() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let init = || init_chunked_upload(Token("token".to_string()), State(state.clone()), Json(ChunkedInit { size: 64, hash: None }));
+        let Json(started) = init().await.unwrap();
+        let id = started["id"].as_str().unwrap().to_string();
+        assert!(upload_chunk(Path((id.clone(), 0)), Token("token".to_string()), State(state.clone()), b"partial".as_slice().into()).await.is_ok());
+
+        assert_eq!(crate::utils::expire_uploads(&state).await.unwrap(), 0);
+        assert_eq!(state.avatars.uploads().await.unwrap(), [id.as_str()]);
+        state.config.write().await.limitations.chunked_upload_ttl = 0;
+        assert_eq!(crate::utils::expire_uploads(&state).await.unwrap(), 1);
+        assert!(state.avatars.uploads().await.unwrap().is_empty());
+        let status = chunked_upload_status(Path(id), Token("token".to_string()), State(state.clone())).await;
+        assert!(matches!(status, Err(ApiError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn uploads_backed_up() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let dir = std::env::temp_dir().join(format!("sculptor-backup-{}", rand::random::<u64>()));
+        let backup = dir.join(format!("{}.moon", format_uuid(&uuid)));
+        let upload = |data: &'static str| upload_avatar(Token("token".to_string()), State(state.clone()), HeaderMap::new(), Body::from(data));
+
+        upload("not backed up").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!dir.exists());
+
+        state.config.write().await.storage.backup_dir = Some(dir.clone());
+        upload("backed up avatar").await.unwrap();
+        for _ in 0..100 {
+            if backup.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.avatars.get(&backup).await.unwrap(), b"backed up avatar");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_upload_rejected() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let upload = |data: &'static [u8]| upload_avatar(Token("token".to_string()), State(state.clone()), HeaderMap::new(), Body::from(data));
+        let path = state.avatars.avatar_path(&uuid);
+
+        assert!(matches!(upload(b"").await, Err(ApiError::BadRequest)));
+        assert!(!path.exists());
+        upload(b"avatar").await.unwrap();
+        state.config.write().await.limitations.min_avatar_size = 16;
+        assert!(matches!(upload(b"truncated").await, Err(ApiError::BadRequest)));
+        // The previous avatar is kept
+        assert_eq!(state.avatars.get(&path).await.unwrap(), b"avatar");
+    }
+
+    #[tokio::test]
+    async fn repeated_upload_key_not_rewritten() {
+        let state = AppState::for_tests();
+        state.config.write().await.limitations.can_upload = true;
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let upload = |data: &'static [u8], key: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(key) = key {
+                headers.insert(IDEMPOTENCY_KEY_HEADER, key.parse().unwrap());
+            }
+            upload_avatar(Token("token".to_string()), State(state.clone()), headers, Body::from(data))
+        };
+        let path = state.avatars.avatar_path(&uuid);
+        let stored = || state.avatars.get(&path);
+
+        upload(b"first", Some("upload-1")).await.unwrap();
+        // The retry is answered without writing
+        assert_eq!(upload(b"retried", Some("upload-1")).await.unwrap(), "ok");
+        assert_eq!(stored().await.unwrap(), b"first");
+        upload(b"second", Some("upload-2")).await.unwrap();
+        assert_eq!(stored().await.unwrap(), b"second");
+        upload(b"third", None).await.unwrap();
+        assert_eq!(stored().await.unwrap(), b"third");
+        assert!(matches!(upload(b"fourth", Some(&"k".repeat(256))).await, Err(ApiError::BadRequest)));
+
+        // Failed uploads can be retried with the same key
+        state.user_manager.put_upload_state(uuid, false);
+        assert!(upload(b"denied", Some("upload-3")).await.is_err());
+        state.user_manager.put_upload_state(uuid, true);
+        upload(b"allowed", Some("upload-3")).await.unwrap();
+        assert_eq!(stored().await.unwrap(), b"allowed");
+    }
+
+    #[tokio::test]
+    async fn large_download_streamed() {
+        use futures_util::StreamExt as _;
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        // Gzipped avatars are stored as is
+        let data: Vec<u8> = [0x1f, 0x8b].into_iter().chain((0..8 * 1024 * 1024).map(|i| (i % 251) as u8)).collect();
+        state.avatars.put(&state.avatars.temp_path(&uuid), &data).await.unwrap();
+
+        let res = download_avatar(Path(uuid), Query(Download { raw: false }), Token("token".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], data.len().to_string());
+        // Deleted only once it's sent
+        assert!(state.avatars.temp_path(&uuid).exists());
+        let mut chunks = res.into_body().into_data_stream();
+        let mut received = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= crate::utils::DOWNLOAD_CHUNK);
+            received.extend_from_slice(&chunk);
+        }
+        assert!(received == data);
+        assert!(!state.avatars.temp_path(&uuid).exists());
+    }
+
+    #[tokio::test]
+    async fn avatar_existence_checked() {
+        let state = AppState::for_tests();
+        let (owner, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        authenticated(&state, owner, "token");
+        let exists = |uuid, raw| avatar_exists(Path(uuid), Query(Download { raw }), Token("token".to_string()), State(state.clone()));
+
+        assert_eq!(exists(other, false).await.unwrap().0, json!({ "exists": false }));
+        state.avatars.put(&state.avatars.avatar_path(&owner), b"committed").await.unwrap();
+        assert_eq!(exists(owner, false).await.unwrap().0, json!({ "exists": true, "hash": calculate_sha256(b"committed") }));
+
+        // Own temp avatar is reported like it's downloaded, and it's kept
+        state.avatars.put(&state.avatars.temp_path(&owner), b"temp").await.unwrap();
+        assert_eq!(exists(owner, false).await.unwrap().0["hash"], calculate_sha256(b"temp"));
+        assert_eq!(exists(owner, true).await.unwrap().0["hash"], calculate_sha256(b"committed"));
+        assert!(state.avatars.temp_path(&owner).exists());
+    }
+
+    #[tokio::test]
+    async fn raw_download_ignores_temp() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"committed").await.unwrap();
+        let download = |raw| {
+            let res = download_avatar(Path(uuid), Query(Download { raw }), Token("token".to_string()), State(state.clone()));
+            async move { axum::body::to_bytes(res.await.unwrap().into_body(), usize::MAX).await.unwrap() }
+        };
+
+        state.avatars.put(&state.avatars.temp_path(&uuid), b"temp").await.unwrap();
+        assert_eq!(download(true).await, &b"committed"[..]);
+        assert!(state.avatars.temp_path(&uuid).exists());
+
+        // Default behavior serves the temp avatar once
+        assert_eq!(download(false).await, &b"temp"[..]);
+        assert!(!state.avatars.temp_path(&uuid).exists());
+        assert_eq!(download(false).await, &b"committed"[..]);
+    }
+
+    #[tokio::test]
+    async fn anonymous_profile_reduced() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"avatar").await.unwrap();
+        state.avatars.put(&state.avatars.temp_path(&uuid), b"temp").await.unwrap();
+        state.statuses.insert(uuid, "away".to_string());
+        let info = |token: Option<&str>| {
+            let state = state.clone();
+            let token = token.map(|token| Token(token.to_string()));
+            async move { user_info(Path(uuid), token, HeaderMap::new(), State(state)).await }
+        };
+        let body = |res: Response| async {
+            serde_json::from_slice::<Value>(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()
+        };
+
+        assert!(matches!(info(None).await, Err(ApiError::Unauthorized)));
+
+        state.config.write().await.allow_anonymous_profiles = true;
+        let public = body(info(None).await.unwrap()).await;
+        let hash = state.avatars.hash(&state.avatars.avatar_path(&uuid)).await.unwrap();
+        assert_eq!(public, json!({
+            "uuid": format_uuid(&uuid),
+            "rank": "default",
+            "equipped": [{ "id": AVATAR_ID, "owner": format_uuid(&uuid), "hash": hash }],
+            "version": Userinfo::default().version
+        }));
+        assert!(!state.user_manager.request_temp_state(uuid, false));
+
+        // The owner still gets the full view with their temp avatar
+        let full = body(info(Some("token")).await.unwrap()).await;
+        assert!(full.get("lastUsed").is_some() && full.get("banned").is_some());
+        assert_eq!(full["status"], json!("away"));
+        assert_ne!(full["equipped"][0]["hash"], json!(hash));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn full_disk_reported() {
+        let mut state = AppState::for_tests();
+        let (webhooks, mut events) = crate::utils::Webhooks::new();
+        state.webhooks = webhooks;
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let Json(started) = init_chunked_upload(Token("token".to_string()), State(state.clone()), Json(ChunkedInit { size: 64, hash: None })).await.unwrap();
+        let id = started["id"].as_str().unwrap().to_string();
+
+        // Writes into /dev/full fail with ENOSPC
+        let part = state.avatars.avatar_path(&uuid).with_file_name("uploads").join(format!("{id}.part"));
+        fs::create_dir_all(part.parent().unwrap()).await.unwrap();
+        fs::symlink("/dev/full", &part).await.unwrap();
+        let res = upload_chunk(Path((id.clone(), 0)), Token("token".to_string()), State(state.clone()), b"chunk".as_slice().into()).await;
+        assert!(matches!(res, Err(ApiError::InsufficientStorage)));
+        assert_eq!(res.unwrap_err().into_response().status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(fs::symlink_metadata(&part).await.is_err());
+        assert!(!state.chunked_uploads.contains(&id));
+        let (event, data) = events.try_recv().unwrap();
+        assert_eq!(event, WebhookEvent::StorageFull);
+        assert_eq!(data["uuid"], json!(uuid));
+
+        // Other write errors stay internal
+        let other = write_error(&state, &uuid, std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(matches!(other, ApiError::Internal));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn avatar_shared_by_link() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        authenticated(&state, uuid, "token");
+        let share = || share_avatar(Path(AVATAR_ID.to_string()), Token("token".to_string()), State(state.clone()));
+        let shared = |token: String| shared_avatar(Path(token), State(state.clone()));
+        assert!(matches!(share().await, Err(ApiError::NotFound)));
+
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"avatar").await.unwrap();
+        let Json(link) = share().await.unwrap();
+        let token = link["token"].as_str().unwrap().to_string();
+        assert_eq!(link["url"], format!("/api/shared/{token}"));
+        let res = shared(token.clone()).await.unwrap();
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), &b"avatar"[..]);
+
+        let tampered = format!("{}{}", &token[..token.len() - 1], if token.ends_with('0') { '1' } else { '0' });
+        assert!(matches!(shared(tampered).await, Err(ApiError::Forbidden)));
+
+        state.config.write().await.sharing.ttl = 0;
+        let Json(link) = share().await.unwrap();
+        assert!(matches!(shared(link["token"].as_str().unwrap().to_string()).await, Err(ApiError::NotFound)));
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, warn, Instrument as _};
+
+use crate::{api::middleware::{forward_request_id, propagate_request_id}, auth::Token, ApiError, ApiResult, AppState};
+
+/// Maximum size of the report body
+pub const REPORT_BODY_LIMIT: usize = 4096;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Report {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub message: String,
+    pub avatar_hash: Option<String>,
+}
+
+pub async fn report(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(report): Json<Report>,
+) -> ApiResult<&'static str> {
+    let user = state.user_manager.get(&token).map(|user| user.clone()).ok_or(ApiError::Unauthorized)?;
+    let settings = state.config.read().await.reports.clone();
+    if !state.report_cooldowns.try_acquire(user.uuid, Duration::from_secs(settings.cooldown)) {
+        return Err(ApiError::TooManyRequests);
+    }
+
+    // Quoted so the reporters can't forge the log lines
+    warn!(
+        "[Report] {} ({}) reported {:?}: {:?} (avatar: {:?})",
+        user.nickname,
+        user.uuid,
+        report.kind,
+        report.message,
+        report.avatar_hash.as_deref().unwrap_or("-")
+    );
+
+    if let Some(webhook) = settings.webhook {
+        let payload = json!({
+            "uuid": user.uuid,
+            "nickname": user.nickname,
+            "report": report,
+        });
+        let client = state.http.clone();
+        tokio::spawn(propagate_request_id(async move {
+            if let Err(e) = forward_request_id(client.post(webhook)).json(&payload).send().await.and_then(|res| res.error_for_status()) {
+                debug!("[Report] Can't forward the report to webhook due: {e:?}");
+            }
+        }).in_current_span());
+    }
+    Ok("ok")
+}
@@ -1,45 +1,72 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
 use anyhow::bail;
-use axum::extract::{ws::{Message, WebSocket}, State};
+use axum::{extract::{ws::{Message, WebSocket}, State}, http::{header, HeaderMap}, response::IntoResponse as _, Extension};
 use dashmap::DashMap;
-use tokio::sync::{broadcast, mpsc};
+use tokio::{sync::{broadcast, mpsc}, time::Instant};
+
+use tracing::Instrument as _;
+
+use crate::{api::middleware::ClientIp, auth::Userinfo, state::{MessageLimits, PingDistance, VersionGate}, ApiError, AppState, FEATURE_LIST_SUBSCRIBERS};
 
-use crate::{auth::Userinfo, AppState};
+use super::{processor::*, AuthModeError, Frame, S2CMessage, C2SMessage, WSSession, SessionMessage, RADError};
 
-use super::{processor::*, AuthModeError, S2CMessage, C2SMessage, WSSession, SessionMessage, RADError};
+/// Notice type sent when pings are dropped
+const NOTICE_PING_RATE: u8 = 1;
+/// Notice type sent when the subscription is rejected
+const NOTICE_SUBSCRIBERS: u8 = 2;
+/// Notice type sent when the status is too long or blocked
+const NOTICE_STATUS: u8 = 3;
+/// Notice type sent when a ping is larger than the ping size of the user
+const NOTICE_PING_SIZE: u8 = 4;
 
 pub async fn initial(
     ws: axum::extract::WebSocketUpgrade,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
     State(state): State<AppState>
 ) -> axum::response::Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    let version = client_version(&headers);
+    let client_ip = client_ip.map(|Extension(ClientIp(ip))| ip);
+    // Counted until the connection is closed
+    let guard = match client_ip {
+        Some(ip) => {
+            let origins = &state.config.read().await.origins;
+            match state.origins.connect(origins.origin(ip), origins.max_connections) {
+                Some(guard) => Some(guard),
+                None => {
+                    tracing::debug!("[WebSocket] Too many connections from the origin of {}", ip);
+                    return ApiError::TooManyRequests.into_response();
+                },
+            }
+        },
+        None => None,
+    };
+    // The upgraded connection is handled outside of the request span
+    let span = tracing::info_span!("websocket", ip = client_ip.map(tracing::field::display));
+    ws.on_upgrade(|socket| async move {
+        handle_socket(socket, state, version).await;
+        drop(guard);
+    }.instrument(span))
 }
 
-async fn handle_socket(mut ws: WebSocket, state: AppState) {
+async fn handle_socket(mut ws: WebSocket, state: AppState, version: Option<semver::Version>) {
+    let gate = state.config.read().await.websocket.version_gate.clone();
     // Trying authenticate & get user data or dropping connection
     match authenticate(&mut ws, &state).await {
-        Ok(user) => {
-
-            // Creating session & creating/getting channels
-            let mut session = {
-                let sub_workers_aborthandles = DashMap::new();
-                
-                // Channel for receiving messages from internal functions.
-                let (own_tx, own_rx) = mpsc::channel(32);
-                state.session.insert(user.uuid, own_tx.clone());
-
-                // Channel for sending messages to subscribers
-                let subs_tx = match state.subscribes.get(&user.uuid) {
-                    Some(tx) => tx.clone(),
-                    None => {
-                        tracing::debug!("[Subscribes] Can't find own subs channel for {}, creating new...", user.uuid);
-                        let (subs_tx, _) = broadcast::channel(32);
-                        state.subscribes.insert(user.uuid, subs_tx.clone());
-                        subs_tx
-                    },
-                };
+        Ok((user, protocol)) if gate.rejects(version.as_ref()) => {
+            tracing::info!(user = user.nickname, version = version.map(tracing::field::display), "[WebSocket] Outdated client rejected");
+            if let Err(kind) = outdated_action(&mut ws, &state, protocol, &gate).await {
+                tracing::warn!("[WebSocket] Didn't get the update message due to {}", kind)
+            }
+            let grace = std::time::Duration::from_secs(state.config.read().await.websocket.reconnect_grace);
+            if let Some(token) = &user.token {
+                state.user_manager.disconnect(&user.uuid, token, grace);
+            }
+        },
+        Ok((user, protocol)) => {
 
-                WSSession { user: user.clone(), own_tx, own_rx, subs_tx, sub_workers_aborthandles }
-            };
+            let mut session = open_session(&state, user.clone(), protocol).await;
 
             // Starting main worker
             match main_worker(&mut session, &mut ws, &state).await {
@@ -47,13 +74,22 @@ async fn handle_socket(mut ws: WebSocket, state: AppState) {
                 Err(kind) => tracing::error!("[WebSocket] Main worker halted due to {}.",  kind),
             }
 
-            for (_, handle) in session.sub_workers_aborthandles {
+            for (uuid, handle) in session.sub_workers_aborthandles {
                 handle.abort();
+                remove_subscriber(&state, uuid, user.uuid);
             }
         
-            // Removing session data
-            state.session.remove(&user.uuid);
-            state.user_manager.remove(&user.uuid);
+            // Removing session data, unless a newer session took its place
+            if state.session.remove_if(&user.uuid, |_, tx| tx.same_channel(&session.own_tx)).is_some() {
+                state.last_pings.remove(&user.uuid);
+                state.positions.remove(&user.uuid);
+                state.statuses.remove(&user.uuid);
+                state.subscribes.remove_if(&user.uuid, |_, tx| tx.receiver_count() == 0);
+            }
+            let grace = std::time::Duration::from_secs(state.config.read().await.websocket.reconnect_grace);
+            if let Some(token) = &user.token {
+                state.user_manager.disconnect(&user.uuid, token, grace);
+            }
         },
         Err(kind) => {
             tracing::info!("[WebSocket] Can't authenticate: {}", kind);
@@ -64,10 +100,57 @@ async fn handle_socket(mut ws: WebSocket, state: AppState) {
     if let Err(kind) = ws.close().await { tracing::trace!("[WebSocket] Closing fault: {}", kind) }
 }
 
+/// Creating session & creating/getting channels
+async fn open_session(state: &AppState, user: Userinfo, protocol: u8) -> WSSession {
+    let sub_workers_aborthandles = DashMap::new();
+
+    // Channel for receiving messages from internal functions.
+    let capacity = state.config.read().await.websocket.session_channel_capacity;
+    let (own_tx, own_rx) = mpsc::channel(capacity.max(1));
+    state.session.insert(user.uuid, own_tx.clone());
+
+    // Channel for sending messages to subscribers
+    let subs_tx = match state.subscribes.get(&user.uuid) {
+        Some(tx) => tx.clone(),
+        None => {
+            tracing::debug!("[Subscribes] Can't find own subs channel for {}, creating new...", user.uuid);
+            let (subs_tx, _) = broadcast::channel(32);
+            state.subscribes.insert(user.uuid, subs_tx.clone());
+            subs_tx
+        },
+    };
+
+    // Subscribers may have a stale avatar of the reconnected user
+    if state.config.read().await.websocket.event_on_reconnect {
+        let _ = subs_tx.send(Frame::new(S2CMessage::Event(user.uuid).into(), &state.metrics));
+    }
+
+    WSSession { user, protocol, own_tx, own_rx, subs_tx, sub_workers_aborthandles }
+}
+
 async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppState) -> anyhow::Result<()> {
     tracing::debug!("WebSocket control for {} is transferred to the main worker", session.user.nickname);
+    let (mut malformed, mut ping_limiter, max_lifetime) = {
+        let settings = &state.config.read().await.websocket;
+        let notice_interval = settings.rate_limit_notice.then(|| Duration::from_secs(settings.rate_limit_notice_interval));
+        (Malformed::new(settings.max_malformed), PingLimiter::new(settings.ping_rate, notice_interval), settings.max_lifetime_secs)
+    };
+    // Never fires without a lifetime
+    let expired = async {
+        match max_lifetime {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
     loop {
         tokio::select! {
+            () = &mut expired => {
+                tracing::info!(user = session.user.nickname, "[WebSocket] Connection reached its max lifetime");
+                state.metrics.server_closed(1001);
+                ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 1001, reason: "Max lifetime reached".into() }))).await?;
+                return Ok(())
+            },
             external_msg = ws.recv_and_decode() => {
 
                 // Getting a value or halt the worker without an error
@@ -75,17 +158,62 @@ async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppSta
                     Ok(m) => m,
                     Err(kind) => {
                         match kind {
-                            RADError::Close(_) => return Ok(()),
-                            RADError::StreamClosed => return Ok(()),
+                            RADError::Close(frame) => {
+                                // 1005 No Status Received
+                                let (code, reason) = frame.map(|f| (f.code, f.reason.to_string())).unwrap_or((1005, String::new()));
+                                tracing::info!(code, reason, user = session.user.nickname, "[WebSocket] Connection closed by client");
+                                state.metrics.client_closed(code);
+                                return Ok(())
+                            },
+                            RADError::StreamClosed => {
+                                // 1006 Abnormal Closure
+                                tracing::info!(code = 1006, user = session.user.nickname, "[WebSocket] Connection lost");
+                                state.metrics.client_closed(1006);
+                                return Ok(())
+                            },
+                            RADError::DecodeError(..) => {
+                                tracing::warn!(user = session.user.nickname, "[WebSocket] Malformed message: {}", kind);
+                                if malformed.failed() {
+                                    state.metrics.server_closed(1007);
+                                    ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 1007, reason: "Malformed messages".into() }))).await?;
+                                    bail!("{} sent too many malformed messages", session.user.nickname)
+                                }
+                                continue
+                            },
                             _ => return Err(kind.into())
                         }
                     },
                 };
+                malformed.reset();
 
                 // Processing message
                 match external_msg {
-                    C2SMessage::Token(_) => bail!("authentication passed, but the client sent the Token again"),
+                    C2SMessage::Token(..) => bail!("authentication passed, but the client sent the Token again"),
                     C2SMessage::Ping(func_id, echo, data) => {
+                        // Oversized pings are rate limited too, so their notices can't flood the client
+                        match ping_limiter.check(Instant::now()) {
+                            PingVerdict::Allow => (),
+                            PingVerdict::Drop { notify } => {
+                                if notify {
+                                    ws.send(Message::Binary(S2CMessage::Notice(NOTICE_PING_RATE).into())).await?
+                                }
+                                continue
+                            },
+                        }
+                        let (replay, distance, max_buffered, ping_size) = {
+                            let config = state.config.read().await;
+                            let settings = &config.websocket;
+                            let ping_size = config.ping_size(&session.user.uuid, &session.user.rank);
+                            (settings.replay_pings, settings.ping_distance.clone(), settings.max_buffered_bytes, ping_size)
+                        };
+                        if data.len() > ping_size {
+                            tracing::debug!("[WebSocket] Ping of {} has {} bytes, over {ping_size}", session.user.nickname, data.len());
+                            ws.send(Message::Binary(S2CMessage::Notice(NOTICE_PING_SIZE).into())).await?;
+                            continue
+                        }
+                        if let Some(position) = distance.and_then(|distance| distance.position(func_id, &data)) {
+                            state.positions.insert(session.user.uuid, position);
+                        }
                         let s2c_ping: Vec<u8> = S2CMessage::Ping(session.user.uuid, func_id, echo, data).into();
                         
                         // Echo check
@@ -93,56 +221,281 @@ async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppSta
                             ws.send(Message::Binary(s2c_ping.clone())).await?
                         }
                         // Sending to others
-                        let _ = session.subs_tx.send(s2c_ping);
+                        if !make_room(state, s2c_ping.len(), max_buffered) {
+                            tracing::debug!("[WebSocket] Buffers are full, dropping the ping of {}", session.user.nickname);
+                            continue
+                        }
+                        record_ping(state, session.user.uuid, &s2c_ping, replay);
+                        let _ = session.subs_tx.send(Frame::new(s2c_ping, &state.metrics));
                     },
                     C2SMessage::Sub(uuid) => {
                         tracing::debug!("[WebSocket] {} subscribes to {}", session.user.nickname, uuid);
                         
                         // Doesn't allow to subscribe to yourself
                         if session.user.uuid != uuid {
-                            // Creates a channel to send pings to a subscriber if it can't find an existing one
-                            let rx = match state.subscribes.get(&uuid) {
-                                Some(tx) => tx.subscribe(),
+                            let (limit, distance) = {
+                                let settings = &state.config.read().await.websocket;
+                                (settings.max_subscribers, settings.ping_distance.clone())
+                            };
+                            match subscribe(state, uuid, limit) {
+                                Some(rx) => {
+                                    for ping in last_pings(state, &uuid) {
+                                        if out_of_range(state, &session.user.uuid, &ping, distance.as_ref()) {
+                                            continue
+                                        }
+                                        ws.send(Message::Binary(ping)).await?
+                                    }
+                                    if let Some(status) = status_message(state, &uuid, session.protocol) {
+                                        ws.send(Message::Binary(status)).await?
+                                    }
+                                    let handle = tokio::spawn(sub_worker(session.own_tx.clone(), rx)).abort_handle();
+                                    match session.sub_workers_aborthandles.insert(uuid, handle) {
+                                        Some(old) => old.abort(),
+                                        None => add_subscriber(state, uuid, session.user.uuid),
+                                    }
+                                },
                                 None => {
-                                    let (tx, rx) = broadcast::channel(32);
-                                    state.subscribes.insert(uuid, tx);
-                                    rx
+                                    tracing::debug!("[WebSocket] {} has too many subscribers, rejecting {}", uuid, session.user.nickname);
+                                    ws.send(Message::Binary(S2CMessage::Notice(NOTICE_SUBSCRIBERS).into())).await?
                                 },
-                            };
-                            let handle = tokio::spawn(sub_worker(session.own_tx.clone(), rx)).abort_handle();
-                            session.sub_workers_aborthandles.insert(uuid, handle);
+                            }
                         }
                     },
                     C2SMessage::Unsub(uuid) => {
                         tracing::debug!("[WebSocket] {} unsubscribes from {}", session.user.nickname, uuid);
 
-                        match session.sub_workers_aborthandles.get(&uuid) {
-                            Some(handle) => handle.abort(),
+                        match session.sub_workers_aborthandles.remove(&uuid) {
+                            Some((_, handle)) => {
+                                handle.abort();
+                                remove_subscriber(state, uuid, session.user.uuid);
+                            },
                             None => tracing::warn!("[WebSocket] {} was not subscribed.", session.user.nickname),
                         };
                     },
+                    C2SMessage::QuerySubscribers => {
+                        let everyone = state.config.read().await.websocket.list_subscribers;
+                        let list = everyone || state.feature_enabled(FEATURE_LIST_SUBSCRIBERS, &session.user.uuid).await;
+                        let frame = subscribers_message(state, &session.user.uuid, list);
+                        ws.send(Message::Binary(frame.into())).await?
+                    },
+                    C2SMessage::Status(status) => {
+                        let limits = state.config.read().await.messages.clone();
+                        match set_status(state, session.user.uuid, status, &limits) {
+                            Some(frame) => {
+                                let _ = session.subs_tx.send(Frame::new(frame, &state.metrics));
+                            },
+                            None => ws.send(Message::Binary(S2CMessage::Notice(NOTICE_STATUS).into())).await?,
+                        }
+                    },
                 }
             },
             internal_msg = session.own_rx.recv() => {
                 let internal_msg = internal_msg.ok_or(anyhow::anyhow!("Unexpected error! Session channel broken!"))?;
                 match internal_msg {
                     SessionMessage::Ping(msg) => {
+                        if S2CMessage::required_protocol(&msg) > session.protocol {
+                            tracing::trace!("[WebSocket] {} can't parse the message, skipping", session.user.nickname);
+                            continue
+                        }
+                        let distance = state.config.read().await.websocket.ping_distance.clone();
+                        if out_of_range(state, &session.user.uuid, &msg, distance.as_ref()) {
+                            continue
+                        }
+                        ws.send(Message::Binary(msg)).await?
+                    },
+                    SessionMessage::Message(msg) => {
+                        let msg = msg.encode(session.protocol);
+                        if S2CMessage::required_protocol(&msg) > session.protocol {
+                            tracing::trace!("[WebSocket] {} can't parse the message, skipping", session.user.nickname);
+                            continue
+                        }
                         ws.send(Message::Binary(msg)).await?
                     },
                     SessionMessage::Banned => {
-                        let _ = ban_action(ws).await
+                        let _ = ban_action(ws, state, session.protocol).await
                             .inspect_err(
                                 |kind| tracing::warn!("[WebSocket] Didn't get the ban message due to {}", kind)
                             );
                         bail!("{} banned!", session.user.nickname)
                     },
+                    SessionMessage::Kick(reason) => {
+                        tracing::info!(user = session.user.nickname, reason, "[WebSocket] Kicked");
+                        state.metrics.server_closed(1000);
+                        ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 1000, reason: reason.into() }))).await?;
+                        return Ok(())
+                    },
                 }
             }
         }
     }
 }
 
-async fn sub_worker(tx_main: mpsc::Sender<SessionMessage>, mut rx: broadcast::Receiver<Vec<u8>>) {
+#[derive(Debug, PartialEq)]
+enum PingVerdict {
+    Allow,
+    /// The notice should be sent if `notify` is set
+    Drop { notify: bool },
+}
+
+/// Pings per second of a connection
+struct PingLimiter {
+    rate: Option<u32>,
+    window: Instant,
+    count: u32,
+    /// None if notices are disabled
+    notice_interval: Option<Duration>,
+    last_notice: Option<Instant>,
+}
+
+impl PingLimiter {
+    fn new(rate: Option<u32>, notice_interval: Option<Duration>) -> Self {
+        Self { rate, window: Instant::now(), count: 0, notice_interval, last_notice: None }
+    }
+    fn check(&mut self, now: Instant) -> PingVerdict {
+        let Some(rate) = self.rate else { return PingVerdict::Allow };
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count <= rate {
+            return PingVerdict::Allow;
+        }
+        let notify = self.notice_interval.is_some_and(|interval| {
+            self.last_notice.is_none_or(|last| now.duration_since(last) >= interval)
+        });
+        if notify {
+            self.last_notice = Some(now);
+        }
+        PingVerdict::Drop { notify }
+    }
+}
+
+/// Consecutive decode failures of a connection
+struct Malformed {
+    count: u32,
+    limit: u32,
+}
+
+impl Malformed {
+    fn new(limit: u32) -> Self {
+        Self { count: 0, limit }
+    }
+    /// Returns true if the limit is reached
+    fn failed(&mut self) -> bool {
+        self.count += 1;
+        self.count >= self.limit
+    }
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+}
+
+/// Creates a channel to send pings to a subscriber if it can't find an existing one.
+/// Returns None if the user already has `limit` subscribers.
+fn subscribe(state: &AppState, uuid: uuid::Uuid, limit: Option<usize>) -> Option<broadcast::Receiver<Frame>> {
+    let tx = state.subscribes.entry(uuid).or_insert_with(|| broadcast::channel(32).0);
+    // The owner only sends, so every receiver is a subscriber
+    if limit.is_some_and(|limit| tx.receiver_count() >= limit) {
+        None
+    } else {
+        Some(tx.subscribe())
+    }
+}
+
+fn add_subscriber(state: &AppState, uuid: uuid::Uuid, subscriber: uuid::Uuid) {
+    *state.subscribers.entry(uuid).or_default().entry(subscriber).or_default() += 1;
+}
+
+fn remove_subscriber(state: &AppState, uuid: uuid::Uuid, subscriber: uuid::Uuid) {
+    if let Some(mut subscribers) = state.subscribers.get_mut(&uuid) {
+        if let Some(count) = subscribers.get_mut(&subscriber) {
+            *count -= 1;
+            if *count == 0 {
+                subscribers.remove(&subscriber);
+            }
+        }
+    }
+    state.subscribers.remove_if(&uuid, |_, subscribers| subscribers.is_empty());
+}
+
+/// Who is subscribed to the user, only the count if `list` isn't set
+fn subscribers_message(state: &AppState, uuid: &uuid::Uuid, list: bool) -> S2CMessage {
+    let subscribers: Vec<uuid::Uuid> = state.subscribers.get(uuid)
+        .map(|subscribers| subscribers.keys().copied().collect())
+        .unwrap_or_default();
+    let count = subscribers.len() as u32;
+    S2CMessage::Subscribers(count, if list { subscribers } else { Vec::new() })
+}
+
+/// Stores the status, or clears it if it's empty, and returns the frame for the subscribers.
+/// Returns None if the status is too long or blocked
+fn set_status(state: &AppState, uuid: uuid::Uuid, status: String, limits: &MessageLimits) -> Option<Vec<u8>> {
+    let status = limits.limit(status, limits.max_status_length).filter(|status| !limits.is_blocked(status))?;
+    if status.is_empty() {
+        state.statuses.remove(&uuid);
+    } else {
+        state.statuses.insert(uuid, status.clone());
+    }
+    Some(S2CMessage::Status(uuid, status).into())
+}
+
+/// Current status of the user for a new subscriber, if it can parse it
+fn status_message(state: &AppState, uuid: &uuid::Uuid, protocol: u8) -> Option<Vec<u8>> {
+    let status = state.statuses.get(uuid)?.clone();
+    let frame: Vec<u8> = S2CMessage::Status(*uuid, status).into();
+    (S2CMessage::required_protocol(&frame) <= protocol).then_some(frame)
+}
+
+/// Keeps the last `size` pings of the user
+fn record_ping(state: &AppState, uuid: uuid::Uuid, ping: &[u8], size: usize) {
+    if size == 0 {
+        return;
+    }
+    let mut pings = state.last_pings.entry(uuid).or_default();
+    while pings.len() >= size {
+        pings.pop_front();
+    }
+    pings.push_back(Frame::new(ping.to_vec(), &state.metrics));
+}
+
+fn last_pings(state: &AppState, uuid: &uuid::Uuid) -> Vec<Vec<u8>> {
+    state.last_pings.get(uuid).map(|pings| pings.iter().map(|ping| ping.to_vec()).collect()).unwrap_or_default()
+}
+
+/// Makes room for `size` more buffered bytes by dropping the oldest replayed pings of every user.
+/// Returns false if the pings still waiting for subscribers take too much
+fn make_room(state: &AppState, size: usize, limit: Option<usize>) -> bool {
+    let Some(limit) = limit else { return true };
+    let fits = || state.metrics.buffered_bytes.load(Ordering::Relaxed) + size <= limit;
+    while !fits() {
+        let mut dropped = false;
+        for mut pings in state.last_pings.iter_mut() {
+            dropped |= pings.pop_front().is_some();
+            if fits() {
+                return true;
+            }
+        }
+        if !dropped {
+            return false;
+        }
+    }
+    true
+}
+
+/// The ping carries a position too far from the recipient.
+/// Recipients without a known position get every ping
+fn out_of_range(state: &AppState, recipient: &uuid::Uuid, frame: &[u8], distance: Option<&PingDistance>) -> bool {
+    let Some(distance) = distance else { return false };
+    if frame.first() != Some(&1) {
+        return false;
+    }
+    let Ok(S2CMessage::Ping(_, func_id, _, data)) = S2CMessage::try_from(frame) else { return false };
+    let Some(position) = distance.position(func_id, &data) else { return false };
+    state.positions.get(recipient).is_some_and(|own| !distance.in_range(*own, position))
+}
+
+async fn sub_worker(tx_main: mpsc::Sender<SessionMessage>, mut rx: broadcast::Receiver<Frame>) {
     loop {
         let msg = match rx.recv().await {
             Ok(m) => m,
@@ -151,7 +504,7 @@ async fn sub_worker(tx_main: mpsc::Sender<SessionMessage>, mut rx: broadcast::Re
                 return;
             },
         };
-        match tx_main.send(SessionMessage::Ping(msg)).await {
+        match forward(&tx_main, SessionMessage::Ping(msg.into_bytes())).await {
             Ok(_) => (),
             Err(kind) => {
                 tracing::error!("[Subscribes_Worker] Session error! {}", kind);
@@ -161,27 +514,42 @@ async fn sub_worker(tx_main: mpsc::Sender<SessionMessage>, mut rx: broadcast::Re
     }
 }
 
-async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<Userinfo, AuthModeError> {
+/// Waits for room in the session channel, telling the operator when it's full
+async fn forward(tx: &mpsc::Sender<SessionMessage>, msg: SessionMessage) -> Result<(), mpsc::error::SendError<SessionMessage>> {
+    match tx.try_send(msg) {
+        Ok(()) => Ok(()),
+        Err(mpsc::error::TrySendError::Full(msg)) => {
+            tracing::warn!("[Subscribes_Worker] Session channel is full, consider raising websocket.sessionChannelCapacity");
+            tx.send(msg).await
+        },
+        Err(mpsc::error::TrySendError::Closed(msg)) => Err(mpsc::error::SendError(msg)),
+    }
+}
+
+async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<(Userinfo, u8), AuthModeError> {
     match socket.recv_and_decode().await {
         Ok(msg) => {
             match msg {
-                C2SMessage::Token(token) => {
+                C2SMessage::Token(token, protocol) => {
                     let token = String::from_utf8(token.to_vec()).map_err(|_| AuthModeError::ConvertError)?;
-                    match state.user_manager.get(&token) {
+                    let user = state.user_manager.get(&token).map(|user| user.clone());
+                    match user {
                         Some(user) => {
                             if socket.send(Message::Binary(S2CMessage::Auth.into())).await.is_err() {
                                 Err(AuthModeError::SendError)
-                            } else if !user.banned {
-                                Ok(user.clone())
+                            } else if !state.is_banned(&user.uuid) {
+                                state.user_manager.mark_connected(&user.uuid);
+                                Ok((user, protocol))
                             } else {
-                                let _ = ban_action(socket).await
+                                let _ = ban_action(socket, state, protocol).await
                                     .inspect_err(
                                         |kind| tracing::warn!("[WebSocket] Didn't get the ban message due to {}", kind)
                                     );
-                                Err(AuthModeError::Banned(user.nickname.clone()))
+                                Err(AuthModeError::Banned(user.nickname))
                             }
                         },
                         None => {
+                            state.metrics.server_closed(4000);
                             if socket.send(
                                 Message::Close(Some(axum::extract::ws::CloseFrame { code: 4000, reason: "Re-auth".into() }))
                             ).await.is_err() {
@@ -203,10 +571,424 @@ async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<Userin
     }
 }
 
-async fn ban_action(ws: &mut WebSocket) -> anyhow::Result<()> {
-    ws.send(Message::Binary(S2CMessage::Toast(2, "You're banned!".to_string(), None).into())).await?;
-    tokio::time::sleep(std::time::Duration::from_secs(6)).await;
+/// Figura version from the `Figura/<version>` product of the User-Agent
+fn client_version(headers: &HeaderMap) -> Option<semver::Version> {
+    let agent = headers.get(header::USER_AGENT)?.to_str().ok()?;
+    agent.split_whitespace()
+        .find_map(|product| product.strip_prefix("Figura/"))
+        .and_then(|version| semver::Version::parse(version).ok())
+}
+
+async fn outdated_action(ws: &mut WebSocket, state: &AppState, protocol: u8, gate: &VersionGate) -> anyhow::Result<()> {
+    state.metrics.server_closed(4002);
+    let message = gate.message.replace("{version}", &gate.min_version);
+    ws.send(Message::Binary(S2CMessage::Toast(2, "Outdated Figura".to_string(), Some(message)).encode(protocol))).await?;
+    tokio::time::sleep(Duration::from_secs(gate.grace)).await;
+    ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 4002, reason: "Outdated client".into() }))).await?;
+
+    Ok(())
+}
+
+/// Shows the ban once and closes, the frames sent meanwhile are never processed
+async fn ban_action(ws: &mut WebSocket, state: &AppState, protocol: u8) -> anyhow::Result<()> {
+    state.metrics.server_closed(4001);
+    let grace = Duration::from_secs(state.config.read().await.websocket.ban_grace);
+    ws.send(Message::Binary(S2CMessage::Toast(2, "You're banned!".to_string(), None).encode(protocol))).await?;
+    tokio::time::sleep(grace).await;
     ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 4001, reason: "You're banned!".into() }))).await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn subscribers_tracked() {
+        let state = AppState::for_tests();
+        let (owner, first, second) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        assert_eq!(subscribers_message(&state, &owner, true), S2CMessage::Subscribers(0, Vec::new()));
+
+        add_subscriber(&state, owner, first);
+        add_subscriber(&state, owner, first); // From a second session
+        add_subscriber(&state, owner, second);
+        remove_subscriber(&state, owner, first);
+        let S2CMessage::Subscribers(count, mut list) = subscribers_message(&state, &owner, true) else { unreachable!() };
+        list.sort();
+        assert_eq!((count, list), (2, vec![first, second]));
+        // Only the count without the permission
+        assert_eq!(subscribers_message(&state, &owner, false), S2CMessage::Subscribers(2, Vec::new()));
+
+        remove_subscriber(&state, owner, first);
+        remove_subscriber(&state, owner, second);
+        assert!(state.subscribers.is_empty());
+    }
+
+    #[test]
+    fn outdated_clients_rejected() {
+        let agent = |agent: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, agent.parse().unwrap());
+            client_version(&headers)
+        };
+        let old = agent("Figura/0.1.3+1.20.1");
+        let current = agent("Java-http-client/21 Figura/0.1.5+1.21.1");
+        assert_eq!(current, Some(semver::Version::parse("0.1.5+1.21.1").unwrap()));
+        assert_eq!(agent("Mozilla/5.0"), None);
+        assert_eq!(client_version(&HeaderMap::new()), None);
+
+        let mut gate = VersionGate { min_version: "0.1.5".to_string(), ..Default::default() };
+        assert!(!gate.rejects(old.as_ref()));
+        gate.enabled = true;
+        assert!(gate.rejects(old.as_ref()));
+        assert!(!gate.rejects(current.as_ref()));
+        // Unknown versions are let in
+        assert!(!gate.rejects(None));
+    }
+
+    #[test]
+    fn status_set_and_observed() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        let mut limits = MessageLimits { max_status_length: 8, blocked_words: vec!["Spam".to_string()], ..Default::default() };
+
+        let frame = set_status(&state, uuid, "Mining".to_string(), &limits).unwrap();
+        assert_eq!(S2CMessage::try_from(frame.as_slice()).unwrap(), S2CMessage::Status(uuid, "Mining".to_string()));
+        // New subscribers get it too, unless they can't parse it
+        assert_eq!(status_message(&state, &uuid, 4), Some(frame));
+        assert_eq!(status_message(&state, &uuid, 3), None);
+
+        // Rejected ones keep the old status
+        assert!(set_status(&state, uuid, "Too long status".to_string(), &limits).is_none());
+        assert!(set_status(&state, uuid, "no SPAM".to_string(), &limits).is_none());
+        assert_eq!(*state.statuses.get(&uuid).unwrap(), "Mining");
+        limits.truncate = true;
+        set_status(&state, uuid, "Too long status".to_string(), &limits).unwrap();
+        assert_eq!(*state.statuses.get(&uuid).unwrap(), "Too long");
+
+        set_status(&state, uuid, String::new(), &limits).unwrap();
+        assert!(state.statuses.get(&uuid).is_none());
+        assert_eq!(status_message(&state, &uuid, 4), None);
+    }
+
+    #[tokio::test]
+    async fn subscribers_notified_on_reconnect() {
+        let state = AppState::for_tests();
+        let user = Userinfo { uuid: Uuid::from_u128(1), ..Default::default() };
+        let (tx, mut rx) = broadcast::channel(32);
+        state.subscribes.insert(user.uuid, tx);
+
+        open_session(&state, user.clone(), 0).await;
+        let expected: Vec<u8> = S2CMessage::Event(user.uuid).into();
+        assert_eq!(rx.try_recv().unwrap().to_vec(), expected);
+
+        state.config.write().await.websocket.event_on_reconnect = false;
+        open_session(&state, user, 0).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_session_channel_waits() {
+        let state = AppState::for_tests();
+        state.config.write().await.websocket.session_channel_capacity = 1;
+        let mut session = open_session(&state, Userinfo { uuid: Uuid::from_u128(1), ..Default::default() }, 0).await;
+        assert_eq!(session.own_tx.max_capacity(), 1);
+
+        let (tx, rx) = broadcast::channel(32);
+        let worker = tokio::spawn(sub_worker(session.own_tx.clone(), rx));
+        for ping in [1, 2, 3] {
+            tx.send(Frame::new(vec![ping], &state.metrics)).unwrap();
+        }
+        // Nothing is dropped, the worker waits for room
+        for ping in [1, 2, 3] {
+            let Some(SessionMessage::Ping(frame)) = session.own_rx.recv().await else { panic!("ping expected") };
+            assert_eq!(frame, vec![ping]);
+        }
+        worker.abort();
+    }
+
+    #[tokio::test]
+    async fn unused_broadcasts_purged() {
+        let state = AppState::for_tests();
+        let (online, offline, watched) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        open_session(&state, Userinfo { uuid: online, ..Default::default() }, 0).await;
+        let _subscriber = subscribe(&state, watched, None).unwrap();
+        subscribe(&state, offline, None).unwrap();
+        record_ping(&state, offline, &[0], 1);
+
+        assert_eq!(state.purge_broadcasts(), 1);
+        assert!(!state.subscribes.contains_key(&offline));
+        assert!(!state.last_pings.contains_key(&offline));
+        assert!(state.subscribes.contains_key(&online) && state.subscribes.contains_key(&watched));
+
+        // The owner disconnected
+        state.session.remove(&online);
+        assert_eq!(state.purge_broadcasts(), 1);
+        assert!(!state.subscribes.contains_key(&online));
+    }
+
+    #[tokio::test]
+    async fn oversubscribed_user() {
+        let state = AppState::for_tests();
+        let celebrity = Uuid::from_u128(1);
+
+        let subscribers: Vec<_> = (0..3).map(|_| subscribe(&state, celebrity, Some(3)).unwrap()).collect();
+        assert!(subscribe(&state, celebrity, Some(3)).is_none());
+        assert_eq!(state.subscribes.get(&celebrity).unwrap().receiver_count(), 3);
+        // Unlimited by default
+        assert!(subscribe(&state, celebrity, None).is_some());
+
+        // Unsubscribing frees a slot
+        drop(subscribers);
+        assert!(subscribe(&state, celebrity, Some(3)).is_some());
+    }
+
+    #[test]
+    fn ping_rate_notice_throttled() {
+        let start = Instant::now();
+        let mut limiter = PingLimiter::new(Some(2), Some(Duration::from_secs(10)));
+        let mut notices = 0;
+        // 5 seconds of 10 pings per second
+        for i in 0..50 {
+            let now = start + Duration::from_millis(i * 100);
+            match limiter.check(now) {
+                PingVerdict::Allow => (),
+                PingVerdict::Drop { notify } => notices += notify as u32,
+            }
+        }
+        assert_eq!(notices, 1);
+        assert_eq!(limiter.check(start + Duration::from_secs(11)), PingVerdict::Allow);
+        limiter.check(start + Duration::from_secs(11));
+        assert_eq!(limiter.check(start + Duration::from_secs(11)), PingVerdict::Drop { notify: true });
+
+        let mut silent = PingLimiter::new(Some(1), None);
+        silent.check(start);
+        assert_eq!(silent.check(start), PingVerdict::Drop { notify: false });
+        assert_eq!(PingLimiter::new(None, None).check(start), PingVerdict::Allow);
+    }
+
+    #[test]
+    fn malformed_limit() {
+        let mut malformed = Malformed::new(3);
+        assert!(!malformed.failed());
+        assert!(!malformed.failed());
+        // Valid message in between
+        malformed.reset();
+        assert!(!malformed.failed());
+        assert!(!malformed.failed());
+        assert!(malformed.failed());
+    }
+
+    #[test]
+    fn last_pings_replayed() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        assert!(last_pings(&state, &uuid).is_empty());
+
+        for i in 0..5u8 {
+            record_ping(&state, uuid, &[i], 2);
+        }
+        assert_eq!(last_pings(&state, &uuid), vec![vec![3], vec![4]]);
+
+        record_ping(&state, Uuid::from_u128(2), &[0], 0);
+        assert!(!state.last_pings.contains_key(&Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn buffered_bytes_accounted() {
+        let state = AppState::for_tests();
+        let (first, second) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let buffered = || state.metrics.buffered_bytes.load(Ordering::Relaxed);
+        let mut rx = subscribe(&state, first, None).unwrap();
+
+        record_ping(&state, first, &[0; 100], 2);
+        record_ping(&state, second, &[0; 50], 2);
+        state.subscribes.get(&first).unwrap().send(Frame::new(vec![0; 30], &state.metrics)).unwrap();
+        assert_eq!(buffered(), 180);
+        // Received by the only subscriber
+        assert_eq!(rx.try_recv().unwrap().into_bytes().len(), 30);
+        assert_eq!(buffered(), 150);
+
+        // The oldest replayed pings are dropped to fit
+        record_ping(&state, first, &[0; 100], 2);
+        assert!(make_room(&state, 60, Some(250)));
+        let kept: usize = [first, second].iter().flat_map(|uuid| last_pings(&state, uuid)).map(|ping| ping.len()).sum();
+        assert!(buffered() <= 190);
+        assert_eq!(buffered(), kept);
+        assert!(!make_room(&state, 300, Some(250)));
+        assert_eq!(buffered(), 0);
+        assert!(make_room(&state, 300, None));
+    }
+
+    #[test]
+    fn distant_subscribers_filtered() {
+        let state = AppState::for_tests();
+        let (owner, near, far) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let distance = PingDistance { max_distance: 64.0, functions: vec![7] };
+        let ping = |func_id: u32, position: [f64; 3]| -> Vec<u8> {
+            let data = position.iter().flat_map(|c| c.to_be_bytes()).chain([1, 2, 3]).collect();
+            S2CMessage::Ping(owner, func_id, false, data).into()
+        };
+        state.positions.insert(near, [10.0, 64.0, 10.0]);
+        state.positions.insert(far, [500.0, 64.0, 10.0]);
+
+        let frame = ping(7, [0.0, 64.0, 0.0]);
+        assert!(!out_of_range(&state, &near, &frame, Some(&distance)));
+        assert!(out_of_range(&state, &far, &frame, Some(&distance)));
+        // Without a known position, other functions, or disabled
+        assert!(!out_of_range(&state, &Uuid::from_u128(4), &frame, Some(&distance)));
+        assert!(!out_of_range(&state, &far, &ping(8, [0.0, 64.0, 0.0]), Some(&distance)));
+        assert!(!out_of_range(&state, &far, &frame, None));
+        assert!(!out_of_range(&state, &far, &Vec::<u8>::from(S2CMessage::Event(owner)), Some(&distance)));
+
+        assert_eq!(distance.position(7, &[0; 23]), None);
+        assert_eq!(distance.position(7, &[0xff; 24]), None); // NaN
+    }
+
+    use tokio::{io::{AsyncReadExt as _, AsyncWriteExt as _}, net::{TcpListener, TcpStream}};
+
+    /// Server frame as (opcode, payload), or None when the connection is gone
+    async fn read_frame(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.ok()?;
+        let len = match head[1] & 0x7f {
+            126 => stream.read_u16().await.ok()? as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.ok()?;
+        Some((head[0] & 0x0f, payload))
+    }
+
+    /// Binary client frame with a zero mask
+    fn frame(payload: Vec<u8>) -> Vec<u8> {
+        [vec![0x82, 0x80 | payload.len() as u8, 0, 0, 0, 0], payload].concat()
+    }
+
+    /// Serves the WebSocket and connects to it as an authenticated user with the "token" token
+    async fn connect(state: &AppState, uuid: Uuid) -> TcpStream {
+        connect_with(state, uuid, "").await
+    }
+
+    /// Like `connect`, with extra headers in the upgrade request
+    async fn connect_with(state: &AppState, uuid: Uuid, headers: &str) -> TcpStream {
+        let user = Userinfo { uuid, nickname: "Tester".to_string(), token: Some("token".to_string()), ..Default::default() };
+        state.user_manager.insert(uuid, "token".to_string(), user).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/ws", axum::routing::get(initial)).with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{headers}\r\n").as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+
+        stream.write_all(&frame(C2SMessage::Token(b"token".to_vec(), 0).into())).await.unwrap();
+        assert_eq!(read_frame(&mut stream).await, Some((2, Vec::from(S2CMessage::Auth))));
+        stream
+    }
+
+    /// Close code of the first close frame
+    async fn close_code(stream: &mut TcpStream) -> Option<u16> {
+        while let Some((opcode, payload)) = read_frame(stream).await {
+            if opcode == 8 {
+                return Some(u16::from_be_bytes([payload[0], payload[1]]));
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn banned_flood_handled_once() {
+        let state = AppState::for_tests();
+        state.config.write().await.websocket.ban_grace = 0;
+        let uuid = Uuid::from_u128(1);
+        let mut stream = connect(&state, uuid).await;
+        let tx = loop {
+            if let Some(tx) = state.session.get(&uuid).map(|tx| tx.clone()) { break tx }
+            tokio::task::yield_now().await;
+        };
+
+        let pings: Vec<u8> = (0..100).flat_map(|_| frame(C2SMessage::Ping(0, false, vec![0; 8]).into())).collect();
+        stream.write_all(&pings).await.unwrap();
+        tx.send(SessionMessage::Banned).await.unwrap();
+        tx.send(SessionMessage::Banned).await.unwrap();
+        let _ = stream.write_all(&pings).await;
+
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut stream).await {
+            frames.push(frame);
+        }
+        let toasts = frames.iter().filter(|(opcode, payload)| *opcode == 2 && payload.first() == Some(&3)).count();
+        let closes: Vec<_> = frames.iter().filter(|(opcode, _)| *opcode == 8).collect();
+        assert_eq!(toasts, 1);
+        assert_eq!(closes.len(), 1);
+        assert_eq!(closes[0].1[..2], 4001u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn oversized_pings_limited() {
+        let state = AppState::for_tests();
+        {
+            let settings = &mut state.config.write().await.websocket;
+            settings.ping_size = 8;
+            settings.ping_rate = Some(1);
+            settings.rate_limit_notice = false;
+        }
+        let uuid = Uuid::from_u128(1);
+        let mut stream = connect(&state, uuid).await;
+        let oversized: Vec<u8> = (0..5).flat_map(|_| frame(C2SMessage::Ping(0, true, vec![0; 16]).into())).collect();
+        stream.write_all(&oversized).await.unwrap();
+        assert_eq!(read_frame(&mut stream).await, Some((2, Vec::from(S2CMessage::Notice(NOTICE_PING_SIZE)))));
+
+        // The size is read again after a reload
+        state.config.write().await.websocket.ping_size = 64;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        stream.write_all(&frame(C2SMessage::Ping(0, true, vec![0; 16]).into())).await.unwrap();
+        // Only one notice before the echo, the other pings were over the rate
+        assert_eq!(read_frame(&mut stream).await, Some((2, Vec::from(S2CMessage::Ping(uuid, 0, true, vec![0; 16])))));
+    }
+
+    #[tokio::test]
+    async fn outdated_client_disconnected() {
+        let state = AppState::for_tests();
+        {
+            let gate = &mut state.config.write().await.websocket.version_gate;
+            gate.enabled = true;
+            gate.min_version = "0.1.4".to_string();
+            gate.grace = 0;
+        }
+        let uuid = Uuid::from_u128(1);
+        let mut stream = connect_with(&state, uuid, "User-Agent: Figura/0.1.0\r\n").await;
+
+        let (opcode, payload) = read_frame(&mut stream).await.unwrap();
+        assert_eq!((opcode, payload[0]), (2, 3));
+        assert_eq!(close_code(&mut stream).await, Some(4002));
+        assert!(!state.session.contains_key(&uuid));
+        assert_eq!(state.metrics.server_close_codes.get(&4002).map(|count| *count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn connection_closed_after_lifetime() {
+        let state = AppState::for_tests();
+        state.config.write().await.websocket.max_lifetime_secs = Some(1);
+        let mut stream = connect(&state, Uuid::from_u128(1)).await;
+        let opened = std::time::Instant::now();
+
+        // Pings don't keep it open
+        stream.write_all(&frame(C2SMessage::Ping(0, false, vec![0; 8]).into())).await.unwrap();
+        let code = tokio::time::timeout(Duration::from_secs(5), close_code(&mut stream)).await.unwrap();
+        assert_eq!(code, Some(1001));
+        assert!(opened.elapsed() >= Duration::from_millis(900));
+        assert_eq!(state.metrics.server_close_codes.get(&1001).map(|count| *count), Some(1));
+    }
+}
@@ -12,7 +12,7 @@ impl RecvAndDecode for WebSocket {
             match msg {
                 Ok(msg) => {
                     match msg {
-                        Message::Close(frame) => Err(RADError::Close(frame.map(|f| format!("code: {}, reason: {}", f.code, f.reason)))),
+                        Message::Close(frame) => Err(RADError::Close(frame)),
                         _ => {
                             match C2SMessage::try_from(msg.clone().into_data().as_slice()) {
                                 Ok(decoded) => Ok(decoded),
@@ -6,10 +6,15 @@ use std::convert::{TryFrom, TryInto};
 #[repr(u8)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum C2SMessage {
-    Token(Vec<u8>) = 0,
+    /// Token and the protocol version of the client
+    Token(Vec<u8>, u8) = 0,
     Ping(u32, bool, Vec<u8>) = 1,
     Sub(Uuid) = 2, // owo
     Unsub(Uuid) = 3,
+    /// Asks who is subscribed to the user
+    QuerySubscribers = 4,
+    /// Status shown to the subscribers, empty clears it
+    Status(String) = 5,
 }
 // 6 - 6
 impl TryFrom<&[u8]> for C2SMessage {
@@ -19,7 +24,13 @@ impl TryFrom<&[u8]> for C2SMessage {
             Err(MessageLoadError::BadLength("C2SMessage", 1, false, 0))
         } else {
             match buf[0] {
-                0 => Ok(C2SMessage::Token(buf[1..].to_vec())),
+                0 => {
+                    // Tokens are printable, a trailing control byte is the protocol version
+                    match buf[1..].split_last() {
+                        Some((&version, token)) if version < 0x20 => Ok(C2SMessage::Token(token.to_vec(), version)),
+                        _ => Ok(C2SMessage::Token(buf[1..].to_vec(), 0)),
+                    }
+                }
                 1 => {
                     if buf.len() >= 6 {
                         Ok(C2SMessage::Ping(
@@ -64,9 +75,24 @@ impl TryFrom<&[u8]> for C2SMessage {
                         ))
                     }
                 }
+                4 => {
+                    if buf.len() == 1 {
+                        Ok(C2SMessage::QuerySubscribers)
+                    } else {
+                        Err(MessageLoadError::BadLength(
+                            "C2SMessage::QuerySubscribers",
+                            1,
+                            true,
+                            buf.len(),
+                        ))
+                    }
+                }
+                5 => String::from_utf8(buf[1..].to_vec())
+                    .map(C2SMessage::Status)
+                    .map_err(|_| MessageLoadError::InvalidUtf8("C2SMessage::Status")),
                 a => Err(MessageLoadError::BadEnum(
                     "C2SMessage.type",
-                    0..=3,
+                    0..=5,
                     a.into(),
                 )),
             }
@@ -77,7 +103,10 @@ impl From<C2SMessage> for Vec<u8> {
     fn from(val: C2SMessage) -> Self {
         use std::iter;
         let a: Vec<u8> = match val {
-            C2SMessage::Token(t) => iter::once(0).chain(t.iter().copied()).collect(),
+            C2SMessage::Token(t, v) => iter::once(0)
+                .chain(t.iter().copied())
+                .chain((v != 0).then_some(v))
+                .collect(),
             C2SMessage::Ping(p, s, d) => iter::once(1)
                 .chain(p.to_be_bytes())
                 .chain(iter::once(s.into()))
@@ -85,11 +114,50 @@ impl From<C2SMessage> for Vec<u8> {
                 .collect(),
             C2SMessage::Sub(s) => iter::once(2).chain(s.into_bytes()).collect(),
             C2SMessage::Unsub(s) => iter::once(3).chain(s.into_bytes()).collect(),
+            C2SMessage::QuerySubscribers => vec![4],
+            C2SMessage::Status(s) => iter::once(5).chain(s.into_bytes()).collect(),
         };
         a
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_protocol_version() {
+        let token = b"0123456789abcdef".to_vec();
+        let frame = |tail: &[u8]| [&[0u8][..], &token, tail].concat();
+
+        assert_eq!(C2SMessage::try_from(frame(&[]).as_slice()).unwrap(), C2SMessage::Token(token.clone(), 0));
+        assert_eq!(C2SMessage::try_from(frame(&[1]).as_slice()).unwrap(), C2SMessage::Token(token.clone(), 1));
+        assert_eq!(C2SMessage::try_from(&[0u8][..]).unwrap(), C2SMessage::Token(Vec::new(), 0));
+
+        for message in [C2SMessage::Token(token.clone(), 0), C2SMessage::Token(token, 2)] {
+            let bytes: Vec<u8> = message.clone().into();
+            assert_eq!(C2SMessage::try_from(bytes.as_slice()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn query_subscribers() {
+        let bytes: Vec<u8> = C2SMessage::QuerySubscribers.into();
+        assert_eq!(bytes, [4]);
+        assert_eq!(C2SMessage::try_from(bytes.as_slice()).unwrap(), C2SMessage::QuerySubscribers);
+        assert!(C2SMessage::try_from(&[4u8, 0][..]).is_err());
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let message = C2SMessage::Status("Building a castle".to_string());
+        let bytes: Vec<u8> = message.clone().into();
+        assert_eq!(C2SMessage::try_from(bytes.as_slice()).unwrap(), message);
+        assert_eq!(C2SMessage::try_from(&[5u8][..]).unwrap(), C2SMessage::Status(String::new()));
+        assert!(C2SMessage::try_from(&[5u8, 0xff][..]).is_err());
+    }
+}
+
 // impl<'a> C2SMessage<'a> {
 //     pub fn to_array(&self) -> Box<[u8]> {
 //         <C2SMessage as Into<Box<[u8]>>>::into(self.clone())
@@ -1,12 +1,14 @@
 use std::fmt::*;
 use std::ops::RangeInclusive;
 
+use axum::extract::ws::CloseFrame;
 use thiserror::Error;
 
 #[derive(Debug)]
 pub enum MessageLoadError {
     BadEnum(&'static str, RangeInclusive<usize>, usize),
     BadLength(&'static str, usize, bool, usize),
+    InvalidUtf8(&'static str),
 }
 impl Display for MessageLoadError {
     fn fmt(&self, fmt: &mut Formatter) -> Result {
@@ -22,6 +24,7 @@ impl Display for MessageLoadError {
                 "buffer wrong size for {f}: must be {} {n} bytes, got {c}",
                 if *e { "exactly" } else { "at least" }
             ),
+            Self::InvalidUtf8(f) => write!(fmt, "invalid UTF-8 in {f}"),
         }
     }
 }
@@ -31,7 +34,7 @@ pub enum RADError {
     #[error("message decode error due: {0}, invalid data: {1}")]
     DecodeError(MessageLoadError, String),
     #[error("close, frame: {0:?}")]
-    Close(Option<String>),
+    Close(Option<CloseFrame<'static>>),
     #[error(transparent)]
     WebSocketError(#[from] axum::Error),
     #[error("stream closed")]
@@ -0,0 +1,48 @@
+use std::{ops::Deref, sync::{atomic::Ordering, Arc}};
+
+use crate::state::Metrics;
+
+/// Message kept in the broadcast channels and the replay buffers.
+/// Its size is counted in the metrics as long as it lives
+pub struct Frame {
+    bytes: Vec<u8>,
+    metrics: Arc<Metrics>,
+}
+
+impl Frame {
+    pub fn new(bytes: Vec<u8>, metrics: &Arc<Metrics>) -> Self {
+        metrics.buffered_bytes.fetch_add(bytes.len(), Ordering::Relaxed);
+        Self { bytes, metrics: metrics.clone() }
+    }
+    /// The bytes aren't counted anymore
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.metrics.buffered_bytes.fetch_sub(self.bytes.len(), Ordering::Relaxed);
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+impl Clone for Frame {
+    fn clone(&self) -> Self {
+        Self::new(self.bytes.clone(), &self.metrics)
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        self.metrics.buffered_bytes.fetch_sub(self.bytes.len(), Ordering::Relaxed);
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl std::fmt::Debug for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Frame").field(&self.bytes.len()).finish()
+    }
+}
@@ -1,9 +1,11 @@
 mod c2s;
 pub(crate) mod s2c;
 mod errors;
+mod frame;
 mod session;
 
 pub use session::*;
 pub use errors::*;
+pub use frame::*;
 pub use c2s::*;
 pub use s2c::*;
\ No newline at end of file
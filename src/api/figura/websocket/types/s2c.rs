@@ -12,7 +12,69 @@ pub enum S2CMessage {
     Toast(u8, String, Option<String>) = 3,
     Chat(String) = 4,
     Notice(u8) = 5,
+    /// Number of subscribers and who they are, the list is empty if it's not allowed
+    Subscribers(u32, Vec<Uuid>) = 6,
+    /// Bytes of the avatar upload received so far and expected in total, 0 if unknown
+    UploadProgress(u32, u32) = 7,
+    /// Status of the user, empty if it's cleared
+    Status(Uuid, String) = 8,
 }
+/// Latest protocol version the server speaks
+pub const PROTOCOL_VERSION: u8 = 4;
+/// Since this version the title and the description of Toast are length-prefixed.
+/// Older clients get them separated by a null byte, which is removed from the title
+const LENGTH_PREFIXED_TOAST: u8 = 3;
+
+impl S2CMessage {
+    /// Protocol version the client needs to parse the frame. Unknown types are never sent to old clients.
+    pub fn required_protocol(frame: &[u8]) -> u8 {
+        match frame.first() {
+            Some(0..=5) | None => 0,
+            Some(6) => 1,
+            Some(7) => 2,
+            Some(8) => 4,
+            Some(_) => PROTOCOL_VERSION,
+        }
+    }
+
+    /// Encodes the message for a client speaking the protocol
+    pub fn encode(self, protocol: u8) -> Vec<u8> {
+        use std::iter::once;
+        use S2CMessage::*;
+        match self {
+            Toast(t, h, d) if protocol < LENGTH_PREFIXED_TOAST => once(3)
+                .chain(once(t))
+                .chain(h.bytes().filter(|b| *b != 0))
+                .chain(d.into_iter().flat_map(|s| once(0).chain(s.into_bytes())))
+                .collect(),
+            Toast(t, h, d) => once(3)
+                .chain(once(t))
+                .chain(length_prefixed(h))
+                .chain(d.into_iter().flat_map(length_prefixed))
+                .collect(),
+            message => message.into(),
+        }
+    }
+}
+
+fn length_prefixed(s: String) -> impl Iterator<Item = u8> {
+    (s.len() as u32).to_be_bytes().into_iter().chain(s.into_bytes())
+}
+
+/// Length-prefixed string at the start of the buffer and the rest of it
+fn read_prefixed<'a>(field: &'static str, buf: &'a [u8]) -> Result<(String, &'a [u8]), MessageLoadError> {
+    if buf.len() < 4 {
+        return Err(MessageLoadError::BadLength(field, 4, false, buf.len()));
+    }
+    let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+    let rest = &buf[4..];
+    if rest.len() < len {
+        return Err(MessageLoadError::BadLength(field, len + 4, false, buf.len()));
+    }
+    let s = String::from_utf8(rest[..len].to_vec()).map_err(|_| MessageLoadError::InvalidUtf8(field))?;
+    Ok((s, &rest[len..]))
+}
+
 impl TryFrom<&[u8]> for S2CMessage {
 
     type Error = MessageLoadError;
@@ -50,10 +112,59 @@ impl TryFrom<&[u8]> for S2CMessage {
                         Err(BadLength("S2CMessage::Event", 17, true, buf.len()))
                     }
                 }
-                3 => todo!(),
-                4 => todo!(),
-                5 => todo!(),
-                a => Err(BadEnum("S2CMessage.type", 0..=5, a.into())),
+                3 => {
+                    if buf.len() < 2 {
+                        return Err(BadLength("S2CMessage::Toast", 6, false, buf.len()));
+                    }
+                    let (title, rest) = read_prefixed("S2CMessage::Toast.title", &buf[2..])?;
+                    let description = if rest.is_empty() {
+                        None
+                    } else {
+                        let (description, rest) = read_prefixed("S2CMessage::Toast.description", rest)?;
+                        if !rest.is_empty() {
+                            return Err(BadLength("S2CMessage::Toast", buf.len() - rest.len(), true, buf.len()));
+                        }
+                        Some(description)
+                    };
+                    Ok(Toast(buf[1], title, description))
+                }
+                4 => String::from_utf8(buf[1..].to_vec()).map(Chat).map_err(|_| InvalidUtf8("S2CMessage::Chat")),
+                5 => {
+                    if buf.len() == 2 {
+                        Ok(Notice(buf[1]))
+                    } else {
+                        Err(BadLength("S2CMessage::Notice", 2, true, buf.len()))
+                    }
+                }
+                6 => {
+                    if buf.len() >= 5 && (buf.len() - 5).is_multiple_of(16) {
+                        Ok(Subscribers(
+                            u32::from_be_bytes((&buf[1..5]).try_into().unwrap()),
+                            buf[5..].chunks_exact(16).map(|uuid| Uuid::from_bytes(uuid.try_into().unwrap())).collect(),
+                        ))
+                    } else {
+                        Err(BadLength("S2CMessage::Subscribers", 5, false, buf.len()))
+                    }
+                }
+                7 => {
+                    if buf.len() == 9 {
+                        Ok(UploadProgress(
+                            u32::from_be_bytes((&buf[1..5]).try_into().unwrap()),
+                            u32::from_be_bytes((&buf[5..9]).try_into().unwrap()),
+                        ))
+                    } else {
+                        Err(BadLength("S2CMessage::UploadProgress", 9, true, buf.len()))
+                    }
+                }
+                8 => {
+                    if buf.len() >= 17 {
+                        let status = String::from_utf8(buf[17..].to_vec()).map_err(|_| InvalidUtf8("S2CMessage::Status"))?;
+                        Ok(Status(Uuid::from_bytes((&buf[1..17]).try_into().unwrap()), status))
+                    } else {
+                        Err(BadLength("S2CMessage::Status", 17, false, buf.len()))
+                    }
+                }
+                a => Err(BadEnum("S2CMessage.type", 0..=8, a.into())),
             }
         }
     }
@@ -72,17 +183,63 @@ impl From<S2CMessage> for Vec<u8> {
                 .chain(d.iter().copied())
                 .collect(),
             Event(u) => once(2).chain(u.into_bytes().iter().copied()).collect(),
-            Toast(t, h, d) => once(3)
-                .chain(once(t))
-                .chain(h.as_bytes().iter().copied())
-                .chain(
-                    d.into_iter()
-                        .flat_map(|s| once(0).chain(s.as_bytes().iter().copied()).collect::<Vec<_>>()), // FIXME: Try find other solution
-                )
-                .collect(),
+            Toast(..) => val.encode(PROTOCOL_VERSION),
             Chat(c) => once(4).chain(c.as_bytes().iter().copied()).collect(),
             Notice(t) => vec![5, t],
+            Subscribers(c, l) => once(6)
+                .chain(c.to_be_bytes())
+                .chain(l.iter().flat_map(|u| u.into_bytes()))
+                .collect(),
+            UploadProgress(r, t) => once(7).chain(r.to_be_bytes()).chain(t.to_be_bytes()).collect(),
+            Status(u, s) => once(8).chain(u.into_bytes()).chain(s.into_bytes()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_round_trip() {
+        for message in [S2CMessage::Subscribers(0, Vec::new()), S2CMessage::Subscribers(2, vec![Uuid::from_u128(1), Uuid::from_u128(2)])] {
+            let bytes: Vec<u8> = message.clone().into();
+            assert_eq!(S2CMessage::required_protocol(&bytes), 1);
+            assert_eq!(S2CMessage::try_from(bytes.as_slice()).unwrap(), message);
+        }
+        assert!(S2CMessage::try_from(&[6u8, 0, 0, 0, 1, 0][..]).is_err());
+    }
+
+    #[test]
+    fn toast_round_trip() {
+        let tricky = ["", "with\0null", "\0", "ünïcødé ✨", "trailing\0"];
+        for title in tricky {
+            for description in tricky.iter().map(|d| Some(d.to_string())).chain([None]) {
+                let message = S2CMessage::Toast(1, title.to_string(), description);
+                let bytes = message.clone().encode(PROTOCOL_VERSION);
+                assert_eq!(S2CMessage::try_from(bytes.as_slice()).unwrap(), message);
+                assert_eq!(bytes, Vec::<u8>::from(message));
+            }
+        }
+        // Old clients get nulls only as the separator
+        let legacy = S2CMessage::Toast(2, "ti\0tle".to_string(), Some("de\0sc".to_string())).encode(0);
+        assert_eq!(legacy, b"\x03\x02title\x00de\x00sc");
+        assert_eq!(S2CMessage::required_protocol(&legacy), 0);
+
+        assert!(S2CMessage::try_from(&[3u8, 1, 0, 0, 0, 9, b'a'][..]).is_err());
+        assert!(S2CMessage::try_from(&[3u8, 1, 0, 0, 0, 1, 0xff][..]).is_err());
+        assert!(S2CMessage::try_from(&[3u8, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1][..]).is_err());
+    }
+
+    #[test]
+    fn status_round_trip() {
+        for status in ["", "AFK ✨"] {
+            let message = S2CMessage::Status(Uuid::from_u128(1), status.to_string());
+            let bytes: Vec<u8> = message.clone().into();
+            assert_eq!(S2CMessage::required_protocol(&bytes), 4);
+            assert_eq!(S2CMessage::try_from(bytes.as_slice()).unwrap(), message);
         }
+        assert!(S2CMessage::try_from(&[8u8, 0, 0][..]).is_err());
     }
 }
 
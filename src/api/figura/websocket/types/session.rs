@@ -3,13 +3,19 @@ use tokio::{sync::{broadcast, mpsc}, task::AbortHandle};
 
 pub struct WSSession {
     pub user: crate::auth::Userinfo,
+    /// Protocol version sent by the client with the token
+    pub protocol: u8,
     pub own_tx: mpsc::Sender<SessionMessage>,
     pub own_rx: mpsc::Receiver<SessionMessage>,
-    pub subs_tx: broadcast::Sender<Vec<u8>>,
+    pub subs_tx: broadcast::Sender<super::Frame>,
     pub sub_workers_aborthandles: DashMap<uuid::Uuid, AbortHandle>,
 }
 
 pub enum SessionMessage {
     Ping(Vec<u8>),
+    /// Encoded for the protocol of the session
+    Message(super::S2CMessage),
     Banned,
+    /// Closes the connection with the reason
+    Kick(String),
 }
\ No newline at end of file
@@ -1,18 +1,16 @@
-use axum::{async_trait, body::Bytes, extract::{Path, State}};
+use std::{sync::Arc, time::Duration};
+
+use axum::{async_trait, body::Bytes, extract::{Path, State}, Json};
+use serde_json::json;
 use axum::extract::FromRequestParts;
 use axum::http::request::Parts;
 use axum::http::StatusCode;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace};
-use tokio::{
-    fs,
-    io::{self, BufWriter},
-};
 use uuid::Uuid;
 
-use crate::{api::errors::internal_and_log, ApiError, ApiResult, AppState, AVATARS_VAR};
-use crate::api::figura::profile::send_event;
-use super::super::figura::websocket::S2CMessage;
-use super::super::figura::websocket::SessionMessage;
+use crate::{api::{errors::internal_and_log, middleware::is_internal_host}, state::Config, utils, ApiError, ApiResult, AppState, CONFIG_VAR, FIGURA_ASSETS_COMMIT_URL};
+use crate::api::figura::{profile::send_event, SessionMessage};
 
 pub async fn temp_avatar(
     Path(uuid): Path<Uuid>,
@@ -23,16 +21,17 @@ pub async fn temp_avatar(
     internal_or_error(host).await?;
     let request_data = body;
 
-    if let Some(user_info) = state.user_manager.get_by_uuid(&uuid) {
+    let user_info = state.user_manager.get_by_uuid(&uuid).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "internal api trying upload temp avatar for {} ({})",
             user_info.uuid,
             user_info.nickname
         );
         state.user_manager.put_request_temp_state(uuid, false);
-        let avatar_file = format!("{}/temp/{}.moon", *AVATARS_VAR, user_info.uuid);
-        let mut file = BufWriter::new(fs::File::create(&avatar_file).await.map_err(internal_and_log)?);
-        io::copy(&mut request_data.as_ref(), &mut file).await.map_err(internal_and_log)?;
+        let avatar_file = state.avatars.temp_path(&user_info.uuid);
+        let _permit = state.upload_permit().await?;
+        state.avatars.put(&avatar_file, &request_data).await.map_err(internal_and_log)?;
     }
     Ok("ok".to_string())
 }
@@ -46,15 +45,16 @@ pub async fn upload_avatar(
     internal_or_error(host).await?;
     let request_data = body;
 
-    if let Some(user_info) = state.user_manager.get_by_uuid(&uuid) {
+    let user_info = state.user_manager.get_by_uuid(&uuid).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "internal api trying upload avatar for {} ({})",
             user_info.uuid,
             user_info.nickname
         );
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        let mut file = BufWriter::new(fs::File::create(&avatar_file).await.map_err(internal_and_log)?);
-        io::copy(&mut request_data.as_ref(), &mut file).await.map_err(internal_and_log)?;
+        let avatar_file = state.avatars.avatar_path(&user_info.uuid);
+        let _permit = state.upload_permit().await?;
+        state.avatars.put(&avatar_file, &request_data).await.map_err(internal_and_log)?;
     }
     Ok("ok".to_string())
 }
@@ -65,19 +65,66 @@ pub async fn delete_avatar(
     State(state): State<AppState>
 ) -> ApiResult<String> {
     internal_or_error(host).await?;
-    if let Some(user_info) = state.user_manager.get_by_uuid(&uuid) {
+    let user_info = state.user_manager.get_by_uuid(&uuid).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "internal api trying to delete avatar for {} ({})",
             user_info.uuid,
             user_info.nickname
         );
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        fs::remove_file(avatar_file).await.map_err(internal_and_log)?;
+        let soft = state.config.read().await.storage.soft_delete;
+        state.avatars.delete(&user_info.uuid, soft).await.map_err(internal_and_log)?;
         send_event(&state, &user_info.uuid).await;
     }
     Ok("ok".to_string())
 }
 
+/// Removes all data of the user and closes their connection
+pub async fn delete_user(
+    Path(uuid): Path<Uuid>,
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    tracing::info!("internal api deletes all data of {uuid}");
+    // Subscribers drop the avatar they have
+    send_event(&state, &uuid).await;
+    let session = state.session.get(&uuid).map(|session| session.clone());
+    if let Some(session) = &session {
+        let _ = session.send(SessionMessage::Kick("Your data was removed from the server".to_string())).await;
+    }
+    let mut files = state.avatars.purge_user(&uuid).await.map_err(internal_and_log)?;
+    let parts = state.avatars.uploads().await.map_err(internal_and_log)?;
+    for id in state.chunked_uploads.remove_owner(&uuid) {
+        state.avatars.remove_upload(&id).await.map_err(internal_and_log)?;
+        if parts.contains(&id) {
+            files.push(format!("uploads/{id}.part"));
+        }
+    }
+    let banned = state.user_manager.ban_info(&uuid).is_some();
+    let (user, tokens) = state.user_manager.forget(&uuid);
+    state.upload_keys.forget(&tokens);
+    state.report_cooldowns.remove(&uuid);
+    state.export_cooldowns.remove(&uuid);
+    state.share_links.revoke(&uuid, chrono::Utc::now().timestamp());
+    state.subscribes.remove(&uuid);
+    state.last_pings.remove(&uuid);
+    state.positions.remove(&uuid);
+    state.statuses.remove(&uuid);
+    state.subscribers.remove(&uuid);
+    state.user_downloads.remove(&uuid);
+    let config_entry = state.config.read().await.advanced_users.contains_key(&uuid);
+    Ok(Json(json!({
+        "user": user.is_some(),
+        "tokens": tokens.len(),
+        "banned": banned,
+        "session": session.is_some(),
+        "files": files,
+        // The config file isn't rewritten, the operator removes the entry
+        "advancedUsers": config_entry,
+    })))
+}
+
 pub async fn user_event(
     Path(uuid): Path<Uuid>,
     Host(host): Host,
@@ -85,13 +132,7 @@ pub async fn user_event(
 ) -> ApiResult<String> {
     internal_or_error(host).await?;
     tracing::info!("internal api request update avatar for user {}", uuid);
-    if let Some(session) = state.session.get(&uuid) {
-        if session.send(SessionMessage::Ping(S2CMessage::Event(uuid).into())).await.is_err() {
-            debug!("[WebSocket] Failed to send Event! WS doesn't connected? UUID: {uuid}")
-        };
-    } else {
-        debug!("[WebSocket] Failed to send Event! Can't find UUID: {uuid}")
-    };
+    send_event(&state, &uuid).await;
     Ok("ok".to_string())
 }
 
@@ -101,7 +142,8 @@ pub async fn user_upload_state(
     State(state): State<AppState>,
 ) -> ApiResult<String> {
     internal_or_error(host).await?;
-    if let Some(user_info) = state.user_manager.get_by_uuid(&uuid) {
+    let user_info = state.user_manager.get_by_uuid(&uuid).map(|user| user.clone());
+    if let Some(user_info) = user_info {
         tracing::info!(
             "internal api trying to update upload state to {} for {} ({})",
             us,
@@ -112,6 +154,157 @@ pub async fn user_upload_state(
     }
     Ok("ok".to_string())
 }
+/// Temp states of the users and their temp avatars, to see how self requests are served
+pub async fn temp_states(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    let files = state.avatars.temp_avatars().await.map_err(internal_and_log)?;
+    Ok(Json(json!({ "states": state.user_manager.temp_states(), "files": files })))
+}
+
+pub async fn debug_state(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    if !state.config.read().await.debug {
+        return Err(ApiError::NotFound);
+    }
+    let authenticated = state.user_manager.authenticated_uuids();
+    let sessions: Vec<Uuid> = state.session.iter().map(|entry| *entry.key()).collect();
+    let broadcasts: Vec<Uuid> = state.subscribes.iter().map(|entry| *entry.key()).collect();
+    Ok(Json(json!({
+        "authenticated": { "count": authenticated.len(), "uuids": authenticated },
+        "sessions": { "count": sessions.len(), "uuids": sessions },
+        "broadcasts": { "count": broadcasts.len(), "uuids": broadcasts },
+        "pendingAuth": state.user_manager.count_pending(),
+        "banned": state.user_manager.count_banned(),
+    })))
+}
+
+pub async fn config(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    let config = state.config.read().await;
+    if !config.debug {
+        return Err(ApiError::NotFound);
+    }
+    Ok(Json(config.sanitized().map_err(internal_and_log)?))
+}
+
+pub async fn bans(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    let bans: Vec<serde_json::Value> = state.user_manager.bans().into_iter()
+        .map(|(user, info)| json!({
+            "uuid": user.uuid,
+            "nickname": user.nickname,
+            "reason": info.reason,
+            "expires": info.expires,
+            "source": info.source,
+        }))
+        .collect();
+    Ok(Json(json!({ "count": bans.len(), "bans": bans })))
+}
+
+/// Applies the bans from the config and Minecraft now, without waiting for the file watchers
+pub async fn reload_bans(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    let config = Config::try_parse(CONFIG_VAR.clone().into()).map_err(internal_and_log)?;
+    let (added, removed) = utils::reload_bans(&state, config).await.map_err(internal_and_log)?;
+    tracing::info!("Bans reloaded, added: {added}, removed: {removed}");
+    Ok(Json(json!({ "added": added, "removed": removed })))
+}
+
+/// Checks the assets commit now and downloads it if it's new.
+/// The update runs in its own task, so it's finished and keeps others from starting even if the request is dropped
+pub async fn update_assets(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    // Another update is running
+    let Ok(running) = Arc::clone(&state.assets_update).try_lock_owned() else { return Err(ApiError::Conflict) };
+    let update = tokio::spawn(async move {
+        let _running = running;
+        let result = async {
+            let sha = utils::get_commit_sha(&state.http, FIGURA_ASSETS_COMMIT_URL).await?;
+            let download = state.config.read().await.assets_download.clone();
+            let update = utils::update_assets(&state.http, &download, &sha, &utils::get_path_to_assets_hash()).await?;
+            anyhow::Ok((update, sha))
+        }.await;
+        match &result {
+            Ok((utils::AssetsUpdate::Updated, sha)) => {
+                tracing::info!("Assets updated to {sha}");
+                state.webhooks.notify(utils::WebhookEvent::AssetsUpdated, json!({ "commit": sha }));
+            },
+            Ok(_) => (),
+            Err(e) => state.webhooks.notify(utils::WebhookEvent::AssetsUpdateFailed, json!({ "error": format!("{e:#}") })),
+        }
+        result
+    });
+    let (update, sha) = update.await.map_err(internal_and_log)?.map_err(internal_and_log)?;
+    Ok(Json(json!({ "status": update.status(), "updated": update == utils::AssetsUpdate::Updated, "commit": sha })))
+}
+
+/// Starts hashing the avatars into the cache in the background, at `storage.warm_rate` files per second
+pub async fn warm_cache(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+    internal_or_error(host).await?;
+    let rate = state.config.read().await.storage.warm_rate;
+    let delay = if rate == 0 { Duration::ZERO } else { Duration::from_secs(1) / rate };
+    let cancel = {
+        let mut running = state.cache_warm.lock().unwrap();
+        if running.is_some() {
+            return Err(ApiError::Conflict);
+        }
+        running.insert(CancellationToken::new()).clone()
+    };
+    tokio::spawn(async move {
+        match state.avatars.warm(delay, &cancel).await {
+            Ok(warmed) => tracing::info!("Hash cache warmed with {warmed} files{}", if cancel.is_cancelled() { ", canceled" } else { "" }),
+            Err(e) => tracing::error!("Hash cache warm-up failed due: {e:?}"),
+        }
+        // A canceled warm-up was already taken out
+        if !cancel.is_cancelled() {
+            *state.cache_warm.lock().unwrap() = None;
+        }
+    });
+    Ok((StatusCode::ACCEPTED, Json(json!({ "status": "started" }))))
+}
+
+/// Stops the running warm-up of the hash cache
+pub async fn cancel_cache_warm(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    let running = state.cache_warm.lock().unwrap().take();
+    if let Some(cancel) = &running {
+        cancel.cancel();
+    }
+    Ok(Json(json!({ "canceled": running.is_some() })))
+}
+
+pub async fn metrics(
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> ApiResult<Json<serde_json::Value>> {
+    internal_or_error(host).await?;
+    Ok(Json(serde_json::to_value(state.metrics.as_ref()).map_err(internal_and_log)?))
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Host(pub String);
 #[async_trait]
@@ -139,8 +332,7 @@ pub async fn check_internal(
     match host {
         Some(host) => {
             let host_value = host.0;
-            let target = String::from("lambda");
-            if host_value == target {
+            if is_internal_host(&host_value) {
                 Ok("ok")
             } else {
                 Err(ApiError::Forbidden)
@@ -152,10 +344,201 @@ pub async fn check_internal(
 pub async fn internal_or_error(
     host: String
 ) -> ApiResult<()> {
-    let lambda = String::from("lambda");
-    if lambda == host {
+    if is_internal_host(&host) {
         Ok(())
     } else {
         Err(ApiError::Forbidden)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::api::figura::websocket::S2CMessage;
+
+    #[tokio::test]
+    async fn user_event_reaches_subscribers() {
+        let state = AppState::for_tests();
+        let uuid = Uuid::from_u128(1);
+        let (tx, mut rx) = broadcast::channel(32);
+        state.subscribes.insert(uuid, tx);
+
+        user_event(Path(uuid), Host("lambda".to_string()), State(state)).await.unwrap();
+
+        let expected: Vec<u8> = S2CMessage::Event(uuid).into();
+        assert_eq!(rx.try_recv().unwrap().to_vec(), expected);
+    }
+
+    #[tokio::test]
+    async fn warmed_hashes_not_reread() {
+        use axum::http::HeaderMap;
+        use crate::{api::figura::profile::user_info, auth::{Token, Userinfo}};
+        let state = AppState::for_tests();
+        let (requester, uuid) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let user = Userinfo { uuid: requester, token: Some("token".to_string()), ..Default::default() };
+        state.user_manager.insert(requester, "token".to_string(), user).unwrap();
+        state.user_manager.insert_user(uuid, Userinfo { uuid, ..Default::default() });
+        let avatar = state.avatars.avatar_path(&uuid);
+        state.avatars.put(&avatar, b"avatar").await.unwrap();
+
+        let (status, _) = warm_cache(Host("lambda".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(warm_cache(Host("lambda".to_string()), State(state.clone())).await, Err(ApiError::Conflict)));
+        while state.cache_warm.lock().unwrap().is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Same size and modification time, only a re-read would see the new content
+        let metadata = std::fs::metadata(&avatar).unwrap();
+        let modified = metadata.modified().unwrap();
+        std::fs::write(&avatar, vec![b'x'; metadata.len() as usize]).unwrap();
+        std::fs::File::options().write(true).open(&avatar).unwrap().set_modified(modified).unwrap();
+        let res = user_info(Path(uuid), Some(Token("token".to_string())), HeaderMap::new(), State(state.clone())).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(info["equipped"][0]["hash"], utils::calculate_sha256(b"avatar"));
+
+        let Json(canceled) = cancel_cache_warm(Host("lambda".to_string()), State(state)).await.unwrap();
+        assert_eq!(canceled, json!({ "canceled": false }));
+    }
+
+    #[tokio::test]
+    async fn user_deleted_entirely() {
+        use crate::auth::{BanInfo, Userinfo};
+        let state = AppState::for_tests();
+        let (uuid, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let user = Userinfo { uuid, nickname: "Leaving".to_string(), token: Some("token".to_string()), ..Default::default() };
+        state.user_manager.insert(uuid, "token".to_string(), user.clone()).unwrap();
+        state.user_manager.insert(other, "other".to_string(), Userinfo { uuid: other, token: Some("other".to_string()), ..Default::default() }).unwrap();
+        state.user_manager.ban(&user, BanInfo::default());
+        state.user_manager.put_upload_state(uuid, true);
+        state.user_manager.put_request_temp_state(uuid, true);
+        for path in [state.avatars.avatar_path(&uuid), state.avatars.temp_path(&uuid), state.avatars.cape_path(&uuid), state.avatars.avatar_path(&other)] {
+            state.avatars.put(&path, b"data").await.unwrap();
+        }
+        state.avatars.put_meta(&uuid, "avatar", b"{}").await.unwrap();
+        let meta = state.avatars.meta_path(&uuid, "avatar");
+        let meta_hash = state.avatars.hash(&meta).await.unwrap();
+        let (meta_data, meta_modified) = (std::fs::read(&meta).unwrap(), std::fs::metadata(&meta).unwrap().modified().unwrap());
+        state.avatars.delete(&uuid, true).await.unwrap();
+        let (upload, _) = state.chunked_uploads.start(uuid, 4, None);
+        state.avatars.append_upload(&upload, b"part").await.unwrap();
+        let ttl = Duration::from_secs(60);
+        state.upload_keys.remember("token", "key", ttl);
+        state.upload_keys.remember("other", "key", ttl);
+        state.report_cooldowns.try_acquire(uuid, ttl);
+        state.export_cooldowns.try_acquire(uuid, ttl);
+        let now = chrono::Utc::now().timestamp();
+        let link = state.share_links.sign(None, &uuid, "avatar", now, now + 100);
+        state.avatars.put(&state.avatars.avatar_path(&uuid), b"data").await.unwrap();
+        state.statuses.insert(uuid, "AFK".to_string());
+        state.positions.insert(uuid, [0.0; 3]);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        state.session.insert(uuid, tx);
+
+        let Json(summary) = delete_user(Path(uuid), Host("lambda".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!((&summary["user"], &summary["tokens"], &summary["banned"], &summary["session"]), (&json!(true), &json!(1), &json!(true), &json!(true)));
+        assert_eq!(summary["files"].as_array().unwrap().len(), 6, "{summary}");
+        assert_eq!(summary["advancedUsers"], false);
+        // The event for the subscribers, then the kick
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Ping(_))));
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Kick(_))));
+
+        assert!(state.user_manager.get_by_uuid(&uuid).is_none());
+        assert!(state.user_manager.get(&"token".to_string()).is_none());
+        assert!(!state.user_manager.is_banned(&uuid) && state.user_manager.bans().is_empty());
+        assert!(state.user_manager.temp_states().is_empty());
+        assert!(!state.avatars.avatar_path(&uuid).exists() && !state.avatars.temp_path(&uuid).exists() && !state.avatars.cape_path(&uuid).exists());
+        assert!(!state.avatars.meta_path(&uuid, "avatar").exists());
+        assert!(!state.avatars.restore(&uuid, std::time::Duration::from_secs(60)).await.unwrap());
+        assert!(state.statuses.is_empty() && state.positions.is_empty());
+        assert!(!state.chunked_uploads.contains(&upload) && state.avatars.uploads().await.unwrap().is_empty());
+        assert!(!state.upload_keys.succeeded("token", "key", ttl));
+        assert!(state.report_cooldowns.try_acquire(uuid, ttl) && state.export_cooldowns.try_acquire(uuid, ttl));
+        assert_eq!(state.share_links.verify(None, &link, now), Err(crate::utils::ShareError::Revoked));
+        // A sidecar created again with the same size and time isn't taken from the cache
+        std::fs::create_dir_all(meta.parent().unwrap()).unwrap();
+        std::fs::write(&meta, [&meta_data[..meta_data.len() - 1], b" "].concat()).unwrap();
+        std::fs::File::options().write(true).open(&meta).unwrap().set_modified(meta_modified).unwrap();
+        assert_ne!(state.avatars.hash(&meta).await.unwrap(), meta_hash);
+        // Other users are kept
+        assert!(state.upload_keys.succeeded("other", "key", ttl));
+        assert!(state.user_manager.get(&"other".to_string()).is_some());
+        assert!(state.avatars.avatar_path(&other).exists());
+    }
+
+    #[tokio::test]
+    async fn temp_states_listed() {
+        use crate::auth::Userinfo;
+        let state = AppState::for_tests();
+        let (uploader, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        state.user_manager.insert_user(uploader, Userinfo { uuid: uploader, ..Default::default() });
+        state.user_manager.put_request_temp_state(other, true);
+
+        temp_avatar(Path(uploader), Host("lambda".to_string()), State(state.clone()), Bytes::from_static(b"temp")).await.unwrap();
+        let Json(listing) = temp_states(Host("lambda".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(listing["states"], json!({ uploader.to_string(): false, other.to_string(): true }));
+        assert_eq!(listing["files"], json!([uploader]));
+        assert!(matches!(temp_states(Host("example.com".to_string()), State(state)).await, Err(ApiError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn banned_users_listed() {
+        use crate::{auth::Userinfo, state::BannedPlayer};
+        let state = AppState::for_tests();
+        let player = BannedPlayer {
+            uuid: Uuid::from_u128(1),
+            name: "Griefer".to_string(),
+            reason: Some("Griefing".to_string()),
+            expires: Some("2030-01-01 00:00:00 +0000".to_string()),
+        };
+        state.user_manager.ban(&player.clone().into(), player.ban_info());
+        state.user_manager.insert_user(Uuid::from_u128(2), Userinfo { uuid: Uuid::from_u128(2), ..Default::default() });
+
+        let Json(listing) = bans(Host("lambda".to_string()), State(state.clone())).await.unwrap();
+        assert_eq!(listing["count"], 1);
+        let ban = &listing["bans"][0];
+        assert_eq!(ban["uuid"], Uuid::from_u128(1).to_string());
+        assert_eq!(ban["reason"], "Griefing");
+        assert_eq!(ban["expires"], "2030-01-01T00:00:00Z");
+        assert_eq!(ban["source"], "minecraft");
+
+        state.user_manager.unban(&player.uuid);
+        let Json(listing) = bans(Host("lambda".to_string()), State(state)).await.unwrap();
+        assert_eq!(listing["count"], 0);
+    }
+
+    #[tokio::test]
+    async fn assets_updated_once_at_a_time() {
+        let state = AppState::for_tests();
+        let _running = state.assets_update.lock().await;
+        let res = update_assets(Host("lambda".to_string()), State(state.clone())).await;
+        assert!(matches!(res, Err(ApiError::Conflict)));
+        let res = update_assets(Host("example.com".to_string()), State(state.clone())).await;
+        assert!(matches!(res, Err(ApiError::Forbidden)));
+    }
+
+    #[tokio::test]
+    async fn reloaded_ban_disconnects() {
+        use crate::{api::figura::SessionMessage, auth::{BanInfo, Userinfo}, state::AdvancedUsers};
+        let state = AppState::for_tests();
+        let (griefer, admin_banned) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        state.session.insert(griefer, tx);
+        state.user_manager.ban(&Userinfo { uuid: admin_banned, ..Default::default() }, BanInfo::default());
+
+        let mut config = state.config.read().await.clone();
+        config.advanced_users.insert(griefer, AdvancedUsers { username: "Griefer".to_string(), banned: true, special: None, pride: None, ping_size: None });
+        assert_eq!(utils::reload_bans(&state, config.clone()).await.unwrap(), (1, 0));
+        assert!(state.user_manager.is_banned(&griefer));
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Banned)));
+        assert_eq!(utils::reload_bans(&state, config.clone()).await.unwrap(), (0, 0));
+
+        // Bans made through the API stay
+        config.advanced_users.clear();
+        assert_eq!(utils::reload_bans(&state, config).await.unwrap(), (0, 1));
+        assert!(!state.user_manager.is_banned(&griefer));
+        assert!(state.user_manager.is_banned(&admin_banned));
+    }
+}
@@ -0,0 +1,462 @@
+use std::{future::Future, net::{IpAddr, SocketAddr}, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, Request, State}, http::{header, HeaderValue, StatusCode}, middleware::{from_fn, Next}, response::{IntoResponse, Redirect, Response}, Json, Router
+};
+use rand::Rng as _;
+use serde_json::json;
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+use tracing::Instrument as _;
+
+use crate::{state::{CompressionAlgorithm, CompressionSettings}, ApiError, AppState, INTERNAL_HOST};
+
+/// Address of the client, resolved with the proxy settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const INSTANCE_HEADER: &str = "x-sculptor-instance";
+const X_ROBOTS_TAG: &str = "x-robots-tag";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled by the current task
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().filter(|id| !id.is_empty())
+}
+
+/// Keeps the request id for a future spawned as another task
+pub fn propagate_request_id<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    REQUEST_ID.scope(current_request_id().unwrap_or_default(), fut)
+}
+
+/// Adds the id of the current request to an outbound request
+pub fn forward_request_id(builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match current_request_id() {
+        Some(id) => builder.header(REQUEST_ID_HEADER, id),
+        None => builder,
+    }
+}
+
+/// Takes `X-Request-Id` from the request or generates it, adds it to the request span and echoes it in the response
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    // Only visible ASCII gets here
+    let value = HeaderValue::from_str(&id).expect("valid request id");
+    req.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+    let span = tracing::info_span!("request", id = %id);
+    let mut res = REQUEST_ID.scope(id, next.run(req)).instrument(span).await;
+    res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    res
+}
+
+/// Adds the instance id to the logs and the `X-Sculptor-Instance` header, wraps the whole app
+pub async fn instance(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let id = state.config.read().await.instance_id().to_string();
+    let span = tracing::info_span!("instance", id = %id);
+    let mut res = next.run(req).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(INSTANCE_HEADER, value);
+    }
+    res
+}
+
+/// Compresses responses with the configured algorithm, does nothing if HTTP compression is disabled
+pub fn compression_layer(settings: &CompressionSettings) -> CompressionLayer {
+    let enabled = |algorithm| settings.http && settings.algorithm == algorithm;
+    CompressionLayer::new()
+        .gzip(enabled(CompressionAlgorithm::Gzip))
+        .zstd(enabled(CompressionAlgorithm::Zstd))
+        .quality(CompressionLevel::Precise(settings.level))
+}
+
+/// Adds the headers from `http.extraHeaders` and `X-Robots-Tag` without replacing the ones set by handlers
+pub async fn extra_headers(State(state): State<AppState>, mut res: Response) -> Response {
+    let config = state.config.read().await;
+    for (name, value) in &config.http.extra_headers {
+        if !res.headers().contains_key(name) {
+            res.headers_mut().insert(name, value.clone());
+        }
+    }
+    if config.robots.noindex && !res.headers().contains_key(X_ROBOTS_TAG) {
+        res.headers_mut().insert(X_ROBOTS_TAG, HeaderValue::from_static("noindex"));
+    }
+    res
+}
+
+/// Serves legacy paths from `legacy.paths` as their current paths, or redirects to them.
+/// Must wrap the whole router, because the routing is already done for `Router::layer` middleware.
+pub async fn legacy_paths(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let (target, redirect) = {
+        let config = state.config.read().await;
+        (config.legacy.paths.get(req.uri().path()).cloned(), config.legacy.redirect)
+    };
+    let Some(target) = target else {
+        return next.run(req).await;
+    };
+    tracing::info!(
+        path = req.uri().path(),
+        user_agent = req.headers().get(header::USER_AGENT).and_then(|value| value.to_str().ok()),
+        "Legacy path requested, serving {}", target
+    );
+    let target = match req.uri().query() {
+        Some(query) => format!("{target}?{query}"),
+        None => target,
+    };
+    if redirect {
+        return Redirect::permanent(&target).into_response();
+    }
+    match target.parse() {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            next.run(req).await
+        },
+        Err(_) => {
+            tracing::error!("Invalid current path for the legacy path: {}", target);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        },
+    }
+}
+
+/// Resolves the client address and adds it to the request span
+pub async fn client_ip(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let ip = match peer {
+        Some(peer) => Some(state.config.read().await.proxy.client_ip(peer, req.headers())),
+        None => None,
+    };
+    if let Some(ip) = ip {
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    let span = tracing::info_span!("client", ip = ip.map(tracing::field::display));
+    next.run(req).instrument(span).await
+}
+
+/// Answers 431 to the requests with more or larger headers than `http` allows
+pub async fn header_limits(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if state.config.read().await.http.headers_too_large(req.headers()) {
+        tracing::debug!("Request headers of {} are too large", req.uri().path());
+        return StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE.into_response();
+    }
+    next.run(req).await
+}
+
+/// Logs the requests taking longer than `http.slow_request_millis`
+pub async fn slow_requests(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(threshold) = state.config.read().await.http.slow_request_millis else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_string();
+    let started = tokio::time::Instant::now();
+    let res = next.run(req).await;
+    let elapsed = started.elapsed();
+    if elapsed > Duration::from_millis(threshold) {
+        tracing::warn!(path, duration_ms = elapsed.as_millis() as u64, "Slow request");
+    }
+    res
+}
+
+/// Answers 503 with a jittered Retry-After to the clients over `reconnect_surge.threshold`
+pub async fn reconnect_surge(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let surge = state.config.read().await.reconnect_surge.clone();
+    if !state.reconnects.admit(surge.threshold) {
+        let retry_after = rand::thread_rng().gen_range(1..=surge.spread.max(1));
+        tracing::debug!("Reconnect surge, {} deferred for {retry_after}s", req.uri().path());
+        return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, retry_after.to_string())], "service unavailable").into_response();
+    }
+    next.run(req).await
+}
+
+/// Answers 429 if the origin of the client uploaded too many avatars in the last minute.
+/// Only the accepted uploads are counted
+pub async fn origin_uploads(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(&ClientIp(ip)) = req.extensions().get::<ClientIp>() else { return next.run(req).await };
+    let origin = {
+        let origins = &state.config.read().await.origins;
+        let origin = origins.origin(ip);
+        if !state.origins.try_upload(origin, origins.max_uploads_per_minute) {
+            tracing::debug!("Too many uploads from the origin of {}", ip);
+            return ApiError::TooManyRequests.into_response();
+        }
+        origin
+    };
+    let res = next.run(req).await;
+    if !res.status().is_success() {
+        state.origins.refund_upload(origin);
+    }
+    res
+}
+
+/// The Host header names the lambda component. Proxies may change its case or add the port
+pub fn is_internal_host(host: &str) -> bool {
+    let host = host.trim();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.strip_suffix('.').unwrap_or(host).eq_ignore_ascii_case(INTERNAL_HOST)
+}
+
+/// Answers 403 with the reason to the requests for /internal from other hosts.
+/// The expected host is told only in debug mode. Requests without the host are left to the handlers
+pub async fn internal_host(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let host = req.headers().get(header::HOST).map(|host| host.to_str().unwrap_or_default().to_string());
+    match host {
+        Some(host) if !is_internal_host(&host) => {
+            tracing::debug!("Internal API requested with host {host:?}");
+            let mut body = json!({ "error": "forbidden", "reason": "host check failed" });
+            if state.config.read().await.debug {
+                body["expected"] = INTERNAL_HOST.into();
+                body["host"] = host.into();
+            }
+            (StatusCode::FORBIDDEN, Json(body)).into_response()
+        },
+        _ => next.run(req).await,
+    }
+}
+
+/// Limits the handling time of every route in the router.
+/// Exceeded requests are answered with 504 Gateway Timeout.
+pub fn with_timeout<S>(router: Router<S>, secs: u64) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let timeout = Duration::from_secs(secs);
+    router.layer(from_fn(move |req: Request, next: Next| async move {
+        // The responses of the handlers, even 408, are left as they are
+        match tokio::time::timeout(timeout, next.run(req)).await {
+            Ok(res) => res,
+            Err(_) => StatusCode::GATEWAY_TIMEOUT.into_response(),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use tower::{Layer as _, ServiceExt as _};
+
+    use super::*;
+    use crate::utils::Captured;
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_handlers_timed_out() {
+        let slow = || async { tokio::time::sleep(Duration::from_secs(60)).await; "ok" };
+        let timed = Router::new()
+            .route("/slow", get(slow))
+            .route("/fast", get(|| async { "ok" }))
+            .route("/own", get(|| async { StatusCode::REQUEST_TIMEOUT }));
+        let app = Router::new()
+            .nest("/api", with_timeout(timed, 30))
+            .route("/ws", get(slow));
+        let status = |uri| {
+            let app = app.clone();
+            async move { app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap().status() }
+        };
+
+        assert_eq!(status("/api/slow").await, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(status("/api/fast").await, StatusCode::OK);
+        assert_eq!(status("/api/own").await, StatusCode::REQUEST_TIMEOUT);
+        // Routes outside of it wait as long as they need
+        assert_eq!(status("/ws").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn responses_compressed_by_default() {
+        let app = Router::new()
+            .route("/", get(|| async { "compressible ".repeat(100) }))
+            .layer(compression_layer(&CompressionSettings::default()));
+        let request = |encoding| Request::builder().uri("/").header(header::ACCEPT_ENCODING, encoding).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(request("gzip")).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        let res = app.oneshot(request("identity")).await.unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn request_id_propagated() {
+        let app = Router::new()
+            .route("/", get(|| async { current_request_id().unwrap_or_default() }))
+            .layer(axum::middleware::from_fn(request_id));
+        let request = |id: Option<&str>| {
+            let builder = Request::builder().uri("/");
+            let builder = match id {
+                Some(id) => builder.header(REQUEST_ID_HEADER, id),
+                None => builder,
+            };
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let res = app.clone().oneshot(request(Some("abc-123"))).await.unwrap();
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "abc-123");
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "abc-123");
+
+        for id in [None, Some("with space"), Some("")] {
+            let res = app.clone().oneshot(request(id)).await.unwrap();
+            let generated = res.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+            assert_eq!(generated.len(), 32);
+            assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), generated);
+        }
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[tokio::test]
+    async fn internal_host_rejected() {
+        let state = AppState::for_tests();
+        let app = Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), internal_host));
+        let request = |host| Request::builder().uri("/health").header(header::HOST, host).body(Body::empty()).unwrap();
+        let body = |res: Response| async { serde_json::from_slice::<serde_json::Value>(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap() };
+
+        assert_eq!(app.clone().oneshot(request(INTERNAL_HOST)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(request("Lambda:8080")).await.unwrap().status(), StatusCode::OK);
+        let res = app.clone().oneshot(request("sculptor.example.com")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(res).await, json!({ "error": "forbidden", "reason": "host check failed" }));
+
+        state.config.write().await.debug = true;
+        let res = app.oneshot(request("sculptor.example.com")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(res).await, json!({ "error": "forbidden", "reason": "host check failed", "expected": "lambda", "host": "sculptor.example.com" }));
+    }
+
+    #[tokio::test]
+    async fn rejected_uploads_not_counted() {
+        let state = AppState::for_tests();
+        state.config.write().await.origins.max_uploads_per_minute = Some(1);
+        let app = Router::new()
+            .route("/rejected", get(|| async { ApiError::Unauthorized }))
+            .route("/accepted", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), origin_uploads));
+        let status = |uri| {
+            let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ClientIp("203.0.113.1".parse().unwrap()));
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+
+        assert_eq!(status("/rejected").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status("/rejected").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status("/accepted").await, StatusCode::OK);
+        assert_eq!(status("/accepted").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn internal_host_normalized() {
+        for host in ["lambda", "LAMBDA", "Lambda", "lambda:80", "LAMBDA:8080", " lambda ", "lambda."] {
+            assert!(is_internal_host(host), "{host}");
+        }
+        for host in ["lambda.example.com", "lambdas", "lambda:", "lambda:http", "example.com:80", ""] {
+            assert!(!is_internal_host(host), "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_rejected() {
+        let state = AppState::for_tests();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), header_limits));
+        let request = |count: usize, size: usize| {
+            let builder = (0..count).fold(Request::builder().uri("/"), |builder, i| builder.header(format!("x-header-{i}"), "v".repeat(size)));
+            builder.body(Body::empty()).unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(request(40, 1000)).await.unwrap().status(), StatusCode::OK);
+        {
+            let mut config = state.config.write().await;
+            config.http.max_headers = Some(32);
+            config.http.max_header_bytes = Some(8192);
+        }
+        assert_eq!(app.clone().oneshot(request(32, 100)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(request(33, 1)).await.unwrap().status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert_eq!(app.oneshot(request(2, 5000)).await.unwrap().status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_requests_logged() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).with_max_level(tracing::Level::WARN).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState::for_tests();
+        state.config.write().await.http.slow_request_millis = Some(500);
+        let app = Router::new()
+            .route("/fast", get(|| async { "ok" }))
+            .route("/slow", get(|| async { tokio::time::sleep(Duration::from_secs(1)).await; "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), slow_requests));
+        let request = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let logged = || captured.output();
+
+        app.clone().oneshot(request("/fast")).await.unwrap();
+        assert!(logged().is_empty());
+        app.oneshot(request("/slow")).await.unwrap();
+        let logged = logged();
+        assert!(logged.contains("Slow request") && logged.contains("path=\"/slow\"") && logged.contains("duration_ms=1000"), "{logged}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_burst_deferred() {
+        let state = AppState::for_tests();
+        state.config.write().await.reconnect_surge = crate::state::ReconnectSurge { threshold: Some(4), spread: 5 };
+        let app = Router::new()
+            .route("/verify", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), reconnect_surge));
+        let request = || Request::builder().uri("/verify").body(Body::empty()).unwrap();
+
+        let burst = futures_util::future::join_all((0..10).map(|_| app.clone().oneshot(request()))).await;
+        let (admitted, deferred): (Vec<_>, Vec<_>) = burst.into_iter().map(Result::unwrap).partition(|res| res.status() == StatusCode::OK);
+        assert_eq!((admitted.len(), deferred.len()), (4, 6));
+        for res in deferred {
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let retry_after: u64 = res.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+            assert!((1..=5).contains(&retry_after));
+        }
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(app.oneshot(request()).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn legacy_path_rewritten() {
+        let state = AppState::for_tests();
+        state.config.write().await.legacy.paths.insert("/old/limits".to_string(), "/api/limits".to_string());
+        let app = Router::new().route("/api/limits", get(|req: Request| async move { req.uri().to_string() }));
+        let app = axum::middleware::from_fn_with_state(state.clone(), legacy_paths).layer(app);
+        let request = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let res = app.clone().oneshot(request("/old/limits?rank=1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "/api/limits?rank=1");
+        assert_eq!(app.clone().oneshot(request("/old/other")).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        state.config.write().await.legacy.redirect = true;
+        let res = app.oneshot(request("/old/limits")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[header::LOCATION], "/api/limits");
+    }
+
+    #[tokio::test]
+    async fn instance_id_reported() {
+        let state = AppState::for_tests();
+        state.config.write().await.instance_id = Some("sculptor-2".to_string());
+        let app = Router::new()
+            .route("/health", get(crate::api::figura::info::health))
+            .with_state(state.clone())
+            .layer(axum::middleware::from_fn_with_state(state, instance));
+
+        let res = app.oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.headers()[INSTANCE_HEADER], "sculptor-2");
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, json!({ "status": "ok", "instance": "sculptor-2" }));
+    }
+}
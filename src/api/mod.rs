@@ -1,4 +1,6 @@
 pub mod figura;
 pub mod lambda;
 pub mod v1;
-pub mod errors;
\ No newline at end of file
+pub mod errors;
+pub mod middleware;
+pub mod server;
\ No newline at end of file
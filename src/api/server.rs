@@ -0,0 +1,112 @@
+use std::{convert::Infallible, future::Future, io, sync::Arc, time::Duration};
+
+use axum::{body::Body, extract::{ConnectInfo, Request}, response::Response};
+use hyper::body::Incoming;
+use hyper_util::{rt::{TokioExecutor, TokioIo, TokioTimer}, server::{conn::auto::Builder, graceful::GracefulShutdown}, service::TowerToHyperService};
+use tokio::{net::TcpListener, sync::RwLock};
+use tower::{Service, ServiceExt as _};
+
+use crate::state::{Config, Http};
+
+/// Hyper only reads the request heads up to a buffer of this size
+const MIN_HEADER_BUFFER: usize = 8192;
+/// Connections not sending the whole request head within it are closed
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Like `axum::serve` with the connect info, but the `http` header limits are applied while hyper reads the requests.
+/// So the oversized ones are answered with 431 before their headers are parsed, whatever route they were sent to
+pub async fn serve<S>(listener: TcpListener, app: S, config: Arc<RwLock<Config>>, signal: impl Future<Output = ()>) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(signal);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    tracing::error!("Can't accept a connection due: {e:?}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                },
+            },
+            () = &mut signal => break,
+        };
+        let service = app.clone().map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(addr));
+            req.map(Body::new)
+        });
+        let builder = connection_builder(&config.read().await.http);
+        // Upgrades are needed for the websockets
+        let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service)).into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::trace!("Connection of {addr} failed: {e:?}");
+            }
+        });
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+fn connection_builder(http: &Http) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().timer(TokioTimer::new()).header_read_timeout(HEADER_READ_TIMEOUT);
+    if let Some(max) = http.max_headers {
+        builder.http1().max_headers(max);
+    }
+    if let Some(max) = http.max_header_bytes {
+        // The smaller limits are left to the `header_limits` middleware
+        builder.http1().max_buf_size(max.max(MIN_HEADER_BUFFER));
+        builder.http2().max_header_list_size(max.try_into().unwrap_or(u32::MAX));
+    }
+    builder
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use tokio::{io::{AsyncReadExt as _, AsyncWriteExt as _}, net::TcpStream};
+
+    use super::*;
+    use crate::AppState;
+
+    #[tokio::test]
+    async fn header_limits_applied_by_hyper() {
+        let state = AppState::for_tests();
+        {
+            let http = &mut state.config.write().await.http;
+            http.max_headers = Some(8);
+            http.max_header_bytes = Some(16384);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/health", get(|ConnectInfo(client): ConnectInfo<SocketAddr>| async move { client.ip().to_string() }));
+        tokio::spawn(serve(listener, app, Arc::clone(&state.config), std::future::pending()));
+
+        let status = |headers: String| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n").as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response).await;
+            String::from_utf8_lossy(&response).to_string()
+        };
+        let response = status(String::new()).await;
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("127.0.0.1"), "{response}");
+        let many: String = (0..10).map(|n| format!("X-Header-{n}: value\r\n")).collect();
+        assert!(status(many).await.starts_with("HTTP/1.1 431"));
+        let large = format!("X-Large: {}\r\n", "a".repeat(20000));
+        assert!(status(large).await.starts_with("HTTP/1.1 431"));
+    }
+}
@@ -1,9 +1,8 @@
 use axum::{body::Bytes, extract::{Path, State}};
-use tokio::{fs, io::{self, BufWriter}};
 use tracing::warn;
 use uuid::Uuid;
 
-use crate::{api::figura::profile::send_event, auth::Token, ApiResult, AppState, AVATARS_VAR};
+use crate::{api::{errors::internal_and_log, figura::profile::send_event}, auth::Token, ApiResult, AppState};
 
 pub async fn upload_avatar(
     Path(uuid): Path<Uuid>,
@@ -20,9 +19,9 @@ pub async fn upload_avatar(
         uuid,
     );
 
-    let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, &uuid);
-    let mut file = BufWriter::new(fs::File::create(&avatar_file).await.unwrap());
-    io::copy(&mut request_data.as_ref(), &mut file).await.unwrap();
+    let avatar_file = state.avatars.avatar_path(&uuid);
+    let _permit = state.upload_permit().await?;
+    state.avatars.put(&avatar_file, &request_data).await.map_err(internal_and_log)?;
     send_event(&state, &uuid).await;
 
     Ok("ok")
@@ -40,8 +39,8 @@ pub async fn delete_avatar(
         uuid,
     );
 
-    let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, &uuid);
-    match fs::remove_file(avatar_file).await {
+    let soft = state.config.read().await.storage.soft_delete;
+    match state.avatars.delete(&uuid, soft).await {
         Ok(_) => {},
         Err(_) => {
             warn!("avatar doesn't exist");
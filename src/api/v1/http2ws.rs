@@ -1,8 +1,9 @@
-use axum::extract::{Query, State};
+use axum::{extract::{Query, State}, Json};
 use tracing::{debug, trace, warn};
+use uuid::Uuid;
 
-use crate::{api::errors::{error_and_log, internal_and_log}, auth::Token, ApiResult, AppState};
-use super::types::UserUuid;
+use crate::{api::{errors::{error_and_log, internal_and_log}, figura::{Frame, S2CMessage, SessionMessage}}, auth::Token, ApiError, ApiResult, AppState};
+use super::types::{Toast, UserUuid};
 
 pub(super) async fn verify(
     Token(token): Token,
@@ -28,8 +29,9 @@ pub(super) async fn raw(
     match query.uuid {
         Some(uuid) => {
             // for only one
-            let tx = state.session.get(&uuid).ok_or_else(|| { warn!("unknown uuid"); crate::ApiError::NotFound })?;
-            tx.value().send(crate::api::figura::SessionMessage::Ping(payload)).await.map_err(internal_and_log)?;
+            // Cloning the sender, so the shard isn't locked while waiting for the channel
+            let tx = state.session.get(&uuid).map(|tx| tx.clone()).ok_or_else(|| { warn!("unknown uuid"); crate::ApiError::NotFound })?;
+            tx.send(crate::api::figura::SessionMessage::Ping(payload)).await.map_err(internal_and_log)?;
             Ok("ok")
         },
         None => {
@@ -56,7 +58,7 @@ pub(super) async fn sub_raw(
         Some(uuid) => {
             // for only one
             let tx = state.subscribes.get(&uuid).ok_or_else(|| { warn!("unknown uuid"); crate::ApiError::NotFound })?;
-            tx.value().send(payload).map_err(internal_and_log)?;
+            tx.value().send(Frame::new(payload, &state.metrics)).map_err(internal_and_log)?;
             Ok("ok")
         },
         None => {
@@ -64,4 +66,124 @@ pub(super) async fn sub_raw(
             Err(crate::ApiError::NotFound)
         },
     }
-}
\ No newline at end of file
+}
+pub(super) async fn toast(
+    Token(token): Token,
+    Query(query): Query<UserUuid>,
+    State(state): State<AppState>,
+    Json(toast): Json<Toast>,
+) -> ApiResult<&'static str> {
+    let limits = {
+        let config = state.config.read().await;
+        config.verify_token(&token)?;
+        config.messages.clone()
+    };
+    let max = limits.max_toast_length;
+    let title = limits.limit(toast.title, max).ok_or(ApiError::PayloadTooLarge)?;
+    let message = match toast.message {
+        Some(message) => Some(limits.limit(message, max).ok_or(ApiError::PayloadTooLarge)?),
+        None => None,
+    };
+    send_message(&state, query.uuid, S2CMessage::Toast(toast.kind, title, message)).await
+}
+
+pub(super) async fn chat(
+    Token(token): Token,
+    Query(query): Query<UserUuid>,
+    State(state): State<AppState>,
+    body: String,
+) -> ApiResult<&'static str> {
+    let limits = {
+        let config = state.config.read().await;
+        config.verify_token(&token)?;
+        config.messages.clone()
+    };
+    let message = limits.limit(body, limits.max_chat_length).ok_or(ApiError::PayloadTooLarge)?;
+    if limits.is_blocked(&message) {
+        return Err(ApiError::BadRequest)
+    }
+    send_message(&state, query.uuid, S2CMessage::Chat(message)).await
+}
+
+/// Sends the message to the session of the user, or to every session if no user is given
+async fn send_message(state: &AppState, uuid: Option<Uuid>, message: S2CMessage) -> ApiResult<&'static str> {
+    // Cloning the senders, so the shards aren't locked while waiting for the channels
+    let senders: Vec<_> = match uuid {
+        Some(uuid) => vec![state.session.get(&uuid).map(|tx| tx.clone()).ok_or_else(|| { warn!("unknown uuid"); ApiError::NotFound })?],
+        None => state.session.iter().map(|tx| tx.clone()).collect(),
+    };
+    for tx in senders {
+        // Sessions closed meanwhile are skipped
+        let _ = tx.send(SessionMessage::Message(message.clone())).await;
+    }
+    Ok("ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::api::figura::SessionMessage;
+
+    #[tokio::test]
+    async fn long_messages_limited() {
+        let state = AppState::for_tests();
+        state.config.write().await.token = Some("secret".to_string());
+        state.config.write().await.messages.max_chat_length = 4;
+        let uuid = Uuid::from_u128(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        state.session.insert(uuid, tx);
+        let send = |text: &str| chat(Token("secret".to_string()), Query(UserUuid { uuid: None }), State(state.clone()), text.to_string());
+
+        assert!(send("ok!!").await.is_ok());
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Message(msg)) if msg.clone().encode(0) == b"\x04ok!!"));
+        assert!(matches!(send("too long").await, Err(ApiError::PayloadTooLarge)));
+        assert!(rx.try_recv().is_err());
+
+        state.config.write().await.messages.truncate = true;
+        // Cut on the char boundary
+        assert!(send("ab\u{00e9}cd").await.is_ok());
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Message(msg)) if msg.clone().encode(0) == "\x04ab\u{00e9}".as_bytes()));
+
+        state.config.write().await.messages.max_toast_length = 2;
+        let toast = Toast { kind: 1, title: "title".to_string(), message: Some("message".to_string()) };
+        assert!(super::toast(Token("secret".to_string()), Query(UserUuid { uuid: Some(uuid) }), State(state.clone()), Json(toast)).await.is_ok());
+        assert!(matches!(rx.try_recv(), Ok(SessionMessage::Message(msg)) if msg.clone().encode(0) == b"\x03\x01ti\x00me"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_raw_does_not_lock_sessions() {
+        let state = AppState::for_tests();
+        state.config.write().await.token = Some("secret".to_string());
+        let uuid = Uuid::from_u128(1);
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.send(SessionMessage::Ping(vec![])).await.unwrap(); // The channel is full now
+        state.session.insert(uuid, tx);
+
+        let senders: Vec<_> = (0..8).map(|_| tokio::spawn(raw(
+            Token("secret".to_string()),
+            Query(UserUuid { uuid: Some(uuid) }),
+            State(state.clone()),
+            "00ff".to_string(),
+        ))).collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Senders are waiting for the channel, but the map must stay writable
+        let sessions = state.session.clone();
+        tokio::time::timeout(Duration::from_secs(5), tokio::task::spawn_blocking(move || sessions.remove(&uuid)))
+            .await
+            .expect("session map is locked by a waiting sender")
+            .unwrap();
+
+        for _ in 0..=senders.len() {
+            rx.recv().await.unwrap();
+        }
+        for sender in senders {
+            assert!(sender.await.unwrap().is_ok());
+        }
+    }
+}
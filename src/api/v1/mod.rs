@@ -11,6 +11,8 @@ pub fn router(limit: usize) -> Router<AppState> {
         .route("/verify", get(http2ws::verify))
         .route("/raw", post(http2ws::raw))
         .route("/sub/raw", post(http2ws::sub_raw))
+        .route("/toast", post(http2ws::toast))
+        .route("/chat", post(http2ws::chat))
         .route("/user/list", get(users::list))
         .route("/user/sessions", get(users::list_sessions))
         .route("/user/create", post(users::create_user))
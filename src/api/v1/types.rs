@@ -1,6 +1,14 @@
 use serde::Deserialize;
 use uuid::Uuid;
 
+#[derive(Deserialize)]
+pub(super) struct Toast {
+    #[serde(rename = "type", default)]
+    pub kind: u8,
+    pub title: String,
+    pub message: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub(super) struct UserUuid {
     pub uuid: Option<Uuid>,
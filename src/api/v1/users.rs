@@ -5,7 +5,7 @@ use axum::{
 use tracing::{debug, info};
 use uuid::Uuid;
 
-use crate::{api::errors::internal_and_log, auth::{Token, Userinfo}, ApiResult, AppState};
+use crate::{api::errors::internal_and_log, auth::{BanInfo, Token, Userinfo}, ApiResult, AppState};
 
 pub(super) async fn create_user(
     Token(token): Token,
@@ -29,8 +29,7 @@ pub(super) async fn ban(
 
     info!("Trying ban user: {uuid}");
     
-    if let Some(tx) = state.session.get(&uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
-    state.user_manager.ban(&Userinfo { uuid, banned: true, ..Default::default() });
+    state.ban(&Userinfo { uuid, banned: true, ..Default::default() }, BanInfo::default(), true).await;
     Ok("ok")
 }
 
@@ -1,15 +1,17 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Context};
 use axum::{
     async_trait, extract::{FromRequestParts, State}, http::{request::Parts, StatusCode}
 };
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, error, trace, warn};
+use tokio::time::Instant;
+use tracing::{debug, error, trace, warn, Instrument as _};
 use uuid::Uuid;
 
-use crate::{ApiError, ApiResult, AppState, TIMEOUT, USER_AGENT};
+use crate::{api::middleware::{forward_request_id, propagate_request_id}, ApiError, ApiResult, AppState};
 use super::types::*;
 
 // It's an extractor that pulls a token from the Header.
@@ -18,12 +20,9 @@ pub struct Token(pub String);
 
 impl Token {
     pub async fn check_auth(self, state: &AppState) -> ApiResult<()> {
-        if let Some(user) = state.user_manager.get(&self.0) {
-            if !user.banned {
-                Ok(())
-            } else {
-                Err(ApiError::Unauthorized)
-            }
+        let uuid = state.user_manager.get(&self.0).map(|user| user.uuid).ok_or(ApiError::Unauthorized)?;
+        if !state.is_banned(&uuid) {
+            Ok(())
         } else {
             Err(ApiError::Unauthorized)
         }
@@ -49,6 +48,27 @@ where
         }
     }
 }
+
+/// Extractor of the user sending the token, if they are listed in `admins`.
+/// Rejects with 401 if the token is unknown and with 403 for other users.
+#[derive(Debug)]
+pub struct RequireAdmin(pub Userinfo);
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequireAdmin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Token(token) = Token::from_request_parts(parts, state).await?;
+        let user = state.user_manager.get(&token).map(|user| user.clone()).ok_or(StatusCode::UNAUTHORIZED)?;
+        if !state.is_banned(&user.uuid) && state.is_admin(&user.uuid).await {
+            Ok(Self(user))
+        } else {
+            warn!("{} ({}) tried to use admin functions", user.uuid, user.nickname);
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
 // End Extractor
 
 // Work with external APIs
@@ -70,17 +90,20 @@ enum FetchError {
 
 }
 
+/// Every provider that could know the user failed to answer, as opposed to not knowing them
+#[derive(Debug, Error)]
+#[error("authentication providers are unreachable: {0:?}")]
+pub struct ProvidersUnreachable(pub Vec<String>);
+
 async fn fetch_json(
     State(state): State<AppState>,
     auth_provider: &AuthProvider,
     server_id: &str,
     username: &str,
 ) -> Result<(Uuid, AuthProvider), FetchError> {
-    let client = reqwest::Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
     let url = auth_provider.url.clone();
 
-    let res = client
-        .get(url)
+    let res = forward_request_id(state.http.get(url))
         .query(&[("serverId", server_id), ("username", username)])
         .send()
         .await?;
@@ -109,15 +132,16 @@ pub async fn has_joined(
     let AuthProviders(auth_providers) = state.config.read().await.auth_providers.clone();
 
     for provider in &auth_providers {
-        tokio::spawn(fetch_and_send(
+        tokio::spawn(propagate_request_id(fetch_and_send(
             State(state.clone()),
             provider.clone(),
             server_id.to_string(),
             username.to_string(),
             tx.clone()
-        ));
+        )).in_current_span());
     } 
     let mut errors = Vec::new(); // Counting fetches what returns errors
+    let mut unreachable = true; // Only network errors so far
     let mut misses = Vec::new(); // Counting non OK results
     let mut prov_count: usize = auth_providers.len();
     while prov_count > 0 {
@@ -126,9 +150,14 @@ pub async fn has_joined(
                 Ok(data) => return Ok(Some(data)),
                 Err(err) => {
                     match err {
+                        // Provider outages are answered by their proxies
+                        FetchError::WrongResponse(code, data) if code >= 500 => errors.push(format!("{code}: {data:?}")),
                         FetchError::WrongResponse(code, data) => misses.push((code, data)),
                         FetchError::SendError(err) => errors.push(err.to_string()),
-                        FetchError::Other(err) => errors.push(err.to_string()),
+                        FetchError::Other(err) => {
+                            unreachable = false;
+                            errors.push(err.to_string())
+                        },
                     }
                 },
             }
@@ -144,7 +173,11 @@ pub async fn has_joined(
     // Returns if some internals errors occured
     if !errors.is_empty() {
         error!("Something wrong with your authentification providers!\nMisses: {misses:?}\nErrors: {errors:?}");
-        Err(anyhow::anyhow!("{:?}", errors))
+        if unreachable {
+            Err(ProvidersUnreachable(errors).into())
+        } else {
+            Err(anyhow::anyhow!("{:?}", errors))
+        }
         
     } else {
         // Returning if user can't be authenticated
@@ -168,7 +201,7 @@ async fn fetch_and_send(
 #[derive(Debug, Clone)]
 pub struct UManager {
     /// Users with incomplete authentication
-    pending: Arc<DashMap<String, String>>, // <SHA1 serverId, USERNAME> TODO: Add automatic purge
+    pending: Arc<DashMap<String, (String, Instant)>>, // <SHA1 serverId, (USERNAME, created)>
     /// Authenticated users TODO: Change name to sessions
     authenticated: Arc<DashMap<String, Uuid>>, // <SHA1 serverId, Userinfo>
     /// Registered users
@@ -177,6 +210,21 @@ pub struct UManager {
     can_upload: Arc<DashMap<Uuid, bool>>,
     /// temp state
     requested_temp: Arc<DashMap<Uuid, bool>>,
+    /// Users in the reconnect grace period
+    disconnected: Arc<DashMap<Uuid, Instant>>,
+    /// Details of the bans
+    bans: Arc<DashMap<Uuid, BanInfo>>,
+}
+
+/// State of the user manager written to disk, pending authentications and reconnect grace periods aren't kept
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UManagerSnapshot {
+    pub authenticated: HashMap<String, Uuid>,
+    pub registered: HashMap<Uuid, Userinfo>,
+    pub can_upload: HashMap<Uuid, bool>,
+    pub requested_temp: HashMap<Uuid, bool>,
+    pub bans: HashMap<Uuid, BanInfo>,
 }
 
 impl UManager {
@@ -187,6 +235,8 @@ impl UManager {
             authenticated: Arc::new(DashMap::new()),
             can_upload: Arc::new(DashMap::new()),
             requested_temp: Arc::new(DashMap::new()),
+            disconnected: Arc::new(DashMap::new()),
+            bans: Arc::new(DashMap::new()),
         }
     }
     pub fn get_all_registered(&self) -> DashMap<Uuid, Userinfo> {
@@ -195,11 +245,28 @@ impl UManager {
     pub fn get_all_authenticated(&self) -> DashMap<String, Uuid> {
         self.authenticated.as_ref().clone()
     }
-    pub fn pending_insert(&self, server_id: String, username: String) {
-        self.pending.insert(server_id, username);
+    /// Adds a first stage authentication, evicting the oldest one if there are already `max` entries
+    pub fn pending_insert(&self, server_id: String, username: String, max: usize) {
+        if self.pending.len() >= max {
+            let oldest = self.pending.iter()
+                .min_by_key(|entry| entry.value().1)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                debug!("Too many pending authentications, evicting {oldest}");
+                self.pending.remove(&oldest);
+            }
+        }
+        self.pending.insert(server_id, (username, Instant::now()));
     }
-    pub fn pending_remove(&self, server_id: &str) -> Option<(String, String)> {
+    /// Returns the username if the first stage was passed less than `ttl` ago
+    pub fn pending_remove(&self, server_id: &str, ttl: Duration) -> Option<String> {
         self.pending.remove(server_id)
+            .filter(|(_, (_, created))| created.elapsed() < ttl)
+            .map(|(_, (username, _))| username)
+    }
+    /// Removes first stage authentications older than `ttl`
+    pub fn purge_pending(&self, ttl: Duration) {
+        self.pending.retain(|_, (_, created)| created.elapsed() < ttl);
     }
     pub fn insert(&self, uuid: Uuid, token: String, userinfo: Userinfo) -> Result<(), ()> {
         // Check for the presence of an active session.
@@ -230,6 +297,27 @@ impl UManager {
                 if userinfo.version != Userinfo::default().version { exist.version = userinfo.version };
             }).or_insert(usercopy);
     }
+    /// Registered user with the nickname, Minecraft nicknames are case-insensitive
+    pub fn find_by_nickname(&self, nickname: &str) -> Option<Userinfo> {
+        self.registered.iter()
+            .find(|user| !user.nickname.is_empty() && user.nickname.eq_ignore_ascii_case(nickname))
+            .map(|user| user.clone())
+    }
+    /// Another registered user with the nickname, compared case-insensitively
+    pub fn nickname_owner(&self, uuid: &Uuid, nickname: &str) -> Option<Uuid> {
+        self.registered.iter()
+            .find(|user| user.key() != uuid && user.nickname.eq_ignore_ascii_case(nickname))
+            .map(|user| *user.key())
+    }
+    /// Returns the previous nickname if the user is known under another one
+    pub fn renamed(&self, uuid: &Uuid, nickname: &str) -> Option<String> {
+        let user = self.registered.get(uuid)?;
+        if !user.nickname.is_empty() && user.nickname != nickname {
+            Some(user.nickname.clone())
+        } else {
+            None
+        }
+    }
     pub fn get(
         &self,
         token: &String,
@@ -243,16 +331,32 @@ impl UManager {
     ) -> Option<dashmap::mapref::one::Ref<'_, Uuid, Userinfo>> {
         self.registered.get(uuid)
     }
-    pub fn ban(&self, banned_user: &Userinfo) {
+    pub fn ban(&self, banned_user: &Userinfo, info: BanInfo) {
         self.registered.entry(banned_user.uuid)
             .and_modify(|exist| {
                 exist.banned = true;
-            }).or_insert(banned_user.clone());
+            }).or_insert(Userinfo { banned: true, ..banned_user.clone() });
+        self.bans.insert(banned_user.uuid, info);
     }
     pub fn unban(&self, uuid: &Uuid) {
         if let Some(mut user) = self.registered.get_mut(uuid) {
             user.banned = false;
         };
+        self.bans.remove(uuid);
+    }
+    /// Banned users with the details of their bans
+    pub fn bans(&self) -> Vec<(Userinfo, BanInfo)> {
+        self.registered.iter()
+            .filter(|user| user.banned)
+            .map(|user| {
+                let info = self.bans.get(user.key()).map(|info| info.clone()).unwrap_or_default();
+                (user.clone(), info)
+            })
+            .collect()
+    }
+    /// Details of the ban if the user is banned
+    pub fn ban_info(&self, uuid: &Uuid) -> Option<BanInfo> {
+        self.is_banned(uuid).then(|| self.bans.get(uuid).map(|info| info.clone()).unwrap_or_default())
     }
     pub fn _is_authenticated(&self, token: &String) -> bool {
         self.authenticated.contains_key(token)
@@ -263,9 +367,29 @@ impl UManager {
     pub fn is_banned(&self, uuid: &Uuid) -> bool {
         if let Some(user) = self.registered.get(uuid) { user.banned } else { false }
     }
+    /// Unbans the user if their ban expired, returns who was unbanned
+    pub fn lift_expired_ban(&self, uuid: &Uuid) -> Option<Userinfo> {
+        let expired = self.bans.get(uuid)
+            .and_then(|info| info.expires)
+            .is_some_and(|expires| expires <= chrono::Utc::now());
+        if !expired || !self.is_banned(uuid) {
+            return None;
+        }
+        self.unban(uuid);
+        self.registered.get(uuid).map(|user| user.clone())
+    }
     pub fn count_authenticated(&self) -> usize {
         self.authenticated.len()
     }
+    pub fn authenticated_uuids(&self) -> Vec<Uuid> {
+        self.authenticated.iter().map(|entry| *entry.value()).collect()
+    }
+    pub fn count_pending(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn count_banned(&self) -> usize {
+        self.registered.iter().filter(|user| user.banned).count()
+    }
     pub fn put_upload_state(&self, uuid: Uuid, upload_state: bool) {
         self.can_upload.insert(uuid, upload_state);
     }
@@ -282,11 +406,84 @@ impl UManager {
             .map(|temp_state| { *temp_state.value() })
             .unwrap_or(def)
     }
+    /// Temp states of every user who has one
+    pub fn temp_states(&self) -> HashMap<Uuid, bool> {
+        self.requested_temp.iter().map(|entry| (*entry.key(), *entry.value())).collect()
+    }
 
+    /// Removes everything known about the user, returns the user and how many tokens they had
+    pub fn forget(&self, uuid: &Uuid) -> (Option<Userinfo>, Vec<String>) {
+        let tokens: Vec<String> = self.authenticated.iter().filter(|entry| entry.value() == uuid).map(|entry| entry.key().clone()).collect();
+        for token in &tokens {
+            self.authenticated.remove(token);
+        }
+        self.can_upload.remove(uuid);
+        self.requested_temp.remove(uuid);
+        self.disconnected.remove(uuid);
+        self.bans.remove(uuid);
+        (self.registered.remove(uuid).map(|(_, user)| user), tokens)
+    }
     pub fn remove(&self, uuid: &Uuid) {
         let token = self.registered.get(uuid).unwrap().token.clone().unwrap();
         self.authenticated.remove(&token);
     }
+    /// Keeps the token valid for `grace` after the WebSocket disconnected.
+    /// Tokens replaced by a newer session are removed immediately.
+    pub fn disconnect(&self, uuid: &Uuid, token: &str, grace: Duration) {
+        let current = self.registered.get(uuid).and_then(|user| user.token.clone());
+        if grace.is_zero() || current.as_deref() != Some(token) {
+            self.authenticated.remove(token);
+            return;
+        }
+        let token = token.to_string();
+        let disconnected_at = Instant::now();
+        self.disconnected.insert(*uuid, disconnected_at);
+
+        let umanager = self.clone();
+        let uuid = *uuid;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            // Only if the user didn't reconnect since then
+            if umanager.disconnected.remove_if(&uuid, |_, at| *at == disconnected_at).is_some() {
+                debug!("Reconnect grace period of {uuid} expired");
+                umanager.authenticated.remove(&token);
+            }
+        });
+    }
+    /// Ends the reconnect grace period
+    pub fn mark_connected(&self, uuid: &Uuid) {
+        self.disconnected.remove(uuid);
+    }
+    pub fn snapshot(&self) -> UManagerSnapshot {
+        UManagerSnapshot {
+            authenticated: self.authenticated.iter().map(|entry| (entry.key().clone(), *entry.value())).collect(),
+            registered: self.registered.iter().map(|entry| (*entry.key(), entry.value().clone())).collect(),
+            can_upload: self.can_upload.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+            requested_temp: self.requested_temp.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+            bans: self.bans.iter().map(|entry| (*entry.key(), entry.value().clone())).collect(),
+        }
+    }
+    /// Adds the state from the snapshot, users known already are kept as they are.
+    /// Only the bans made through the API are restored, the other sources are synced again
+    pub fn restore(&self, snapshot: UManagerSnapshot) {
+        let bans: HashMap<Uuid, BanInfo> = snapshot.bans.into_iter().filter(|(_, info)| info.source == BanSource::Api).collect();
+        for (uuid, mut user) in snapshot.registered {
+            user.banned &= bans.contains_key(&uuid);
+            self.registered.entry(uuid).or_insert(user);
+        }
+        for (token, uuid) in snapshot.authenticated {
+            self.authenticated.entry(token).or_insert(uuid);
+        }
+        for (uuid, state) in snapshot.can_upload {
+            self.can_upload.entry(uuid).or_insert(state);
+        }
+        for (uuid, state) in snapshot.requested_temp {
+            self.requested_temp.entry(uuid).or_insert(state);
+        }
+        for (uuid, info) in bans {
+            self.bans.entry(uuid).or_insert(info);
+        }
+    }
 }
 // End of User manager
 
@@ -302,4 +499,104 @@ pub async fn check_auth(
         },
         None => Err(ApiError::BadRequest), 
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait(secs: u64) {
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        tokio::task::yield_now().await;
+    }
+
+    #[tokio::test]
+    async fn admin_required() {
+        let state = AppState::for_tests();
+        let (admin, user) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        state.config.write().await.admins.insert(admin);
+        for (uuid, token) in [(admin, "admin"), (user, "user")] {
+            let info = Userinfo { uuid, token: Some(token.to_string()), ..Default::default() };
+            state.user_manager.insert(uuid, token.to_string(), info).unwrap();
+        }
+        let extract = |token: &str| {
+            let (mut parts, _) = axum::http::Request::builder().header("token", token).body(()).unwrap().into_parts();
+            let state = state.clone();
+            async move { RequireAdmin::from_request_parts(&mut parts, &state).await }
+        };
+
+        assert_eq!(extract("admin").await.unwrap().0.uuid, admin);
+        assert_eq!(extract("user").await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(extract("unknown").await.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_grace() {
+        let umanager = UManager::new();
+        let uuid = Uuid::from_u128(1);
+        let token = "token".to_string();
+        let user = Userinfo { uuid, token: Some(token.clone()), ..Default::default() };
+        let grace = Duration::from_secs(10);
+        umanager.insert(uuid, token.clone(), user).unwrap();
+
+        // Reconnect within grace
+        umanager.disconnect(&uuid, &token, grace);
+        wait(5).await;
+        assert!(umanager.get(&token).is_some());
+        umanager.mark_connected(&uuid);
+        wait(10).await;
+        assert!(umanager.get(&token).is_some());
+
+        // Reconnect after grace
+        umanager.disconnect(&uuid, &token, grace);
+        wait(11).await;
+        assert!(umanager.get(&token).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_pending_removed() {
+        let umanager = UManager::new();
+        let ttl = Duration::from_secs(60);
+        umanager.pending_insert("stale".to_string(), "Old".to_string(), 10);
+        umanager.pending_insert("expired".to_string(), "Old".to_string(), 10);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        umanager.pending_insert("fresh".to_string(), "New".to_string(), 10);
+
+        assert_eq!(umanager.pending_remove("expired", ttl), None);
+        umanager.purge_pending(ttl);
+        assert_eq!(umanager.pending.len(), 1);
+        assert_eq!(umanager.pending_remove("fresh", ttl), Some("New".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_evicts_oldest() {
+        let umanager = UManager::new();
+        for id in ["a", "b", "c"] {
+            umanager.pending_insert(id.to_string(), id.to_string(), 2);
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(umanager.pending.len(), 2);
+        assert!(!umanager.pending.contains_key("a"));
+    }
+
+    #[test]
+    fn username_change_on_reauth() {
+        let umanager = UManager::new();
+        let uuid = Uuid::from_u128(1);
+        let user = |nickname: &str, token: &str| Userinfo {
+            uuid,
+            nickname: nickname.to_string(),
+            token: Some(token.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(umanager.renamed(&uuid, "Old"), None);
+        umanager.insert(uuid, "first".to_string(), user("Old", "first")).unwrap();
+        umanager.remove(&uuid);
+
+        assert_eq!(umanager.renamed(&uuid, "Old"), None);
+        assert_eq!(umanager.renamed(&uuid, "New"), Some("Old".to_string()));
+        umanager.insert(uuid, "second".to_string(), user("New", "second")).unwrap();
+        assert_eq!(umanager.get_by_uuid(&uuid).unwrap().nickname, "New");
+        assert_eq!(umanager.renamed(&uuid, "New"), None);
+    }
+}
@@ -1,4 +1,4 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -54,7 +54,7 @@ impl AuthProvider {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthProviders(pub Vec<AuthProvider>);
 
@@ -63,4 +63,26 @@ pub fn default_authproviders() -> AuthProviders {
         AuthProvider { name: "Mojang".to_string(), url: "https://sessionserver.mojang.com/session/minecraft/hasJoined".to_string() },
         AuthProvider { name: "ElyBy".to_string(), url: "http://minecraft.ely.by/session/hasJoined".to_string() }
         ])
-}
\ No newline at end of file
+}
+/// Details of a ban
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanInfo {
+    pub reason: Option<String>,
+    /// Permanent if not set
+    pub expires: Option<DateTime<Utc>>,
+    pub source: BanSource,
+}
+
+/// Where the ban came from
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BanSource {
+    /// Admin API
+    #[default]
+    Api,
+    /// advancedUsers in the config
+    Config,
+    /// banned-players.json of the Minecraft server
+    Minecraft,
+}
@@ -4,11 +4,21 @@ pub const CONFIG_ENV: &str = "RUST_CONFIG";
 pub const LOGS_ENV: &str = "LOGS_FOLDER";
 pub const ASSETS_ENV: &str = "ASSETS_FOLDER";
 pub const AVATARS_ENV: &str = "AVATARS_FOLDER";
+pub const LOG_FORMAT_ENV: &str = "LOG_FORMAT";
 
 // Instance info
 pub const SCULPTOR_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const REPOSITORY: &str = "shiroyashik/sculptor";
 
+// Host header of the requests from the lambda component, the only ones served by /internal
+pub const INTERNAL_HOST: &str = "lambda";
+
+// Tracing target of the avatar downloads, written to its own log file
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+// Features of `featureFlags` in the config
+pub const FEATURE_LIST_SUBSCRIBERS: &str = "list-subscribers";
+
 // reqwest parameters
 pub const USER_AGENT: &str = "reqwest";
 pub const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
@@ -19,4 +29,6 @@ pub const FIGURA_DEFAULT_VERSION: &str = "0.1.4";
 
 // Figura Assets
 pub const FIGURA_ASSETS_ZIP_URL: &str = "https://github.com/FiguraMC/Assets/archive/refs/heads/main.zip";
-pub const FIGURA_ASSETS_COMMIT_URL: &str = "https://api.github.com/repos/FiguraMC/Assets/commits/main";
\ No newline at end of file
+pub const FIGURA_ASSETS_COMMIT_URL: &str = "https://api.github.com/repos/FiguraMC/Assets/commits/main";
+pub const FIGURA_ASSETS_TREE_URL: &str = "https://api.github.com/repos/FiguraMC/Assets/git/trees";
+pub const FIGURA_ASSETS_RAW_URL: &str = "https://raw.githubusercontent.com/FiguraMC/Assets";
\ No newline at end of file
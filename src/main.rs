@@ -1,11 +1,12 @@
 #![allow(clippy::module_inception)]
 use anyhow::Result;
-use axum::{extract::DefaultBodyLimit, routing::{delete, get, post, put}, Router};
+use axum::{extract::DefaultBodyLimit, middleware, routing::{delete, get, post, put}, Router};
 use dashmap::DashMap;
 use tracing_panic::panic_hook;
-use tracing_subscriber::{fmt::{self, time::ChronoLocal}, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-use std::{path::PathBuf, sync::Arc, env::var};
-use tokio::{fs, sync::RwLock, time::Instant};
+use tracing_subscriber::{filter::filter_fn, fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _};
+use std::{path::{Path, PathBuf}, sync::{atomic::AtomicUsize, Arc}, env::var};
+use tokio::{fs, sync::{Mutex, RwLock, Semaphore}, time::Instant};
+use tower::Layer as _;
 use tower_http::trace::TraceLayer;
 use lazy_static::lazy_static;
 
@@ -19,8 +20,10 @@ pub use api::errors::{ApiResult, ApiError};
 // API
 mod api;
 use api::{
-    figura::{ws, info as api_info, profile as api_profile, auth as api_auth, assets as api_assets},
+    figura::{ws, info as api_info, profile as api_profile, auth as api_auth, assets as api_assets, report as api_report, cape as api_cape, admin as api_admin},
     lambda::{internal as lambda_internal, },
+    server::serve,
+    middleware::{client_ip, compression_layer, extra_headers, header_limits, instance, internal_host, legacy_paths, origin_uploads, reconnect_surge, request_id, slow_requests, with_timeout},
     // v1::{},
 };
 
@@ -31,7 +34,7 @@ use lambda_internal::{check_internal};
 
 // Config
 mod state;
-use state::{Config, AppState};
+use state::{Config, AppState, HttpClient, LogFormat, Metrics};
 
 // Utils
 mod utils;
@@ -53,6 +56,13 @@ lazy_static! {
     pub static ref AVATARS_VAR: String = {
         var(AVATARS_ENV).unwrap_or(String::from("data/avatars"))
     };
+    pub static ref HOSTNAME: String = {
+        var("HOSTNAME").ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(String::from("sculptor"))
+    };
 }
 
 #[tokio::main]
@@ -62,26 +72,39 @@ async fn main() -> Result<()> {
 
     // 2. Set up logging
     let file_appender = tracing_appender::rolling::never(&*LOGS_VAR, get_log_file(&LOGS_VAR));
-    let timer = ChronoLocal::new(String::from("%Y-%m-%dT%H:%M:%S%.3f%:z"));
+    let timer = log_timer();
+    // Parsed once before logging, so the log format can be configured
+    let config = Config::try_parse(CONFIG_VAR.clone().into());
+    let log_format = var(LOG_FORMAT_ENV).ok()
+        .and_then(|format| LogFormat::parse(&format))
+        .or_else(|| config.as_ref().ok().map(|config| config.log_format))
+        .unwrap_or_default();
 
-    let file_layer = fmt::layer()
-        .with_ansi(false) // Disable ANSI colors for file logs
-        .with_timer(timer.clone())
-        .pretty()
-        .with_writer(file_appender);
+    let not_access = filter_fn(|meta| meta.target() != ACCESS_LOG_TARGET);
+    let file_layer = file_log_layer(log_format, file_appender)
+        .with_filter(not_access.clone());
 
     // Create a layer for the terminal
     let terminal_layer = fmt::layer()
         .with_ansi(true)
-        .with_timer(timer)
+        .with_timer(timer.clone())
         .pretty()
-        .with_writer(std::io::stdout);
+        .with_writer(std::io::stdout)
+        .with_filter(not_access);
+
+    // Avatar downloads are kept apart, so they can be handled with their own retention
+    let access_layer = fmt::layer()
+        .with_ansi(false)
+        .with_timer(timer)
+        .with_writer(tracing_appender::rolling::daily(&*LOGS_VAR, "access.log"))
+        .with_filter(filter_fn(|meta| meta.target() == ACCESS_LOG_TARGET));
 
     // Combine the layers and set the global subscriber
     tracing_subscriber::registry()
         .with(EnvFilter::from(&*LOGGER_VAR))
         .with(file_layer)
         .with(terminal_layer)
+        .with(access_layer)
         .init();
 
     // std::panic::set_hook(Box::new(panic_hook));
@@ -94,7 +117,9 @@ async fn main() -> Result<()> {
     // 3. Display info about current instance and check updates
     tracing::info!("The Sculptor v{SCULPTOR_VERSION} ({REPOSITORY})");
 
-    match get_latest_version(REPOSITORY).await {
+    let config = config.unwrap_or_else(|err| {tracing::error!("{err:#?}"); panic!("Panic occured! See log messages!")});
+    let http = config.http_client.build()?;
+    match get_latest_version(&http, REPOSITORY).await {
         Ok(latest_version) => {
             if latest_version > semver::Version::parse(SCULPTOR_VERSION).expect("SCULPTOR_VERSION does not match SemVer!") {
                 tracing::info!("Available new v{latest_version}! Check https://github.com/{REPOSITORY}/releases");
@@ -109,7 +134,7 @@ async fn main() -> Result<()> {
 
     // 4. Starting an app() that starts to serve. If app() returns true, the sculptor will be restarted. for future
     loop {
-        if !app().await? {
+        if !app(config.clone(), http.clone()).await? {
             break;
         }
     }
@@ -117,7 +142,7 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn app() -> Result<bool> {
+async fn app(config: Config, http: reqwest::Client) -> Result<bool> {
     // Preparing for launch
     {
         let path = PathBuf::from(&*AVATARS_VAR);
@@ -134,9 +159,15 @@ async fn app() -> Result<bool> {
     }
 
     // Config
-    let config = Arc::new(RwLock::new(Config::parse(CONFIG_VAR.clone().into())));
+    let config = Arc::new(RwLock::new(config));
     let listen = config.read().await.listen.clone();
     let limit = get_limit_as_bytes(config.read().await.limitations.max_avatar_size as usize);
+    let timeouts = config.read().await.timeouts.clone();
+    config.read().await.validate()?;
+    let http_client = config.read().await.http_client.clone();
+
+    let (webhooks, webhook_events) = Webhooks::new();
+    tokio::spawn(dispatch_webhooks(webhook_events, Arc::clone(&config), http.clone()));
 
     if config.read().await.assets_updater_enabled {
         // Force update assets if folder or hash file doesn't exists.
@@ -144,89 +175,234 @@ async fn app() -> Result<bool> {
             tracing::debug!("Removing broken assets...");
             remove_assets().await
         }
-        match get_commit_sha(FIGURA_ASSETS_COMMIT_URL).await {
-            Ok(sha) => {
-                if is_assets_outdated(&sha).await.unwrap_or_else(|e| {tracing::error!("Can't check assets state due: {:?}", e); false}) {
-                    remove_assets().await;
-                    match tokio::task::spawn_blocking(|| { download_assets() }).await.unwrap() {
-                        Err(e) => tracing::error!("Assets outdated! Can't download new version due: {:?}", e),
-                        Ok(_) => {
-                            match write_sha_to_file(&sha).await {
-                                Ok(_) => tracing::info!("Assets successfully updated!"),
-                                Err(e) => tracing::error!("Assets successfully updated! Can't create assets hash file due: {:?}", e),
-                            }
-                        }
-                    };
-                } else { tracing::info!("Assets are up to date!") }
+        let download = config.read().await.assets_download.clone();
+        match get_commit_sha(&http, FIGURA_ASSETS_COMMIT_URL).await {
+            Ok(sha) => match update_assets(&http, &download, &sha, &get_path_to_assets_hash()).await {
+                Ok(AssetsUpdate::Updated) => {
+                    tracing::info!("Assets successfully updated!");
+                    webhooks.notify(WebhookEvent::AssetsUpdated, serde_json::json!({ "commit": sha }));
+                },
+                Ok(AssetsUpdate::UpToDate) => {
+                    tracing::info!("Assets are up to date!");
+                    if config.read().await.verify_assets {
+                        check_assets_integrity(&http_client).await;
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Can't update assets due: {:?}", e);
+                    webhooks.notify(WebhookEvent::AssetsUpdateFailed, serde_json::json!({ "error": format!("{e:#}") }));
+                },
+            },
+            Err(e) => {
+                tracing::error!("Can't get assets last commit! Assets update check aborted due {:?}", e);
+                webhooks.notify(WebhookEvent::AssetsUpdateFailed, serde_json::json!({ "error": format!("{e:#}") }));
             },
-            Err(e) => tracing::error!("Can't get assets last commit! Assets update check aborted due {:?}", e)
         }
     }
 
     // State
+    let compression = config.read().await.compression.clone();
+    let avatars = if config.read().await.storage.compress_avatars {
+        AvatarStore::new(PathBuf::from(&*AVATARS_VAR), compression.clone())
+    } else {
+        AvatarStore::uncompressed(PathBuf::from(&*AVATARS_VAR))
+    }.with_max_len(limit as u64);
+    let upload_limit = Arc::new(Semaphore::new(config.read().await.limitations.max_concurrent_uploads));
+    let download_limit = Arc::new(Semaphore::new(config.read().await.limitations.max_concurrent_downloads));
     let state = AppState {
         uptime: Instant::now(),
         user_manager: Arc::new(UManager::new()),
         session: Arc::new(DashMap::new()),
         subscribes: Arc::new(DashMap::new()),
+        last_pings: Arc::new(DashMap::new()),
+        positions: Arc::new(DashMap::new()),
+        statuses: Arc::new(DashMap::new()),
+        subscribers: Arc::new(DashMap::new()),
         figura_versions: Arc::new(RwLock::new(None)),
+        motd_rotation: Arc::new(AtomicUsize::new(0)),
+        remote_motd: Arc::new(RwLock::new(None)),
+        avatars,
+        report_cooldowns: Cooldown::default(),
+        export_cooldowns: Cooldown::default(),
+        upload_keys: IdempotencyKeys::default(),
+        chunked_uploads: ChunkedUploads::default(),
+        share_links: ShareLinks::default(),
+        origins: OriginLimiter::default(),
+        reconnects: SurgeLimiter::default(),
+        http,
+        assets_update: Arc::new(Mutex::new(())),
+        cache_warm: Arc::new(std::sync::Mutex::new(None)),
+        webhooks,
+        metrics: Arc::new(Metrics::default()),
+        upload_limit,
+        download_limit,
+        user_downloads: Arc::new(DashMap::new()),
         config,
     };
 
+    // Users of the previous run if it crashed
+    let snapshots = state.config.read().await.snapshots.clone();
+    if snapshots.interval.is_some() {
+        if let Some(snapshot) = read_snapshot(&snapshots.path).await {
+            tracing::info!("Restored {} users from the snapshot", snapshot.registered.len());
+            state.user_manager.restore(snapshot);
+        }
+    }
+    tokio::spawn(snapshot_users(state.clone()));
+
     // Automatic update of configuration/ban list while the server is running
-    tokio::spawn(update_advanced_users(
-        CONFIG_VAR.clone().into(),
+    tokio::spawn(update_advanced_users(CONFIG_VAR.clone().into(), state.clone(), SYNC_POLL_INTERVAL));
+    tokio::spawn(purge_broadcasts(state.clone()));
+    tokio::spawn(purge_trash(state.clone()));
+    tokio::spawn(purge_uploads(state.clone()));
+    tokio::spawn(purge_pending_auth(
         Arc::clone(&state.user_manager),
-        Arc::clone(&state.session),
         Arc::clone(&state.config)
     ));
+    let storage = state.config.read().await.storage.clone();
+    if storage.integrity_scan {
+        let avatars = state.avatars.clone();
+        tokio::spawn(async move {
+            match avatars.scan(storage.quarantine_corrupt).await {
+                Ok(corrupt) => tracing::info!("Avatar integrity scan finished, {} corrupt", corrupt.len()),
+                Err(e) => tracing::error!("Avatar integrity scan failed due: {:?}", e),
+            }
+        });
+    }
     if state.config.read().await.mc_folder.exists() {
-        tokio::spawn(update_bans_from_minecraft(
-            state.config.read().await.mc_folder.clone(),
-            Arc::clone(&state.user_manager),
-            Arc::clone(&state.session)
-        ));
+        tokio::spawn(update_bans_from_minecraft(state.config.read().await.mc_folder.clone(), state.clone(), SYNC_POLL_INTERVAL));
     }
 
+    let uploads_per_origin = middleware::from_fn_with_state(state.clone(), origin_uploads);
+    let surge = middleware::from_fn_with_state(state.clone(), reconnect_surge);
     let api = Router::new()
-        .nest("//auth", api_auth::router()) // => /api//auth ¯\_(ツ)_/¯
-        .nest("//assets", api_assets::router())
-        .nest("/v1", api::v1::router(limit))
         .route("/limits", get(api_info::limits))
+        .route("/limits/:rank", get(api_info::rank_limits))
         .route("/version", get(api_info::version))
         .route("/motd", get(api_info::motd))
+        .route("/time", get(api_info::time))
+        .route("/badges", get(api_info::badges))
         .route("/equip", post(api_profile::equip_avatar))
+        .route("/me/export", get(api_profile::export))
         .route("/:uuid", get(api_profile::user_info))
         .route("/:uuid/avatar", get(api_profile::download_avatar))
-        .route("/avatar", put(api_profile::upload_avatar).layer(DefaultBodyLimit::max(limit)))
-        .route("/avatar", delete(api_profile::delete_avatar));
+        .route("/:uuid/avatar/exists", get(api_profile::avatar_exists))
+        .route("/:uuid/avatars", get(api_profile::list_avatars))
+        .route("/avatar", delete(api_profile::delete_avatar))
+        .route("/avatar/temp", delete(api_profile::delete_temp_avatar))
+        .route("/avatar/restore", post(api_profile::restore_avatar))
+        .route("/avatar/:id/share", post(api_profile::share_avatar))
+        .route("/shared/:token", get(api_profile::shared_avatar))
+        .route("/avatar/:id/meta", put(api_profile::put_avatar_meta).layer(DefaultBodyLimit::max(api_profile::META_BODY_LIMIT)))
+        .route("/avatar/upload/init", post(api_profile::init_chunked_upload).layer(uploads_per_origin.clone()))
+        .route("/avatar/upload/:id", get(api_profile::chunked_upload_status))
+        .route("/avatar/upload/:id/chunk/:n", put(api_profile::upload_chunk).layer(DefaultBodyLimit::max(limit)))
+        .route("/avatar/upload/:id/complete", post(api_profile::complete_chunked_upload))
+        .route("/avatar/multipart", put(api_profile::upload_avatar_multipart).layer(DefaultBodyLimit::max(limit + api_profile::MULTIPART_OVERHEAD)).layer(uploads_per_origin.clone()))
+        .route("/admin/online", get(api_admin::online))
+        .route("/:uuid/cape", get(api_cape::download_cape))
+        .route("/cape", get(api_cape::own_cape).delete(api_cape::delete_cape))
+        .route("/cape", put(api_cape::upload_cape).layer(DefaultBodyLimit::max(limit)))
+        .route("/report", post(api_report::report).layer(DefaultBodyLimit::max(api_report::REPORT_BODY_LIMIT)));
+    // Uploads of slow clients take longer
+    let upload = Router::new()
+        .route("/avatar", put(api_profile::upload_avatar).layer(DefaultBodyLimit::max(limit)).layer(uploads_per_origin));
+    let api = with_timeout(api, timeouts.api)
+        .merge(with_timeout(upload, timeouts.upload))
+        .nest("//auth", with_timeout(api_auth::router(), timeouts.auth).layer(surge.clone())) // => /api//auth ¯\_(ツ)_/¯
+        .nest("//assets", with_timeout(api_assets::router(), timeouts.assets))
+        .nest("/v1", with_timeout(api::v1::router(limit), timeouts.v1))
+        .layer(middleware::map_response_with_state(state.clone(), extra_headers))
+        .layer(compression_layer(&compression))
+        .layer(middleware::from_fn_with_state(state.clone(), slow_requests));
 
     let internal = Router::new()
+        .route("/:uuid", delete(lambda_internal::delete_user))
         .route("/:uuid/temp", put(lambda_internal::temp_avatar))
         .route("/:uuid/avatar", put(lambda_internal::upload_avatar))
         .route("/:uuid/avatar", delete(lambda_internal::delete_avatar))
         .route("/:uuid/event", get(lambda_internal::user_event))
         .route("/:uuid/upload_state/:us", get(lambda_internal::user_upload_state))
-        .route("/health", get(check_internal));
+        .route("/bans", get(lambda_internal::bans))
+        .route("/bans/reload", post(lambda_internal::reload_bans))
+        .route("/metrics", get(lambda_internal::metrics))
+        .route("/temp-states", get(lambda_internal::temp_states))
+        .route("/cache/warm", post(lambda_internal::warm_cache).delete(lambda_internal::cancel_cache_warm))
+        .route("/debug/state", get(lambda_internal::debug_state))
+        .route("/config", get(lambda_internal::config))
+        .route("/health", get(check_internal))
+        .layer(middleware::from_fn_with_state(state.clone(), internal_host));
+    // Downloading the assets takes longer than the timeout
+    let internal = with_timeout(internal, timeouts.internal)
+        .route("/assets/update", post(lambda_internal::update_assets).layer(middleware::from_fn_with_state(state.clone(), internal_host)));
 
     let app = Router::new()
         .nest("/api", api)
         .route("/api/", get(check_auth))
-        .route("/ws", get(ws))
+        .route("/ws", get(ws).layer(surge))
         .nest("/internal", internal)
-        .with_state(state)
+        .route("/", get(api_info::root))
+        .route("/robots.txt", get(api_info::robots))
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.clone(), client_ip))
         .layer(TraceLayer::new_for_http().on_request(()))
-        .route("/health", get(|| async { "ok" }));
+        .layer(middleware::from_fn(request_id))
+        .route("/health", get(api_info::health).with_state(state.clone()))
+        .layer(middleware::from_fn_with_state(state.clone(), header_limits))
+        .layer(middleware::from_fn_with_state(state.clone(), instance));
 
+    let (user_manager, config) = (Arc::clone(&state.user_manager), Arc::clone(&state.config));
+    let legacy_state = state;
     let listener = tokio::net::TcpListener::bind(listen).await?;
     tracing::info!("Listening on {}", listener.local_addr()?);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    let app = middleware::from_fn_with_state(legacy_state, legacy_paths).layer(app);
+    serve(listener, app, Arc::clone(&config), shutdown_signal()).await?;
     tracing::info!("Serve stopped.");
+    let snapshots = config.read().await.snapshots.clone();
+    if snapshots.interval.is_some() {
+        if let Err(e) = write_snapshot(&snapshots.path, &user_manager.snapshot()).await {
+            tracing::error!("Can't write snapshot due: {e:?}");
+        }
+    }
     Ok(false)
 }
 
+/// Downloads again the asset files that don't match the manifest, or all assets if it fails
+async fn check_assets_integrity(http_client: &HttpClient) {
+    let broken = match verify_assets(Path::new(&*ASSETS_VAR), &get_path_to_assets_manifest()).await {
+        Ok(broken) if broken.is_empty() => {
+            tracing::info!("Assets are intact!");
+            return;
+        },
+        Ok(broken) => Some(broken),
+        Err(e) => {
+            tracing::warn!("Can't verify assets due: {:?}", e);
+            None
+        },
+    };
+    if let Some(broken) = broken {
+        let count = broken.len();
+        let http_client = http_client.clone();
+        match tokio::task::spawn_blocking(move || repair_assets(&http_client, &broken)).await.unwrap() {
+            Ok(()) => {
+                tracing::info!("{} broken asset files downloaded again", count);
+                return;
+            },
+            Err(e) => tracing::error!("Can't repair assets due: {:?}", e),
+        }
+    }
+    tracing::warn!("Downloading all assets again...");
+    remove_assets().await;
+    let http_client = http_client.clone();
+    let downloaded = tokio::task::spawn_blocking(move || {
+        let files = download_assets(&http_client, Path::new(&*ASSETS_VAR))?;
+        write_manifest(&get_path_to_assets_manifest(), &files)
+    }).await.unwrap();
+    if let Err(e) = downloaded {
+        tracing::error!("Can't download assets due: {:?}", e);
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -1,28 +1,110 @@
-use std::{collections::HashMap, io::Read, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, io::Read, net::IpAddr, path::PathBuf, time::Duration};
 
-use serde::Deserialize;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 use uuid::Uuid;
 
-use crate::auth::{default_authproviders, AuthProviders, Userinfo};
+use crate::{auth::{default_authproviders, AuthProviders, BanInfo, BanSource, Userinfo}, utils::{Motd, WebhookEvent}, HOSTNAME, TIMEOUT, USER_AGENT};
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub listen: String,
     pub token: Option<String>,
+    /// Name of this instance in logs, `/health` and `X-Sculptor-Instance`, the hostname if not set
+    pub instance_id: Option<String>,
+    /// Enables debugging endpoints
+    #[serde(default)]
+    pub debug: bool,
     pub assets_updater_enabled: bool,
+    /// Check the asset files against their hashes on startup
+    #[serde(default)]
+    pub verify_assets: bool,
     pub motd: CMotd,
     #[serde(default = "default_authproviders")]
     pub auth_providers: AuthProviders,
+    /// Let known users log in with the token of their last login while the auth providers are unreachable
+    #[serde(default)]
+    pub offline_fallback: bool,
     pub limitations: Limitations,
     #[serde(default)]
     pub mc_folder: PathBuf,
     #[serde(default)]
     pub advanced_users: HashMap<Uuid, AdvancedUsers>,
+    #[serde(default)]
+    pub ranks: HashMap<String, Rank>,
+    /// What to do when a player logs in while already having a session
+    #[serde(default)]
+    pub second_session_policy: SecondSessionPolicy,
+    /// What to do when a username is already known under another uuid
+    #[serde(default)]
+    pub duplicate_username_policy: DuplicateUsernamePolicy,
+    /// Rank of the players who log in for the first time
+    #[serde(default = "default_rank")]
+    pub default_rank: String,
+    /// Players with access to the admin endpoints
+    #[serde(default)]
+    pub admins: HashSet<Uuid>,
+    /// Serve a reduced public profile to requests without a token
+    #[serde(default)]
+    pub allow_anonymous_profiles: bool,
+    /// Format of the log file, overridden by `LOG_FORMAT`. Applied on restart
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Log who downloads whose avatar into access.log
+    #[serde(default = "default_access_log")]
+    pub access_log: bool,
+    /// `/` redirects there instead of showing the server info
+    #[serde(default)]
+    pub root_redirect: Option<String>,
+    #[serde(default)]
+    pub robots: Robots,
+    #[serde(default)]
+    pub sharing: Sharing,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub assets_download: AssetsDownload,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub reports: Reports,
+    #[serde(default)]
+    pub pending_auth: PendingAuth,
+    #[serde(default)]
+    pub websocket: WebSocketSettings,
+    #[serde(default)]
+    pub proxy: Proxy,
+    #[serde(default)]
+    pub origins: Origins,
+    #[serde(default)]
+    pub reconnect_surge: ReconnectSurge,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub compression: CompressionSettings,
+    #[serde(default)]
+    pub legacy: Legacy,
+    #[serde(default)]
+    pub messages: MessageLimits,
+    #[serde(default)]
+    pub badges: Badges,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub usernames: Usernames,
+    #[serde(default)]
+    pub snapshots: Snapshots,
+    #[serde(default)]
+    pub http_client: HttpClient,
+    /// Features enabled only for the listed users
+    #[serde(default)]
+    pub feature_flags: HashMap<String, Vec<Uuid>>,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct CMotd {
     pub display_server_info: bool,
@@ -33,34 +115,867 @@ pub struct CMotd {
     pub text_authclients: String,
     #[serde(rename = "sInfoDrawIndent")]
     pub draw_indent: bool,
+    #[serde(default)]
+    pub rotation: CMotdRotation,
+    /// Replaces customText with the MOTD fetched from this URL
+    pub remote_url: Option<String>,
+    /// How long the fetched MOTD is cached, in seconds
+    #[serde(default = "default_remote_ttl")]
+    pub remote_ttl: u64,
+}
+
+fn default_access_log() -> bool {
+    true
+}
+
+fn default_rank() -> String {
+    Userinfo::default().rank
+}
+
+fn default_remote_ttl() -> u64 {
+    300
+}
+
+/// Messages shown one at a time after the custom text
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CMotdRotation {
+    pub mode: RotationMode,
+    pub entries: Vec<CMotdEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RotationMode {
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CMotdEntry {
+    pub components: Vec<Motd>,
+    /// How often the entry is picked in random mode, relative to the other ones
+    #[serde(default = "default_motd_weight")]
+    pub weight: f64,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+fn default_motd_weight() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Limitations {
     pub max_avatar_size: u64,
+    /// Smaller uploads are rejected, they're usually interrupted ones. In bytes, unlike the maximum
+    #[serde(default = "default_min_avatar_size")]
+    pub min_avatar_size: u64,
     pub max_avatars: u64,
     pub can_upload: bool,
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+    #[serde(default = "default_max_concurrent_downloads")]
+    pub max_concurrent_downloads: usize,
+    /// Downloads of a single user processed at the same time, unlimited if not set
+    #[serde(default)]
+    pub max_concurrent_downloads_per_user: Option<usize>,
+    /// Seconds to wait for a free upload/download slot
+    #[serde(default = "default_concurrency_wait")]
+    pub concurrency_wait: u64,
+    /// Bytes per second for each avatar download, unlimited if not set
+    #[serde(default)]
+    pub download_rate: Option<u64>,
+    /// Milliseconds between the progress messages sent to the uploader, disabled if not set
+    #[serde(default)]
+    pub upload_progress: Option<u64>,
+    /// Seconds an upload with the `Idempotency-Key` header is remembered, so its retries aren't written again. 0 disables
+    #[serde(default = "default_idempotency_ttl")]
+    pub idempotency_ttl: u64,
+    /// Seconds between two exports of the data of a user
+    #[serde(default = "default_export_cooldown")]
+    pub export_cooldown: u64,
+    /// Seconds a resumable upload is kept after its last chunk
+    #[serde(default = "default_chunked_upload_ttl")]
+    pub chunked_upload_ttl: u64,
+}
+
+fn default_min_avatar_size() -> u64 {
+    16
+}
+
+fn default_idempotency_ttl() -> u64 {
+    600
+}
+
+fn default_export_cooldown() -> u64 {
+    60 * 60
+}
+
+fn default_chunked_upload_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    16
+}
+
+fn default_max_concurrent_downloads() -> usize {
+    128
+}
+
+fn default_concurrency_wait() -> u64 {
+    5
+}
+
+/// How the asset files of a new commit are downloaded
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AssetsDownload {
+    /// Files downloaded at the same time, 0 downloads the whole archive instead
+    pub concurrency: usize,
+    /// Attempts after the first one for each file
+    pub retries: u32,
+}
+
+impl Default for AssetsDownload {
+    fn default() -> Self {
+        Self { concurrency: 8, retries: 3 }
+    }
+}
+
+/// Request timeouts in seconds for each group of routes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Timeouts {
+    pub auth: u64,
+    pub assets: u64,
+    pub api: u64,
+    pub v1: u64,
+    pub internal: u64,
+    /// Avatar uploads, they are also rejected once no data is received for `upload_idle`
+    pub upload: u64,
+    pub upload_idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            auth: 15,
+            assets: 30,
+            api: 30,
+            v1: 30,
+            internal: 30,
+            upload: 300,
+            upload_idle: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Storage {
+    /// Compress avatars with the `compression` settings
+    pub compress_avatars: bool,
+    /// Uploads starting with any of these are rejected (case insensitive)
+    pub deny_patterns: Vec<String>,
+    /// Check avatars on startup
+    pub integrity_scan: bool,
+    /// Move corrupt avatars into `corrupt/`
+    pub quarantine_corrupt: bool,
+    /// Move deleted avatars into `trash/`, so they can be restored
+    pub soft_delete: bool,
+    /// Seconds the deleted avatars are kept
+    pub trash_retention: u64,
+    /// Files hashed per second by /internal/cache/warm, 0 is unlimited
+    pub warm_rate: u32,
+    /// Make a preview of the uploaded avatars for `user_info`
+    pub previews: bool,
+    /// Uploaded avatars are copied there in the background
+    pub backup_dir: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            compress_avatars: false,
+            deny_patterns: ["<!doctype", "<html", "<head", "<body", "<script", "<iframe", "<svg", "<?xml", "<?php", "#!"]
+                .iter().map(|pattern| pattern.to_string()).collect(),
+            integrity_scan: false,
+            quarantine_corrupt: false,
+            soft_delete: false,
+            trash_retention: 7 * 24 * 60 * 60,
+            warm_rate: 100,
+            previews: false,
+            backup_dir: None,
+        }
+    }
+}
+
+/// Compression of the stored avatars and HTTP responses
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompressionSettings {
+    pub algorithm: CompressionAlgorithm,
+    pub level: i32,
+    /// Compress responses for clients that accept it
+    pub http: bool,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Gzip,
+            level: 6,
+            http: true,
+        }
+    }
+}
+
+impl CompressionSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let range = match self.algorithm {
+            CompressionAlgorithm::None => return Ok(()),
+            CompressionAlgorithm::Gzip => 0..=9,
+            CompressionAlgorithm::Zstd => 1..=22,
+        };
+        if !range.contains(&self.level) {
+            anyhow::bail!("compression level of {:?} must be {} to {}, got {}", self.algorithm, range.start(), range.end(), self.level);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Http {
+    /// Added to all /api responses, unless the handler sets them
+    #[serde(deserialize_with = "deserialize_headers", serialize_with = "serialize_headers")]
+    pub extra_headers: HeaderMap,
+    /// Requests with more headers are answered with 431. Hyper already rejects more than 100
+    pub max_headers: Option<usize>,
+    /// Same for the total size of the header names and values, hyper allows about 400 KiB.
+    /// Hyper stops reading the larger request heads, the limits under 8 KiB are checked once they are parsed
+    pub max_header_bytes: Option<usize>,
+    /// /api requests taking longer than this many milliseconds are logged at warn level
+    pub slow_request_millis: Option<u64>,
+}
+
+impl Http {
+    /// The headers exceed a limit
+    pub fn headers_too_large(&self, headers: &HeaderMap) -> bool {
+        let size: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        self.max_headers.is_some_and(|max| headers.len() > max) || self.max_header_bytes.is_some_and(|max| size > max)
+    }
+}
+
+fn serialize_headers<S: serde::Serializer>(headers: &HeaderMap, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(headers.iter().map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default())))
+}
+
+fn deserialize_headers<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<HeaderMap, D::Error> {
+    use serde::de::Error;
+    let map = HashMap::<String, String>::deserialize(deserializer)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in map {
+        let name = HeaderName::try_from(&name).map_err(|_| D::Error::custom(format!("invalid header name: {name}")))?;
+        let value = HeaderValue::try_from(&value).map_err(|_| D::Error::custom(format!("invalid value of header {name}: {value}")))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Client of the requests to auth providers, GitHub, webhooks and the remote MOTD. Applied on restart
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HttpClient {
+    /// Seconds
+    pub timeout: u64,
+    pub user_agent: String,
+    /// All requests go through it, e.g. `http://proxy:3128`
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self {
+            timeout: TIMEOUT.as_secs(),
+            user_agent: USER_AGENT.to_string(),
+            proxy: None,
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn build(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .user_agent(&self.user_agent);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        builder.build()
+    }
+    /// For the blocking downloads, it must be built outside of the async runtime
+    pub fn build_blocking(&self) -> reqwest::Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .user_agent(&self.user_agent);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        builder.build()
+    }
+}
+
+/// Copies of the users on disk, restored after a crash
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Snapshots {
+    /// Seconds between two snapshots, disabled if not set
+    pub interval: Option<u64>,
+    pub path: PathBuf,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            path: PathBuf::from("data/snapshot.json"),
+        }
+    }
+}
+
+/// Names accepted in the authentication
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Usernames {
+    pub max_length: usize,
+    #[serde(serialize_with = "serialize_pattern", deserialize_with = "deserialize_pattern")]
+    pub pattern: UsernamePattern,
+}
+
+impl Default for Usernames {
+    fn default() -> Self {
+        Self {
+            max_length: 16,
+            // Names of Minecraft accounts
+            pattern: UsernamePattern(regex::Regex::new("^[A-Za-z0-9_]{3,16}$").unwrap()),
+        }
+    }
+}
+
+impl Usernames {
+    pub fn is_valid(&self, username: &str) -> bool {
+        username.chars().count() <= self.max_length && self.pattern.0.is_match(username)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UsernamePattern(pub regex::Regex);
+
+impl PartialEq for UsernamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+fn serialize_pattern<S: serde::Serializer>(pattern: &UsernamePattern, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(pattern.0.as_str())
+}
+
+fn deserialize_pattern<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<UsernamePattern, D::Error> {
+    use serde::de::Error;
+    let pattern = String::deserialize(deserializer)?;
+    regex::Regex::new(&pattern).map(UsernamePattern).map_err(|e| D::Error::custom(format!("invalid username pattern: {e}")))
+}
+
+/// Old paths still used by outdated clients
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Legacy {
+    /// Answer with a redirect instead of serving the current path
+    pub redirect: bool,
+    /// Legacy path -> current path
+    pub paths: HashMap<String, String>,
+}
+
+/// Limits of the toasts and chat messages sent through the API and of the statuses, in bytes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MessageLimits {
+    pub max_toast_length: usize,
+    pub max_chat_length: usize,
+    pub max_status_length: usize,
+    /// Cut over-long messages instead of rejecting them
+    pub truncate: bool,
+    /// Chat messages and statuses containing any of them are rejected, case-insensitive
+    pub blocked_words: Vec<String>,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_toast_length: 256,
+            max_chat_length: 1024,
+            max_status_length: 64,
+            truncate: false,
+            blocked_words: Vec::new(),
+        }
+    }
+}
+
+impl MessageLimits {
+    /// Returns the text cut to `max` bytes on a char boundary, or `None` if it's too long and can't be cut
+    pub fn limit(&self, text: String, max: usize) -> Option<String> {
+        if text.len() <= max {
+            return Some(text);
+        }
+        if !self.truncate {
+            return None;
+        }
+        let mut end = max;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(text[..end].to_string())
+    }
+
+    pub fn is_blocked(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.blocked_words.iter().any(|word| !word.is_empty() && text.contains(&word.to_lowercase()))
+    }
+}
+
+/// Reverse proxies in front of the Sculptor
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Proxy {
+    /// Take the client address from X-Forwarded-For/X-Real-IP
+    pub trust_proxy: bool,
+    /// Only these proxies are trusted
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self {
+            trust_proxy: false,
+            trusted_proxies: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        }
+    }
+}
+
+impl Proxy {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+    /// Address of the client behind trusted proxies
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trust_proxy || !self.is_trusted(&peer) {
+            return peer;
+        }
+        // X-Forwarded-For: client, proxy1, proxy2
+        let forwarded = headers.get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.rsplit(',').filter_map(|ip| ip.trim().parse::<IpAddr>().ok()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if let Some(client) = forwarded.iter().find(|ip| !self.is_trusted(ip)).or(forwarded.last()) {
+            return *client;
+        }
+        headers.get("x-real-ip")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+}
+
+/// Limits shared by all clients of an origin.
+/// Clients in the same bucket are one origin, other clients are limited by their address.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Origins {
+    pub buckets: Vec<IpNet>,
+    /// WebSocket connections of an origin
+    pub max_connections: Option<usize>,
+    /// Avatar uploads of an origin per minute
+    pub max_uploads_per_minute: Option<u32>,
+}
+
+impl Origins {
+    /// The most specific bucket of the address, or the address itself
+    pub fn origin(&self, ip: IpAddr) -> IpNet {
+        self.buckets.iter()
+            .filter(|bucket| bucket.contains(&ip))
+            .max_by_key(|bucket| bucket.prefix_len())
+            .map(IpNet::trunc)
+            .unwrap_or_else(|| IpNet::from(ip))
+    }
+}
+
+/// What search engines are told about the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Robots {
+    /// Served as /robots.txt
+    pub content: String,
+    /// Add `X-Robots-Tag: noindex` to the /api responses
+    pub noindex: bool,
+}
+
+impl Default for Robots {
+    fn default() -> Self {
+        Self { content: "User-agent: *\nDisallow: /\n".to_string(), noindex: true }
+    }
+}
+
+/// Links to an avatar for people who aren't on the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Sharing {
+    /// Seconds a link is valid
+    pub ttl: u64,
+    /// Key of the link signatures, a random one is used until the restart if not set
+    pub key: Option<String>,
+}
+
+impl Default for Sharing {
+    fn default() -> Self {
+        Self { ttl: 86400, key: None }
+    }
+}
+
+/// Spreads the reconnections after a restart or a network blip.
+/// Clients over the threshold get 503 and come back after a random part of `spread`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ReconnectSurge {
+    /// Authentications and WebSocket upgrades per second, unlimited if not set
+    pub threshold: Option<u32>,
+    /// Seconds over which the deferred clients are spread
+    pub spread: u64,
+}
+
+impl Default for ReconnectSurge {
+    fn default() -> Self {
+        Self { threshold: None, spread: 10 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WebSocketSettings {
+    /// Tell subscribers to reload the avatar of a user when they connect
+    pub event_on_reconnect: bool,
+    /// Seconds the token stays valid after disconnect, so the client can reconnect without re-auth
+    pub reconnect_grace: u64,
+    /// Seconds a banned player sees the ban toast before the connection is closed
+    pub ban_grace: u64,
+    /// Seconds after which a connection is closed with 1001 so the client reconnects, unlimited if not set
+    pub max_lifetime_secs: Option<u64>,
+    /// How many clients can subscribe to the pings of a single user, unlimited if not set
+    pub max_subscribers: Option<usize>,
+    /// How many last pings of a user are replayed to a new subscriber
+    pub replay_pings: usize,
+    /// Consecutive malformed messages after which the connection is closed
+    pub max_malformed: u32,
+    /// Seconds between removals of unused broadcast channels
+    pub broadcast_gc_interval: u64,
+    /// Pings per second of a client, further ones are dropped. Unlimited if not set
+    pub ping_rate: Option<u32>,
+    /// Tell the client that their pings are dropped
+    pub rate_limit_notice: bool,
+    /// Seconds between two such notices
+    pub rate_limit_notice_interval: u64,
+    /// Tell users who is subscribed to them, otherwise only how many
+    pub list_subscribers: bool,
+    /// Only nearby subscribers get the pings carrying a position, all get them if not set
+    pub ping_distance: Option<PingDistance>,
+    /// Bytes of the pings kept for subscribers, the oldest replayed ones are dropped first. Unlimited if not set
+    pub max_buffered_bytes: Option<usize>,
+    /// Messages waiting to be sent to a single connection
+    pub session_channel_capacity: usize,
+    /// Bytes of the ping data, larger pings are dropped. Ranks and users can override it
+    pub ping_size: usize,
+    pub version_gate: VersionGate,
+}
+
+/// Clients older than the minimum version are told to update and disconnected.
+/// The version is taken from the `Figura/<version>` User-Agent of the handshake, clients without it are let in
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VersionGate {
+    pub enabled: bool,
+    /// SemVer, e.g. "0.1.5"
+    pub min_version: String,
+    /// Description of the toast shown to outdated clients, `{version}` is replaced with the minimum version
+    pub message: String,
+    /// Seconds an outdated client sees the toast before the connection is closed
+    pub grace: u64,
+}
+
+impl Default for VersionGate {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_version: "0.1.4".to_string(),
+            message: "This server requires Figura {version} or newer".to_string(),
+            grace: 6,
+        }
+    }
+}
+
+impl VersionGate {
+    /// The client is known to be older than the minimum version
+    pub fn rejects(&self, version: Option<&semver::Version>) -> bool {
+        let Ok(min) = semver::Version::parse(&self.min_version) else { return false };
+        self.enabled && version.is_some_and(|version| *version < min)
+    }
+}
+
+/// Pings of the listed functions start with the position of the sender:
+/// x, y and z as big-endian f64. It isn't checked, so avatars must agree on it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PingDistance {
+    /// In blocks
+    pub max_distance: f64,
+    /// Ids of the ping functions, other pings are sent to everyone
+    pub functions: Vec<u32>,
+}
+
+impl PingDistance {
+    /// Position of the sender if the ping carries it
+    pub fn position(&self, func_id: u32, data: &[u8]) -> Option<[f64; 3]> {
+        if !self.functions.contains(&func_id) || data.len() < 24 {
+            return None;
+        }
+        let coordinate = |i: usize| f64::from_be_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+        let position = [coordinate(0), coordinate(1), coordinate(2)];
+        position.iter().all(|c| c.is_finite()).then_some(position)
+    }
+    pub fn in_range(&self, a: [f64; 3], b: [f64; 3]) -> bool {
+        let squared: f64 = a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum();
+        squared <= self.max_distance.powi(2)
+    }
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            event_on_reconnect: true,
+            reconnect_grace: 0,
+            ban_grace: 6,
+            max_lifetime_secs: None,
+            max_subscribers: None,
+            replay_pings: 4,
+            max_malformed: 8,
+            broadcast_gc_interval: 300,
+            ping_rate: None,
+            rate_limit_notice: true,
+            rate_limit_notice_interval: 10,
+            list_subscribers: false,
+            ping_distance: None,
+            max_buffered_bytes: None,
+            session_channel_capacity: 32,
+            ping_size: 1024,
+            version_gate: VersionGate::default(),
+        }
+    }
+}
+
+/// Users who passed only the first stage of authentication
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PendingAuth {
+    /// Seconds to complete the authentication
+    pub ttl: u64,
+    pub max_entries: usize,
+}
+
+impl Default for PendingAuth {
+    fn default() -> Self {
+        Self {
+            ttl: 60,
+            max_entries: 10000,
+        }
+    }
+}
+
+/// Reports sent by clients to /api/report
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Reports {
+    /// Seconds between two reports of the same user
+    pub cooldown: u64,
+    /// Reports are also sent there
+    pub webhook: Option<String>,
+}
+
+/// Receives the events as JSON
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub url: String,
+    /// Only these events are sent, all if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Default for Reports {
+    fn default() -> Self {
+        Self {
+            cooldown: 30,
+            webhook: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct AdvancedUsers {
     #[serde(default)]
     pub username: String,
     #[serde(default)]
     pub banned: bool,
+    /// Overrides the badges of the rank
+    #[serde(default)]
+    pub special: Option<Vec<u8>>,
     #[serde(default)]
-    pub special: [u8;6],
+    pub pride: Option<Vec<u8>>,
+    /// Overrides the ping size of the rank
     #[serde(default)]
-    pub pride: [u8;25],
+    pub ping_size: Option<usize>,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Readable, the same as the terminal
+    #[default]
+    Pretty,
+    /// A JSON object per line for log aggregators
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecondSessionPolicy {
+    /// The new login replaces the old session
+    #[default]
+    Replace,
+    /// The new login is rejected
+    Reject,
+    /// The new login replaces the old session, and the old connection is closed
+    KickOld,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateUsernamePolicy {
+    /// Both accounts can log in
+    #[default]
+    Allow,
+    /// Both accounts can log in, the duplicate is logged
+    Warn,
+    /// The account logging in under a known username of another uuid is rejected
+    Reject,
+}
+
+/// Badge slots known by the clients
+pub const SPECIAL_BADGES: usize = 6;
+pub const PRIDE_BADGES: usize = 25;
+
+/// Descriptions of the badge slots shown by the clients
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Badges {
+    pub special: Vec<BadgeInfo>,
+    pub pride: Vec<BadgeInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BadgeInfo {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub icon: u32,
+}
+
+impl Default for Badges {
+    fn default() -> Self {
+        let badges = |names: &[&str], description: fn(&str) -> String| names.iter().enumerate()
+            .map(|(icon, name)| BadgeInfo { name: name.to_string(), description: description(name), icon: icon as u32 })
+            .collect();
+        Self {
+            special: badges(
+                &["Developer", "Discord Staff", "Contest Winner", "Donator", "Translator", "Texture Artist"],
+                |name| format!("{name} of Figura"),
+            ),
+            pride: badges(
+                &[
+                    "Agender", "Aroace", "Aromantic", "Asexual", "Bigender", "Bisexual", "Demiboy", "Demigender",
+                    "Demigirl", "Demiromantic", "Demisexual", "Disability", "Finsexual", "Gay Men", "Genderfae",
+                    "Genderfluid", "Genderqueer", "Intersex", "Lesbian", "Nonbinary", "Pansexual", "Plural",
+                    "Polysexual", "Pride", "Transgender",
+                ],
+                |name| format!("{name} pride flag"),
+            ),
+        }
+    }
+}
+
+/// Settings shared by all users of the rank
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Rank {
+    /// Default badges, users without their own get these
+    pub special: Option<Vec<u8>>,
+    pub pride: Option<Vec<u8>>,
+    /// Overrides `websocket.ping_size`
+    pub ping_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct BannedPlayer {
     pub uuid: Uuid,
     pub name: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// "forever" or "2024-01-01 00:00:00 +0000"
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+impl BannedPlayer {
+    pub fn ban_info(&self) -> BanInfo {
+        let expires = self.expires.as_deref()
+            .and_then(|expires| chrono::DateTime::parse_from_str(expires, "%Y-%m-%d %H:%M:%S %z").ok())
+            .map(|expires| expires.to_utc());
+        BanInfo { reason: self.reason.clone(), expires, source: BanSource::Minecraft }
+    }
 }
 
 impl From<BannedPlayer> for Userinfo {
@@ -83,6 +998,89 @@ impl Config {
         toml::from_str(&data).unwrap_or_else(|err| {tracing::error!("{err:#?}"); panic!("Panic occured! See log messages!")})
     }
 
+    /// Like `parse`, but returns the error
+    pub fn try_parse(path: PathBuf) -> anyhow::Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Current configuration without secrets
+    pub fn sanitized(&self) -> serde_json::Result<serde_json::Value> {
+        const REDACTED: &str = "<redacted>";
+        let mut config = self.clone();
+        config.token = config.token.map(|_| REDACTED.to_string());
+        config.reports.webhook = config.reports.webhook.map(|_| REDACTED.to_string());
+        config.sharing.key = config.sharing.key.map(|_| REDACTED.to_string());
+        for webhook in &mut config.webhooks {
+            webhook.url = REDACTED.to_string();
+        }
+        // May contain credentials
+        config.http_client.proxy = config.http_client.proxy.map(|_| REDACTED.to_string());
+        serde_json::to_value(config)
+    }
+
+    /// Checks the settings that can't be checked while parsing
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.compression.validate()?;
+        if self.badges.special.len() != SPECIAL_BADGES || self.badges.pride.len() != PRIDE_BADGES {
+            anyhow::bail!("badges must describe {SPECIAL_BADGES} special and {PRIDE_BADGES} pride badges");
+        }
+        if !self.ranks.is_empty() && !self.ranks.contains_key(&self.default_rank) {
+            anyhow::bail!("default rank {} is not in ranks", self.default_rank);
+        }
+        let rotation = &self.motd.rotation;
+        if rotation.entries.iter().any(|entry| !(entry.weight >= 0.0 && entry.weight.is_finite())) {
+            anyhow::bail!("weights of motd.rotation.entries must be non-negative numbers");
+        }
+        if rotation.mode == RotationMode::Random && !rotation.entries.is_empty() && rotation.entries.iter().all(|entry| entry.weight == 0.0) {
+            anyhow::bail!("at least one of motd.rotation.entries must have a positive weight");
+        }
+        if self.pending_auth.ttl == 0 {
+            anyhow::bail!("pendingAuth.ttl must be at least 1 second");
+        }
+        if self.websocket.broadcast_gc_interval == 0 {
+            anyhow::bail!("websocket.broadcastGcInterval must be at least 1 second");
+        }
+        let timeouts = &self.timeouts;
+        if [timeouts.auth, timeouts.assets, timeouts.api, timeouts.v1, timeouts.internal, timeouts.upload, timeouts.upload_idle].contains(&0) {
+            anyhow::bail!("timeouts must be at least 1 second");
+        }
+        let gate = &self.websocket.version_gate;
+        if gate.enabled {
+            semver::Version::parse(&gate.min_version)
+                .map_err(|e| anyhow::anyhow!("websocket.versionGate.minVersion {:?} isn't a valid version: {e}", gate.min_version))?;
+        }
+        for problem in self.badge_problems() {
+            warn!("{problem}, it's padded with zeros or truncated");
+        }
+        Ok(())
+    }
+
+    /// Ping size of the user, from their advancedUsers entry, then their rank, then `websocket.ping_size`
+    pub fn ping_size(&self, uuid: &Uuid, rank: &str) -> usize {
+        self.advanced_users.get(uuid).and_then(|user| user.ping_size)
+            .or_else(|| self.ranks.get(rank).and_then(|rank| rank.ping_size))
+            .unwrap_or(self.websocket.ping_size)
+    }
+
+    pub fn instance_id(&self) -> &str {
+        self.instance_id.as_deref().unwrap_or(&HOSTNAME)
+    }
+
+    /// Badge arrays of the ranks and users with the wrong length
+    pub fn badge_problems(&self) -> Vec<String> {
+        let check = |owner: String, special: &Option<Vec<u8>>, pride: &Option<Vec<u8>>| {
+            let wrong = |kind: &str, badges: &Option<Vec<u8>>, len: usize| badges.as_ref()
+                .filter(|badges| badges.len() != len)
+                .map(|badges| format!("{kind} badges of {owner} have {} entries instead of {len}", badges.len()));
+            [wrong("special", special, SPECIAL_BADGES), wrong("pride", pride, PRIDE_BADGES)].into_iter().flatten().collect::<Vec<_>>()
+        };
+        let ranks = self.ranks.iter().flat_map(|(name, rank)| check(format!("rank {name}"), &rank.special, &rank.pride));
+        let users = self.advanced_users.iter().flat_map(|(uuid, user)| check(format!("user {uuid}"), &user.special, &user.pride));
+        let mut problems: Vec<String> = ranks.chain(users).collect();
+        problems.sort();
+        problems
+    }
+
     pub fn verify_token(&self, suspicious: &str) -> crate::ApiResult<()> {
         use crate::ApiError;
         match &self.token {
@@ -101,4 +1099,151 @@ impl Config {
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn http_client_user_agent() {
+        let app = axum::Router::new().route("/", axum::routing::get(|headers: HeaderMap| async move {
+            headers.get(axum::http::header::USER_AGENT).map(|agent| agent.to_str().unwrap().to_string()).unwrap_or_default()
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = HttpClient { user_agent: "Sculptor-test/1.0".to_string(), ..Default::default() }.build().unwrap();
+        assert_eq!(client.get(&url).send().await.unwrap().text().await.unwrap(), "Sculptor-test/1.0");
+        assert!(HttpClient { proxy: Some("not a proxy".to_string()), ..Default::default() }.build().is_err());
+    }
+
+    #[test]
+    fn client_ip_behind_proxy() {
+        let mut proxy = Proxy { trust_proxy: true, ..Default::default() };
+        proxy.trusted_proxies.push("10.0.0.0/8".parse().unwrap());
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1, 203.0.113.9, 10.1.1.1".parse().unwrap());
+
+        assert_eq!(proxy.client_ip(local, &headers), "203.0.113.9".parse::<IpAddr>().unwrap());
+        // Untrusted peers can't spoof the address
+        assert_eq!(proxy.client_ip(stranger, &headers), stranger);
+
+        headers.clear();
+        headers.insert("x-real-ip", "198.51.100.2".parse().unwrap());
+        assert_eq!(proxy.client_ip(local, &headers), "198.51.100.2".parse::<IpAddr>().unwrap());
+
+        proxy.trust_proxy = false;
+        assert_eq!(proxy.client_ip(local, &headers), local);
+    }
+
+    #[test]
+    fn secrets_redacted() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.token = Some("admin-secret".to_string());
+        config.reports.webhook = Some("https://example.com/hook/secret".to_string());
+        config.webhooks.push(Webhook { url: "https://example.com/events/secret".to_string(), events: Vec::new() });
+        config.http.extra_headers.insert("server", "Sculptor".parse().unwrap());
+        config.sharing.key = Some("share-secret".to_string());
+
+        let sanitized = config.sanitized().unwrap();
+        assert!(!sanitized.to_string().contains("secret"));
+        assert_eq!(sanitized["token"], "<redacted>");
+        assert_eq!(sanitized["reports"]["webhook"], "<redacted>");
+        assert_eq!(sanitized["webhooks"][0]["url"], "<redacted>");
+        assert_eq!(sanitized["sharing"]["key"], "<redacted>");
+        assert_eq!(sanitized["http"]["extraHeaders"]["server"], "Sculptor");
+        assert_eq!(sanitized["listen"], config.listen.as_str());
+
+        config.token = None;
+        assert!(config.sanitized().unwrap()["token"].is_null());
+    }
+
+    #[test]
+    fn default_rank_validated() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.default_rank = "member".to_string();
+        assert!(config.validate().is_ok());
+        config.ranks.insert("donor".to_string(), Rank::default());
+        assert!(config.validate().is_err());
+        config.ranks.insert("member".to_string(), Rank::default());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn zero_pending_auth_ttl_rejected() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        assert!(config.validate().is_ok());
+        config.pending_auth.ttl = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn zero_broadcast_gc_interval_rejected() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.websocket.broadcast_gc_interval = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn zero_timeouts_rejected() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.timeouts.v1 = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rank_badges_validated() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.advanced_users.clear();
+        config.ranks.insert("donor".to_string(), Rank { special: Some(vec![0, 0, 0, 1, 0, 0]), pride: Some(vec![1; PRIDE_BADGES]), ..Default::default() });
+        assert!(config.badge_problems().is_empty());
+        config.ranks.insert("staff".to_string(), Rank { special: Some(vec![1]), ..Default::default() });
+        assert_eq!(config.badge_problems(), ["special badges of rank staff have 1 entries instead of 6"]);
+        // Only a warning, the arrays are normalized when they're used
+        config.default_rank = "donor".to_string();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn ping_size_overridden() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        let (trusted, donor, player) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let user = AdvancedUsers { username: String::new(), banned: false, special: None, pride: None, ping_size: Some(4096) };
+        config.advanced_users.insert(trusted, user);
+        config.ranks.insert("donor".to_string(), Rank { ping_size: Some(2048), ..Default::default() });
+
+        let ping = vec![0u8; 3000];
+        assert!(ping.len() <= config.ping_size(&trusted, "donor"));
+        assert!(ping.len() > config.ping_size(&donor, "donor"));
+        assert_eq!(config.ping_size(&donor, "donor"), 2048);
+        assert_eq!(config.ping_size(&player, "default"), 1024);
+    }
+
+    #[test]
+    fn compression_level_validated() {
+        let mut compression = CompressionSettings::default();
+        assert!(compression.validate().is_ok());
+        compression.level = 10;
+        assert!(compression.validate().is_err());
+        compression.algorithm = CompressionAlgorithm::Zstd;
+        assert!(compression.validate().is_ok());
+        compression.level = 0;
+        assert!(compression.validate().is_err());
+        compression.algorithm = CompressionAlgorithm::None;
+        assert!(compression.validate().is_ok());
+    }
+
+    #[test]
+    fn extra_headers_validated() {
+        let http: Http = toml::from_str("[extraHeaders]\nServer = \"Sculptor\"\nX-Frame-Options = \"DENY\"").unwrap();
+        assert_eq!(http.extra_headers["server"], "Sculptor");
+        assert_eq!(http.extra_headers["x-frame-options"], "DENY");
+
+        assert!(toml::from_str::<Http>("[extraHeaders]\n\"Bad Name\" = \"1\"").is_err());
+        assert!(toml::from_str::<Http>("[extraHeaders]\nServer = \"line\\nbreak\"").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::atomic::AtomicUsize;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Counters exposed to operators
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metrics {
+    /// WebSocket close codes sent by clients
+    pub client_close_codes: DashMap<u16, u64>,
+    /// WebSocket close codes sent by the server
+    pub server_close_codes: DashMap<u16, u64>,
+    /// Bytes of the pings kept for subscribers
+    pub buffered_bytes: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn client_closed(&self, code: u16) {
+        *self.client_close_codes.entry(code).or_default() += 1;
+    }
+    pub fn server_closed(&self, code: u16) {
+        *self.server_close_codes.entry(code).or_default() += 1;
+    }
+}
@@ -1,5 +1,7 @@
 mod config;
+mod metrics;
 mod state;
 
 pub use config::*;
+pub use metrics::*;
 pub use state::*;
\ No newline at end of file
@@ -1,10 +1,13 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::AtomicUsize, Arc}, time::Duration};
 
 use dashmap::DashMap;
 use tokio::{sync::*, time::Instant};
+use tokio_util::sync::CancellationToken;
+use serde_json::json;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::{api::figura::SessionMessage, auth::UManager, FiguraVersions};
+use crate::{api::{errors::internal_and_log, figura::{Frame, SessionMessage}}, auth::{BanInfo, UManager, Userinfo}, utils::{AvatarStore, ChunkedUploads, Cooldown, IdempotencyKeys, OriginLimiter, RemoteMotd, ShareLinks, SurgeLimiter, WebhookEvent, Webhooks}, ApiError, ApiResult, FiguraVersions};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -15,9 +18,229 @@ pub struct AppState {
     /// Send into WebSocket
     pub session: Arc<DashMap<Uuid, mpsc::Sender<SessionMessage>>>,
     /// Send messages for subscribers
-    pub subscribes: Arc<DashMap<Uuid, broadcast::Sender<Vec<u8>>>>,
+    pub subscribes: Arc<DashMap<Uuid, broadcast::Sender<Frame>>>,
+    /// Last pings of users, replayed to new subscribers
+    pub last_pings: Arc<DashMap<Uuid, VecDeque<Frame>>>,
+    /// Last known positions of users, from their pings
+    pub positions: Arc<DashMap<Uuid, [f64; 3]>>,
+    /// Statuses set by the connected users
+    pub statuses: Arc<DashMap<Uuid, String>>,
+    /// Who is subscribed to the user, with the number of their subscriptions
+    pub subscribers: Arc<DashMap<Uuid, HashMap<Uuid, usize>>>,
     /// Current configuration
     pub config: Arc<RwLock<super::Config>>,
     /// Caching Figura Versions
     pub figura_versions: Arc<RwLock<Option<FiguraVersions>>>,
-}
\ No newline at end of file
+    /// Position of the MOTD rotation
+    pub motd_rotation: Arc<AtomicUsize>,
+    /// Caching remote MOTD
+    pub remote_motd: Arc<RwLock<Option<RemoteMotd>>>,
+    /// Avatar files
+    pub avatars: AvatarStore,
+    /// Last reports of users
+    pub report_cooldowns: Cooldown,
+    /// Last data exports of users
+    pub export_cooldowns: Cooldown,
+    /// Idempotency keys of the recent uploads
+    pub upload_keys: IdempotencyKeys,
+    /// Resumable uploads in progress
+    pub chunked_uploads: ChunkedUploads,
+    pub share_links: ShareLinks,
+    /// Connections and uploads of each origin
+    pub origins: OriginLimiter,
+    /// Recent authentications and WebSocket upgrades
+    pub reconnects: SurgeLimiter,
+    /// Client of the outbound requests
+    pub http: reqwest::Client,
+    /// Held while the assets are updated
+    pub assets_update: Arc<Mutex<()>>,
+    /// Cancels the running warm-up of the avatar hashes
+    pub cache_warm: Arc<std::sync::Mutex<Option<CancellationToken>>>,
+    /// Events for the webhooks
+    pub webhooks: Webhooks,
+    /// Counters for operators
+    pub metrics: Arc<super::Metrics>,
+    /// Avatar uploads in progress
+    pub upload_limit: Arc<Semaphore>,
+    /// Avatar downloads in progress
+    pub download_limit: Arc<Semaphore>,
+    /// Avatar downloads in progress of each user
+    pub user_downloads: Arc<DashMap<Uuid, Arc<Semaphore>>>,
+}
+
+/// Download slot of a user, the semaphore of the user is removed with the last slot
+pub struct UserDownloadPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    downloads: Arc<DashMap<Uuid, Arc<Semaphore>>>,
+    uuid: Uuid,
+}
+
+impl Drop for UserDownloadPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        // Only the map holds the semaphore when no one else downloads
+        self.downloads.remove_if(&self.uuid, |_, semaphore| Arc::strong_count(semaphore) == 1);
+    }
+}
+
+impl AppState {
+    /// Removes broadcast channels of disconnected users without subscribers
+    pub fn purge_broadcasts(&self) -> usize {
+        let before = self.subscribes.len();
+        self.subscribes.retain(|uuid, tx| tx.receiver_count() > 0 || self.session.contains_key(uuid));
+        self.last_pings.retain(|uuid, _| self.subscribes.contains_key(uuid));
+        before.saturating_sub(self.subscribes.len())
+    }
+    /// Expired bans are lifted here, so the user is let in the moment it expires
+    pub fn is_banned(&self, uuid: &Uuid) -> bool {
+        if let Some(user) = self.user_manager.lift_expired_ban(uuid) {
+            info!("[Bans] the ban of {} ({}) expired", user.nickname, user.uuid);
+            self.webhooks.notify(WebhookEvent::UserUnbanned, json!({ "uuid": user.uuid, "nickname": user.nickname }));
+        }
+        self.user_manager.is_banned(uuid)
+    }
+    /// Bans the user from any source and disconnects them.
+    /// The webhooks are told about new bans, unless `announce` is unset for the bans loaded on startup
+    pub async fn ban(&self, user: &Userinfo, info: BanInfo, announce: bool) {
+        let (newly, source) = (!self.user_manager.is_banned(&user.uuid), info.source);
+        self.user_manager.ban(user, info);
+        if newly && announce {
+            let nickname = self.user_manager.get_by_uuid(&user.uuid).map(|user| user.nickname.clone()).unwrap_or_default();
+            info!("[Bans] {nickname} ({}) was banned by {source:?}", user.uuid);
+            self.webhooks.notify(WebhookEvent::UserBanned, json!({ "uuid": user.uuid, "nickname": nickname, "source": source }));
+        }
+        let tx = self.session.get(&user.uuid).map(|tx| tx.clone());
+        if let Some(tx) = tx {
+            let _ = tx.send(SessionMessage::Banned).await;
+        }
+    }
+    pub async fn is_admin(&self, uuid: &Uuid) -> bool {
+        self.config.read().await.admins.contains(uuid)
+    }
+    /// The feature is enabled for the user in `featureFlags`
+    pub async fn feature_enabled(&self, feature: &str, uuid: &Uuid) -> bool {
+        self.config.read().await.feature_flags.get(feature).is_some_and(|uuids| uuids.contains(uuid))
+    }
+    /// Takes a download slot of the user without waiting, None if all `max` slots are taken
+    pub fn user_download_permit(&self, uuid: Uuid, max: usize) -> Option<UserDownloadPermit> {
+        let semaphore = Arc::clone(&self.user_downloads.entry(uuid).or_insert_with(|| Arc::new(Semaphore::new(max))));
+        let permit = semaphore.try_acquire_owned().ok();
+        let permit = UserDownloadPermit { permit, downloads: Arc::clone(&self.user_downloads), uuid };
+        // Dropping it cleans up the semaphore if it was just created
+        permit.permit.is_some().then_some(permit)
+    }
+    /// Waits for a free upload slot
+    pub async fn upload_permit(&self) -> ApiResult<OwnedSemaphorePermit> {
+        self.permit(&self.upload_limit).await
+    }
+    /// Waits for a free download slot
+    pub async fn download_permit(&self) -> ApiResult<OwnedSemaphorePermit> {
+        self.permit(&self.download_limit).await
+    }
+    async fn permit(&self, semaphore: &Arc<Semaphore>) -> ApiResult<OwnedSemaphorePermit> {
+        let wait = Duration::from_secs(self.config.read().await.limitations.concurrency_wait);
+        match tokio::time::timeout(wait, Arc::clone(semaphore).acquire_owned()).await {
+            Ok(permit) => permit.map_err(internal_and_log),
+            Err(_) => {
+                warn!("Too many concurrent transfers, request rejected");
+                Err(ApiError::ServiceUnavailable)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State with a minimal configuration, for handler tests
+    pub fn for_tests() -> Self {
+        let config: super::Config = toml::from_str(r#"
+            listen = "127.0.0.1:0"
+            assetsUpdaterEnabled = false
+            [motd]
+            displayServerInfo = false
+            customText = "[]"
+            sInfoUptime = ""
+            sInfoAuthClients = ""
+            sInfoDrawIndent = false
+            [limitations]
+            maxAvatarSize = 100
+            minAvatarSize = 1 # Test avatars are tiny
+            maxAvatars = 10
+            canUpload = true
+        "#).unwrap();
+        let avatars = std::env::temp_dir().join(format!("sculptor-tests-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(avatars.join("temp")).unwrap();
+        Self {
+            upload_limit: Arc::new(Semaphore::new(config.limitations.max_concurrent_uploads)),
+            download_limit: Arc::new(Semaphore::new(config.limitations.max_concurrent_downloads)),
+            user_downloads: Arc::new(DashMap::new()),
+            uptime: Instant::now(),
+            user_manager: Arc::new(UManager::new()),
+            session: Arc::new(DashMap::new()),
+            subscribes: Arc::new(DashMap::new()),
+            last_pings: Arc::new(DashMap::new()),
+            positions: Arc::new(DashMap::new()),
+            statuses: Arc::new(DashMap::new()),
+            subscribers: Arc::new(DashMap::new()),
+            config: Arc::new(RwLock::new(config)),
+            figura_versions: Arc::new(RwLock::new(None)),
+            motd_rotation: Arc::new(AtomicUsize::new(0)),
+            remote_motd: Arc::new(RwLock::new(None)),
+            avatars: AvatarStore::uncompressed(avatars),
+            report_cooldowns: Cooldown::default(),
+            export_cooldowns: Cooldown::default(),
+            upload_keys: IdempotencyKeys::default(),
+            chunked_uploads: ChunkedUploads::default(),
+            share_links: ShareLinks::default(),
+            origins: OriginLimiter::default(),
+            reconnects: SurgeLimiter::default(),
+            http: reqwest::Client::new(),
+            assets_update: Arc::new(Mutex::new(())),
+            cache_warm: Arc::new(std::sync::Mutex::new(None)),
+            // The events are dropped without the dispatcher
+            webhooks: Webhooks::new().0,
+            metrics: Arc::new(super::Metrics::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn expired_ban_lifted() {
+        use crate::auth::{BanInfo, Userinfo};
+        let mut state = AppState::for_tests();
+        let (webhooks, mut events) = Webhooks::new();
+        state.webhooks = webhooks;
+        let user = Userinfo { uuid: Uuid::from_u128(1), nickname: "Griefer".to_string(), ..Default::default() };
+        let expires = chrono::Utc::now() + chrono::Duration::milliseconds(100);
+        state.user_manager.ban(&user, BanInfo { expires: Some(expires), ..Default::default() });
+        state.user_manager.ban(&Userinfo { uuid: Uuid::from_u128(2), ..Default::default() }, BanInfo::default());
+
+        assert!(state.is_banned(&user.uuid));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!state.is_banned(&user.uuid));
+        let (event, data) = events.try_recv().unwrap();
+        assert_eq!((event, data["nickname"].as_str()), (WebhookEvent::UserUnbanned, Some("Griefer")));
+        assert!(state.user_manager.bans().iter().all(|(banned, _)| banned.uuid != user.uuid));
+        // Notified once, permanent bans stay
+        assert!(!state.is_banned(&user.uuid));
+        assert!(state.is_banned(&Uuid::from_u128(2)));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn feature_enabled_for_flagged() {
+        let state = AppState::for_tests();
+        let (tester, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        assert!(!state.feature_enabled("beta", &tester).await);
+
+        // Applied with the reloaded config
+        state.config.write().await.feature_flags = toml::from_str(&format!("beta = [\"{tester}\"]")).unwrap();
+        assert!(state.feature_enabled("beta", &tester).await);
+        assert!(!state.feature_enabled("beta", &other).await);
+        assert!(!state.feature_enabled("other", &tester).await);
+    }
+}
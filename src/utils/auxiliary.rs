@@ -1,4 +1,4 @@
-use std::{fs::File, io::Read, path::{Path, PathBuf}, sync::Arc};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
 
 use notify::{Event, Watcher};
 use tokio::{io::AsyncReadExt, sync::RwLock};
@@ -8,7 +8,7 @@ use ring::digest::{self, digest};
 use uuid::Uuid;
 use chrono::prelude::*;
 
-use crate::{auth::Userinfo, state::{BannedPlayer, Config}, UManager};
+use crate::{auth::{BanInfo, BanSource, Userinfo}, state::{BannedPlayer, Config}, AppState, UManager};
 
 pub fn rand() -> [u8; 50] {
     let mut rng = thread_rng();
@@ -20,19 +20,22 @@ pub fn rand() -> [u8; 50] {
     nums
 }
 
-pub async fn update_advanced_users(
-    path: PathBuf,
-    umanager: Arc<UManager>,
-    sessions: Arc<dashmap::DashMap<Uuid, tokio::sync::mpsc::Sender<crate::api::figura::SessionMessage>>>,
-    config: Arc<RwLock<Config>>,
-) {
+/// How often the config and banned-players.json are checked for changes
+pub const SYNC_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+fn poll_config(interval: std::time::Duration) -> notify::Config {
+    notify::Config::default().with_poll_interval(interval)
+}
+
+pub async fn update_advanced_users(path: PathBuf, state: AppState, interval: std::time::Duration) {
+    let (umanager, config) = (&state.user_manager, &state.config);
     let (tx, mut rx) = tokio::sync::mpsc::channel::<notify::Result<Event>>(1);
     tx.send(Ok(notify::Event::default())).await.unwrap();
     let mut watcher = notify::PollWatcher::new(
         move |res| {
             tx.blocking_send(res).unwrap();
         },
-        notify::Config::default(),
+        poll_config(interval),
     ).unwrap();
     watcher.watch(&path, notify::RecursiveMode::NonRecursive).unwrap();
 
@@ -43,6 +46,7 @@ pub async fn update_advanced_users(
 
         if new_config != *config || first_time {
             if !first_time { tracing::info!("Server configuration modification detected!") }
+            let announce = !first_time;
             first_time = false;
             *config = new_config;
             let users: Vec<(Uuid, Userinfo)> = config.advanced_users
@@ -59,11 +63,11 @@ pub async fn update_advanced_users(
                 )})
                 .collect();
         
+            drop(config);
             for (uuid, userinfo) in users {
                 umanager.insert_user(uuid, userinfo.clone());
                 if userinfo.banned {
-                    umanager.ban(&userinfo);
-                    if let Some(tx) = sessions.get(&uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+                    state.ban(&userinfo, BanInfo { source: BanSource::Config, ..Default::default() }, announce).await;
                 } else {
                     umanager.unban(&uuid);
                 }
@@ -72,11 +76,120 @@ pub async fn update_advanced_users(
     }
 }
 
-pub async fn update_bans_from_minecraft(
-    folder: PathBuf,
-    umanager: Arc<UManager>,
-    sessions: Arc<dashmap::DashMap<Uuid, tokio::sync::mpsc::Sender<crate::api::figura::SessionMessage>>>
-) {
+pub async fn purge_pending_auth(umanager: Arc<UManager>, config: Arc<RwLock<Config>>) {
+    loop {
+        // A reloaded config isn't validated, 0 would be a busy loop
+        let ttl = std::time::Duration::from_secs(config.read().await.pending_auth.ttl.max(1));
+        tokio::time::sleep(ttl).await;
+        umanager.purge_pending(ttl);
+    }
+}
+
+pub async fn purge_broadcasts(state: AppState) {
+    loop {
+        // A reloaded config isn't validated, 0 would be a busy loop
+        let interval = std::time::Duration::from_secs(state.config.read().await.websocket.broadcast_gc_interval.max(1));
+        tokio::time::sleep(interval).await;
+        let purged = state.purge_broadcasts();
+        if purged > 0 {
+            tracing::debug!("Purged {purged} unused broadcast channels");
+        }
+    }
+}
+
+/// Temporary files of the avatar store older than it are left by crashes
+const STALE_TEMP_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Removes expired avatars from the trash and the stale temporary files on start and every hour
+pub async fn purge_trash(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match state.avatars.purge_temp(STALE_TEMP_AGE).await {
+            Ok(0) => (),
+            Ok(purged) => tracing::info!("Removed {purged} temporary files left in the avatars directory"),
+            Err(e) => tracing::error!("Can't remove the temporary files due: {e:?}"),
+        }
+        let retention = std::time::Duration::from_secs(state.config.read().await.storage.trash_retention);
+        match state.avatars.purge_trash(retention).await {
+            Ok(0) => (),
+            Ok(purged) => tracing::debug!("Purged {purged} avatars from the trash"),
+            Err(e) => tracing::error!("Can't purge the trash due: {e:?}"),
+        }
+    }
+}
+
+/// Removes the resumable uploads abandoned for `limitations.chunked_upload_ttl` every minute
+pub async fn purge_uploads(state: AppState) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        match expire_uploads(&state).await {
+            Ok(0) => (),
+            Ok(purged) => tracing::debug!("Purged {purged} abandoned uploads"),
+            Err(e) => tracing::error!("Can't purge the abandoned uploads due: {e:?}"),
+        }
+    }
+}
+
+/// Removes the expired uploads and the chunks left from before a restart
+pub async fn expire_uploads(state: &AppState) -> std::io::Result<usize> {
+    let ttl = std::time::Duration::from_secs(state.config.read().await.limitations.chunked_upload_ttl);
+    state.chunked_uploads.expire(ttl);
+    let mut purged = 0;
+    for id in state.avatars.uploads().await? {
+        if !state.chunked_uploads.contains(&id) {
+            state.avatars.remove_upload(&id).await?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Applies the bans from the config and `banned-players.json` at once, disconnecting the newly banned users.
+/// Bans made through the API are kept. Returns how many bans were added and removed.
+pub async fn reload_bans(state: &AppState, config: Config) -> anyhow::Result<(usize, usize)> {
+    let mut wanted: HashMap<Uuid, (Userinfo, BanInfo)> = config.advanced_users.iter()
+        .filter(|(_, user)| user.banned)
+        .map(|(uuid, user)| {
+            let userinfo = Userinfo { uuid: *uuid, nickname: user.username.clone(), banned: true, ..Default::default() };
+            (*uuid, (userinfo, BanInfo { source: BanSource::Config, ..Default::default() }))
+        })
+        .collect();
+    let bans_file = config.mc_folder.join("banned-players.json");
+    if bans_file.exists() {
+        let players: Vec<BannedPlayer> = serde_json::from_str(&tokio::fs::read_to_string(bans_file).await?)
+            .inspect_err(|e| ban_list_invalid(state, e))?;
+        for player in players {
+            wanted.insert(player.uuid, (player.clone().into(), player.ban_info()));
+        }
+    }
+    *state.config.write().await = config;
+
+    let umanager = &state.user_manager;
+    let mut removed = 0;
+    for (user, info) in umanager.bans() {
+        if info.source != BanSource::Api && !wanted.contains_key(&user.uuid) {
+            umanager.unban(&user.uuid);
+            removed += 1;
+        }
+    }
+    let mut added = 0;
+    for (uuid, (user, info)) in wanted {
+        if umanager.is_banned(&uuid) {
+            continue;
+        }
+        state.ban(&user, info, true).await;
+        added += 1;
+    }
+    Ok((added, removed))
+}
+
+fn ban_list_invalid(state: &AppState, e: &serde_json::Error) {
+    state.webhooks.notify(super::WebhookEvent::BanListInvalid, serde_json::json!({ "error": e.to_string() }));
+}
+
+pub async fn update_bans_from_minecraft(folder: PathBuf, state: AppState, interval: std::time::Duration) {
+    let umanager = &state.user_manager;
     let path = folder.join("banned-players.json");
     let mut file = tokio::fs::File::open(path.clone()).await.expect("Access denied or banned-players.json doesn't exists!");
     let mut data = String::new();
@@ -92,8 +205,7 @@ pub async fn update_bans_from_minecraft(
     }
 
     for player in &old_bans {
-        umanager.ban(&player.clone().into());
-        if let Some(tx) = sessions.get(&player.uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+        state.ban(&player.clone().into(), player.ban_info(), false).await;
     }
 
     let (tx, mut rx) = tokio::sync::mpsc::channel::<notify::Result<Event>>(1);
@@ -101,7 +213,7 @@ pub async fn update_bans_from_minecraft(
         move |res| {
             tx.blocking_send(res).unwrap();
         },
-        notify::Config::default(),
+        poll_config(interval),
     ).unwrap();
     watcher.watch(&path, notify::RecursiveMode::NonRecursive).unwrap();
 
@@ -110,9 +222,13 @@ pub async fn update_bans_from_minecraft(
         let mut file = tokio::fs::File::open(path.clone()).await.expect("Access denied or file doesn't exists!");
         let mut data = String::new();
         file.read_to_string(&mut data).await.expect("cant read banned-players.json");
-        let new_bans: Vec<BannedPlayer> = if let Ok(res) = serde_json::from_str(&data) { res } else {
-            tracing::error!("Error occured while parsing a banned-players.json");
-            continue;
+        let new_bans: Vec<BannedPlayer> = match serde_json::from_str(&data) {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::error!("Error occured while parsing a banned-players.json");
+                ban_list_invalid(&state, &e);
+                continue;
+            },
         };
 
         if new_bans != old_bans {
@@ -128,8 +244,7 @@ pub async fn update_bans_from_minecraft(
             let mut ban_names = ban.iter().map(|user| user.name.clone()).collect::<Vec<String>>().join(", ");
             if !ban.is_empty() {
                 for player in ban {
-                    umanager.ban(&player.clone().into());
-                    if let Some(tx) = sessions.get(&player.uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+                    state.ban(&player.clone().into(), player.ban_info(), true).await;
                 }
             } else { ban_names = String::from("-")};
             tracing::info!("List of changes:\n    Banned: {ban_names}\n    Unbanned: {unban_names}");
@@ -145,23 +260,16 @@ pub fn format_uuid(uuid: &Uuid) -> String {
     uuid.as_hyphenated().to_string()
 }
 
-pub fn calculate_file_sha256(file_path: &str) -> Result<String, std::io::Error> {
-    // Read the file content
-    let mut file = File::open(file_path)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-
+pub fn calculate_sha256(content: &[u8]) -> String {
     // Convert the content to base64
-    let base64_content = BASE64_STANDARD.encode(&content);
+    let base64_content = BASE64_STANDARD.encode(content);
 
     // Calculate the SHA-256 hash of the base64 string
     let binding = digest(&digest::SHA256, base64_content.as_bytes());
     let hash = binding.as_ref();
 
     // Convert the hash to a hexadecimal string
-    let hex_hash = faster_hex::hex_string(hash);
-
-    Ok(hex_hash)
+    faster_hex::hex_string(hash)
 }
 
 pub fn get_log_file(folder: &str) -> String {
@@ -179,4 +287,54 @@ pub fn get_log_file(folder: &str) -> String {
 
 pub fn get_limit_as_bytes(limit: usize) -> usize {
     1024 + limit * 1024 // Adding additional 1 KB just for fun :)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{api::figura::SessionMessage, utils::{WebhookEvent, Webhooks}};
+
+    #[tokio::test]
+    async fn minecraft_bans_synced() {
+        let mut state = AppState::for_tests();
+        let (webhooks, mut events) = Webhooks::new();
+        state.webhooks = webhooks;
+        let folder = std::env::temp_dir().join(format!("sculptor-bans-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+        let bans_file = folder.join("banned-players.json");
+        let (old, new) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let player = |uuid: Uuid, name: &str| serde_json::json!({ "uuid": uuid, "name": name, "expires": "forever" });
+        tokio::fs::write(&bans_file, serde_json::json!([player(old, "Old")]).to_string()).await.unwrap();
+        let (tx, mut session) = tokio::sync::mpsc::channel(8);
+        state.session.insert(new, tx);
+        // The watcher only sees modification times in later seconds
+        let rewrite = |data: Vec<u8>, later: u64| {
+            std::fs::write(&bans_file, data).unwrap();
+            let modified = std::time::SystemTime::now() + Duration::from_secs(later);
+            std::fs::File::options().write(true).open(&bans_file).unwrap().set_modified(modified).unwrap();
+        };
+
+        tokio::spawn(update_bans_from_minecraft(folder.clone(), state.clone(), Duration::from_millis(20)));
+        while !state.user_manager.is_banned(&old) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // The bans loaded on startup aren't announced
+        assert!(events.try_recv().is_err());
+
+        rewrite(serde_json::json!([player(old, "Old"), player(new, "New")]).to_string().into_bytes(), 10);
+        let (event, data) = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event, WebhookEvent::UserBanned);
+        assert_eq!((data["nickname"].as_str(), data["source"].as_str()), (Some("New"), Some("minecraft")));
+        assert!(state.user_manager.is_banned(&new));
+        assert!(matches!(session.try_recv(), Ok(SessionMessage::Banned)));
+
+        rewrite(b"[{".to_vec(), 20);
+        let (event, _) = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event, WebhookEvent::BanListInvalid);
+        assert!(state.user_manager.is_banned(&new));
+
+        tokio::fs::remove_dir_all(folder).await.unwrap();
+    }
+}
@@ -0,0 +1,718 @@
+use std::{io::{self, Read as _, Write as _}, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use dashmap::DashMap;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use tokio::{fs, io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _, BufReader}};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::state::{CompressionAlgorithm, CompressionSettings};
+use super::{calculate_sha256, format_uuid};
+
+/// Every file written by the store starts with the magic, how the rest is stored and the length as it was uploaded.
+/// Files without the header were written before and are read as they are
+const STORE_MAGIC: &[u8] = b"SCZ";
+const HEADER_LEN: usize = STORE_MAGIC.len() + 1 + 8;
+/// Larger files aren't decompressed, unless `with_max_len` allows them
+const DEFAULT_MAX_LEN: u64 = 16 << 20;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Storage of the avatar files
+#[derive(Debug, Clone)]
+pub struct AvatarStore {
+    root: PathBuf,
+    compression: CompressionSettings,
+    /// Files with a larger length in the header are never read
+    max_len: u64,
+    /// Hashes of the files, valid while their size and modification time are the same
+    hashes: Arc<DashMap<PathBuf, CachedHash>>,
+}
+
+/// How the file is written after the header
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stored {
+    Raw = 0,
+    Gzip = 1,
+    Zstd = 2,
+}
+
+#[derive(Debug, Clone)]
+struct CachedHash {
+    modified: SystemTime,
+    len: u64,
+    hash: String,
+}
+
+impl AvatarStore {
+    /// Avatars are compressed unless the algorithm is none
+    pub fn new(root: PathBuf, compression: CompressionSettings) -> Self {
+        Self { root, compression, max_len: DEFAULT_MAX_LEN, hashes: Arc::new(DashMap::new()) }
+    }
+    /// Limits the length of the files as they were uploaded, so the compressed ones can't take more memory
+    pub fn with_max_len(self, max_len: u64) -> Self {
+        Self { max_len, ..self }
+    }
+    pub fn uncompressed(root: PathBuf) -> Self {
+        Self::new(root, CompressionSettings { algorithm: CompressionAlgorithm::None, ..Default::default() })
+    }
+    pub fn avatar_path(&self, uuid: &Uuid) -> PathBuf {
+        self.root.join(format!("{}.moon", format_uuid(uuid)))
+    }
+    pub fn cape_path(&self, uuid: &Uuid) -> PathBuf {
+        self.root.join(format!("{}.cape", format_uuid(uuid)))
+    }
+    /// Metadata sidecar of the avatar with the `id`
+    pub fn meta_path(&self, uuid: &Uuid, id: &str) -> PathBuf {
+        self.root.join(format_uuid(uuid)).join(format!("{id}.json"))
+    }
+    pub async fn put_meta(&self, uuid: &Uuid, id: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(self.root.join(format_uuid(uuid))).await?;
+        self.put(&self.meta_path(uuid, id), data).await
+    }
+    /// Preview sidecar of the avatar, with the hash of the avatar it was made from
+    pub fn preview_path(&self, uuid: &Uuid) -> PathBuf {
+        self.root.join(format_uuid(uuid)).join("avatar.preview")
+    }
+    /// Stores the preview of the avatar with the `hash`
+    pub async fn put_preview(&self, uuid: &Uuid, hash: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(self.root.join(format_uuid(uuid))).await?;
+        let preview = serde_json::json!({ "hash": hash, "preview": preview_signature(data) });
+        self.put(&self.preview_path(uuid), preview.to_string().as_bytes()).await
+    }
+    /// Preview of the avatar, None if it has none or it was made from another avatar
+    pub async fn preview(&self, uuid: &Uuid, hash: &str) -> Option<String> {
+        let data = self.get(&self.preview_path(uuid)).await.ok()?;
+        let stored: serde_json::Value = serde_json::from_slice(&data).ok()?;
+        (stored["hash"] == hash).then(|| stored["preview"].as_str().map(str::to_string)).flatten()
+    }
+    pub fn temp_path(&self, uuid: &Uuid) -> PathBuf {
+        self.root.join("temp").join(format!("{}.moon", format_uuid(uuid)))
+    }
+    /// Writes the avatar, compressing it if enabled and it's not compressed yet.
+    /// The file is written next to the target and renamed into place, so readers never see a partial file.
+    pub async fn put(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let compressed_already = data.starts_with(GZIP_MAGIC) || data.starts_with(ZSTD_MAGIC);
+        let compressed = match self.compression.algorithm {
+            _ if compressed_already => None,
+            CompressionAlgorithm::None => None,
+            CompressionAlgorithm::Gzip => Some((Stored::Gzip, compress(data, CompressionAlgorithm::Gzip, self.compression.level)?)),
+            CompressionAlgorithm::Zstd => Some((Stored::Zstd, compress(data, CompressionAlgorithm::Zstd, self.compression.level)?)),
+        };
+        if let Some((_, compressed)) = &compressed {
+            debug!("Compressed {}: {} -> {} bytes", path.display(), data.len(), compressed.len());
+        }
+        let (kind, body) = compressed.filter(|(_, compressed)| compressed.len() < data.len()).unwrap_or((Stored::Raw, data.to_vec()));
+        let mut stored = Vec::with_capacity(HEADER_LEN + body.len());
+        stored.extend_from_slice(STORE_MAGIC);
+        stored.push(kind as u8);
+        stored.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        stored.extend_from_slice(&body);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".tmp-{:016x}", rand::random::<u64>()));
+        let temp = PathBuf::from(temp);
+        self.hashes.remove(path);
+        let written = match fs::write(&temp, stored).await {
+            Ok(()) => fs::rename(&temp, path).await,
+            Err(e) => Err(e),
+        };
+        if written.is_err() {
+            let _ = fs::remove_file(&temp).await;
+        }
+        written
+    }
+    /// Reads the avatar as it was uploaded
+    pub async fn get(&self, path: &Path) -> io::Result<Vec<u8>> {
+        decode(fs::read(path).await?, self.max_len)
+    }
+    /// Opens the avatar as it was uploaded and returns its length from the header.
+    /// Files compressed by the store are decompressed while they are read
+    pub async fn open(&self, path: &Path) -> io::Result<(Box<dyn AsyncRead + Send + Unpin>, u64)> {
+        let mut file = fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let mut data = Vec::new();
+        (&mut file).take(HEADER_LEN as u64).read_to_end(&mut data).await?;
+        match parse_header(&data, len) {
+            Some((_, len)) if len > self.max_len => Err(too_large(len, self.max_len)),
+            Some((Stored::Raw, len)) => Ok((Box::new(file.take(len)), len)),
+            Some((Stored::Gzip, len)) => Ok((Box::new(GzipDecoder::new(BufReader::new(file)).take(len)), len)),
+            Some((Stored::Zstd, len)) => Ok((Box::new(ZstdDecoder::new(BufReader::new(file)).take(len)), len)),
+            None => {
+                file.rewind().await?;
+                Ok((Box::new(file), len))
+            },
+        }
+    }
+    /// Chunks of the resumable upload received so far, as they were sent
+    fn upload_path(&self, id: &str) -> PathBuf {
+        self.root.join("uploads").join(format!("{id}.part"))
+    }
+    pub async fn append_upload(&self, id: &str, chunk: &[u8]) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt as _;
+        fs::create_dir_all(self.root.join("uploads")).await?;
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(self.upload_path(id)).await?;
+        file.write_all(chunk).await?;
+        file.flush().await
+    }
+    pub async fn read_upload(&self, id: &str) -> io::Result<Vec<u8>> {
+        match fs::read(self.upload_path(id)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            read => read,
+        }
+    }
+    pub async fn remove_upload(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.upload_path(id)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            removed => removed,
+        }
+    }
+    /// Ids of the uploads with chunks on the disk
+    pub async fn uploads(&self) -> io::Result<Vec<String>> {
+        let mut entries = match fs::read_dir(self.root.join("uploads")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(id) = entry.file_name().to_string_lossy().strip_suffix(".part") {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+    /// Copies the stored file into `dir` under the same name, replacing the previous copy at once
+    pub async fn backup(&self, path: &Path, dir: &Path) -> io::Result<PathBuf> {
+        let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        fs::create_dir_all(dir).await?;
+        let target = dir.join(name);
+        let mut temp = target.as_os_str().to_owned();
+        temp.push(format!(".tmp-{:016x}", rand::random::<u64>()));
+        let temp = PathBuf::from(temp);
+        let copied = match fs::copy(path, &temp).await {
+            Ok(_) => fs::rename(&temp, &target).await,
+            Err(e) => Err(e),
+        };
+        if copied.is_err() {
+            let _ = fs::remove_file(&temp).await;
+        }
+        copied.map(|()| target)
+    }
+    /// Users with a temp avatar on the disk
+    pub async fn temp_avatars(&self) -> io::Result<Vec<Uuid>> {
+        let mut uuids = Vec::new();
+        let mut entries = match fs::read_dir(self.root.join("temp")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(uuids),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "moon") {
+                continue;
+            }
+            if let Some(uuid) = path.file_stem().and_then(|stem| Uuid::parse_str(&stem.to_string_lossy()).ok()) {
+                uuids.push(uuid);
+            }
+        }
+        uuids.sort();
+        Ok(uuids)
+    }
+    /// Finds empty and broken avatars, moves them into `corrupt/` if `quarantine` is set.
+    /// Files that can't be checked or moved are logged and skipped
+    pub async fn scan(&self, quarantine: bool) -> io::Result<Vec<PathBuf>> {
+        let mut corrupt = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "moon") {
+                continue;
+            }
+            match entry.file_type().await {
+                Ok(kind) if kind.is_file() => (),
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::warn!("Can't check {} due: {e:?}", path.display());
+                    continue
+                },
+            }
+            let valid = match self.get(&path).await {
+                Ok(data) => is_valid_avatar(&data),
+                Err(_) => false,
+            };
+            if valid {
+                continue;
+            }
+            tracing::warn!("Avatar {} is corrupt", path.display());
+            if quarantine {
+                let dir = self.root.join("corrupt");
+                let moved = match fs::create_dir_all(&dir).await {
+                    Ok(()) => fs::rename(&path, dir.join(entry.file_name())).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = moved {
+                    tracing::warn!("Can't quarantine {} due: {e:?}", path.display());
+                }
+            }
+            corrupt.push(path);
+        }
+        Ok(corrupt)
+    }
+    /// Removes the avatar, or moves it into `trash/` if `soft` is set
+    pub async fn delete(&self, uuid: &Uuid, soft: bool) -> io::Result<()> {
+        let path = self.avatar_path(uuid);
+        self.hashes.remove(&path);
+        if !soft {
+            return fs::remove_file(path).await;
+        }
+        let trash = self.root.join("trash");
+        fs::create_dir_all(&trash).await?;
+        let deleted = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        fs::rename(path, trash.join(format!("{}.{deleted}.moon", format_uuid(uuid)))).await
+    }
+    /// Removes every file of the user: the avatar, temp avatar, cape, sidecars and trash.
+    /// Returns the names of the removed files
+    pub async fn purge_user(&self, uuid: &Uuid) -> io::Result<Vec<String>> {
+        let mut files = vec![self.avatar_path(uuid), self.temp_path(uuid), self.cape_path(uuid)];
+        files.extend(self.trashed().await?.into_iter().filter(|(owner, _, _)| owner == &format_uuid(uuid)).map(|(_, _, path)| path));
+        let mut removed = Vec::new();
+        for path in files {
+            self.hashes.remove(&path);
+            match fs::remove_file(&path).await {
+                Ok(()) => removed.push(path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        let sidecars = self.root.join(format_uuid(uuid));
+        self.hashes.retain(|path, _| !path.starts_with(&sidecars));
+        if let Ok(mut entries) = fs::read_dir(&sidecars).await {
+            while let Some(entry) = entries.next_entry().await? {
+                removed.push(entry.path());
+            }
+            fs::remove_dir_all(&sidecars).await?;
+        }
+        Ok(removed.iter().filter_map(|path| path.strip_prefix(&self.root).ok()).map(|path| path.display().to_string()).collect())
+    }
+    /// Brings back the latest deleted avatar of the user if it was deleted within `retention`.
+    /// The current avatar goes into the trash. Returns false if there is nothing to restore.
+    pub async fn restore(&self, uuid: &Uuid, retention: Duration) -> io::Result<bool> {
+        let latest = self.trashed().await?.into_iter()
+            .filter(|(owner, deleted, _)| owner == &format_uuid(uuid) && deleted.elapsed().unwrap_or_default() < retention)
+            .max_by_key(|(_, deleted, _)| *deleted);
+        let Some((_, _, path)) = latest else { return Ok(false) };
+        if fs::try_exists(self.avatar_path(uuid)).await? {
+            self.delete(uuid, true).await?;
+        }
+        fs::rename(path, self.avatar_path(uuid)).await?;
+        Ok(true)
+    }
+    /// Removes the avatars deleted more than `retention` ago
+    pub async fn purge_trash(&self, retention: Duration) -> io::Result<usize> {
+        let mut purged = 0;
+        for (_, deleted, path) in self.trashed().await? {
+            if deleted.elapsed().unwrap_or_default() >= retention {
+                fs::remove_file(path).await?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+    /// Removes the temporary files of the writes interrupted more than `age` ago, in the root and the sidecar directories
+    pub async fn purge_temp(&self, age: Duration) -> io::Result<usize> {
+        let mut purged = 0;
+        let mut dirs = vec![(self.root.clone(), true)];
+        while let Some((dir, nested)) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                // Renamed into place meanwhile
+                let Ok(metadata) = entry.metadata().await else { continue };
+                if metadata.is_dir() {
+                    if nested {
+                        dirs.push((entry.path(), false));
+                    }
+                    continue;
+                }
+                let stale = metadata.modified()?.elapsed().unwrap_or_default() >= age;
+                if stale && is_temp(&entry.file_name().to_string_lossy()) {
+                    match fs::remove_file(entry.path()).await {
+                        Ok(()) => purged += 1,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(purged)
+    }
+    /// Avatars in the trash with their owner and the deletion time
+    async fn trashed(&self) -> io::Result<Vec<(String, SystemTime, PathBuf)>> {
+        let mut entries = match fs::read_dir(self.root.join("trash")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut trashed = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let parsed = name.strip_suffix(".moon")
+                .and_then(|name| name.split_once('.'))
+                .and_then(|(owner, deleted)| Some((owner.to_string(), deleted.parse::<u64>().ok()?)));
+            if let Some((owner, deleted)) = parsed {
+                trashed.push((owner, UNIX_EPOCH + Duration::from_millis(deleted), entry.path()));
+            }
+        }
+        Ok(trashed)
+    }
+    /// Hash of the avatar as it was uploaded, the file is read only if it changed since the last time
+    pub async fn hash(&self, path: &Path) -> io::Result<String> {
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.hashes.remove(path);
+                return Err(e);
+            }
+        };
+        let (modified, len) = (metadata.modified()?, metadata.len());
+        if let Some(cached) = self.hashes.get(path).filter(|cached| cached.modified == modified && cached.len == len) {
+            return Ok(cached.hash.clone());
+        }
+        let hash = calculate_sha256(&self.get(path).await?);
+        self.hashes.insert(path.to_path_buf(), CachedHash { modified, len, hash: hash.clone() });
+        Ok(hash)
+    }
+    /// Hashes the avatars and capes into the cache, waiting `delay` after each file.
+    /// Returns the number of files hashed before it finished or was canceled
+    pub async fn warm(&self, delay: Duration, cancel: &CancellationToken) -> io::Result<usize> {
+        let mut warmed = 0;
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "moon" && ext != "cape") || !entry.file_type().await?.is_file() {
+                continue;
+            }
+            if let Err(e) = self.hash(&path).await {
+                debug!("Can't hash {} due: {e:?}", path.display());
+                continue;
+            }
+            warmed += 1;
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+        Ok(warmed)
+    }
+}
+
+/// Parts of the avatar hashed into the preview
+const PREVIEW_SEGMENTS: usize = 16;
+
+/// 64 bytes made of the first 4 bytes of the SHA-256 of each sixteenth of the avatar,
+/// so clients can tell which parts of it changed
+pub fn preview_signature(data: &[u8]) -> String {
+    let segment = data.len().div_ceil(PREVIEW_SEGMENTS).max(1);
+    let mut signature = Vec::with_capacity(PREVIEW_SEGMENTS * 4);
+    for i in 0..PREVIEW_SEGMENTS {
+        let part = data.get(i * segment..((i + 1) * segment).min(data.len())).unwrap_or_default();
+        signature.extend_from_slice(&ring::digest::digest(&ring::digest::SHA256, part).as_ref()[..4]);
+    }
+    faster_hex::hex_string(&signature)
+}
+
+/// Checks if the data starts like a web page or a script
+pub fn is_denied_content(data: &[u8], patterns: &[String]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data); // UTF-8 BOM
+    let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
+    let data = &data[start..];
+    patterns.iter().any(|pattern| {
+        !pattern.is_empty() && data.len() >= pattern.len() && data[..pattern.len()].eq_ignore_ascii_case(pattern.as_bytes())
+    })
+}
+
+/// Avatars are gzipped NBT, or plain NBT starting with a compound tag
+fn is_valid_avatar(data: &[u8]) -> bool {
+    const NBT_COMPOUND: u8 = 0x0a;
+    if data.starts_with(GZIP_MAGIC) {
+        let mut decoder = GzDecoder::new(data);
+        let mut first = [0u8; 1];
+        decoder.read_exact(&mut first).is_ok() && first[0] == NBT_COMPOUND && io::copy(&mut decoder, &mut io::sink()).is_ok()
+    } else {
+        data.first() == Some(&NBT_COMPOUND)
+    }
+}
+
+fn compress(data: &[u8], algorithm: CompressionAlgorithm, level: i32) -> io::Result<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::None => Ok(data.to_vec()),
+        CompressionAlgorithm::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level as u32));
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        CompressionAlgorithm::Zstd => {
+            let mut encoder = zstd::Encoder::new(Vec::new(), level)?;
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+    }
+}
+
+/// How the file of `file_len` bytes starting with `header` is stored and its length as it was uploaded.
+/// None if it has no header, the raw files must have exactly that length after it
+fn parse_header(header: &[u8], file_len: u64) -> Option<(Stored, u64)> {
+    let header = header.get(..HEADER_LEN)?.strip_prefix(STORE_MAGIC)?;
+    let len = u64::from_le_bytes(header[1..].try_into().ok()?);
+    match header[0] {
+        0 if file_len == HEADER_LEN as u64 + len => Some((Stored::Raw, len)),
+        1 => Some((Stored::Gzip, len)),
+        2 => Some((Stored::Zstd, len)),
+        _ => None,
+    }
+}
+
+fn too_large(len: u64, max_len: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("stored file of {len} bytes is larger than {max_len}"))
+}
+
+/// The file as it was uploaded, compressed ones are read regardless of the current settings
+/// and never decompressed past their length or `max_len`
+fn decode(mut data: Vec<u8>, max_len: u64) -> io::Result<Vec<u8>> {
+    let Some((stored, len)) = parse_header(&data, data.len() as u64) else { return Ok(data) };
+    if len > max_len {
+        return Err(too_large(len, max_len));
+    }
+    let body = &data[HEADER_LEN..];
+    let mut decoded = Vec::with_capacity(len as usize);
+    match stored {
+        Stored::Raw => {
+            data.drain(..HEADER_LEN);
+            return Ok(data);
+        },
+        Stored::Gzip => GzDecoder::new(body).take(len + 1).read_to_end(&mut decoded)?,
+        Stored::Zstd => zstd::Decoder::new(body)?.take(len + 1).read_to_end(&mut decoded)?,
+    };
+    if decoded.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stored file doesn't match its length"));
+    }
+    Ok(decoded)
+}
+
+/// Name of the file written before the rename by `put` and `backup`
+fn is_temp(name: &str) -> bool {
+    name.rsplit_once(".tmp-").is_some_and(|(_, id)| id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str, algorithm: CompressionAlgorithm) -> AvatarStore {
+        let root = std::env::temp_dir().join(format!("sculptor-{name}-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        AvatarStore::new(root, CompressionSettings { algorithm, ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn compression_round_trip() {
+        let data = vec![7u8; 4096];
+        for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd, CompressionAlgorithm::None] {
+            let store = temp_store("round-trip", algorithm);
+            let path = store.avatar_path(&Uuid::from_u128(1));
+            store.put(&path, &data).await.unwrap();
+            let stored = fs::read(&path).await.unwrap();
+            assert_eq!(stored.len() < data.len(), algorithm != CompressionAlgorithm::None);
+            assert_eq!(store.get(&path).await.unwrap(), data);
+            // Streamed with the length from the header
+            let (mut reader, len) = store.open(&path).await.unwrap();
+            assert_eq!(len, data.len() as u64);
+            let mut opened = Vec::new();
+            reader.read_to_end(&mut opened).await.unwrap();
+            assert_eq!(opened, data);
+            fs::remove_dir_all(&store.root).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_files_unambiguous() {
+        for algorithm in [CompressionAlgorithm::None, CompressionAlgorithm::Gzip] {
+            let store = temp_store("unambiguous", algorithm);
+            let path = store.avatar_path(&Uuid::from_u128(1));
+            // Like a file written by the store, but uploaded, and too short to be compressed
+            let tricky = [STORE_MAGIC, &[1], &u64::MAX.to_le_bytes(), b"x"].concat();
+            store.put(&path, &tricky).await.unwrap();
+            assert_eq!(store.get(&path).await.unwrap(), tricky);
+            let (mut reader, len) = store.open(&path).await.unwrap();
+            let mut opened = Vec::new();
+            reader.read_to_end(&mut opened).await.unwrap();
+            assert_eq!((opened, len), (tricky.clone(), tricky.len() as u64));
+            fs::remove_dir_all(&store.root).await.unwrap();
+        }
+
+        // Compressed files aren't decompressed past the limit
+        let store = temp_store("bomb", CompressionAlgorithm::Zstd).with_max_len(1024);
+        let path = store.avatar_path(&Uuid::from_u128(1));
+        store.put(&path, &vec![0u8; 4096]).await.unwrap();
+        assert_eq!(store.get(&path).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Nor past the length in the header
+        let bomb = [STORE_MAGIC, &[2], &16u64.to_le_bytes(), &compress(&vec![0u8; 1 << 20], CompressionAlgorithm::Zstd, 3).unwrap()].concat();
+        fs::write(&path, bomb).await.unwrap();
+        assert_eq!(store.get(&path).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&store.root).await.unwrap();
+    }
+
+    #[test]
+    fn web_content_denied() {
+        let patterns = crate::state::Storage::default().deny_patterns;
+        assert!(is_denied_content(b"<!DOCTYPE html><html></html>", &patterns));
+        assert!(is_denied_content(b"\xEF\xBB\xBF \n\t<Script>alert(1)</script>", &patterns));
+        assert!(!is_denied_content(&compress(b"<html>", CompressionAlgorithm::Gzip, 6).unwrap(), &patterns));
+        assert!(!is_denied_content(&[0x1f, 0x8b, 0x08, 0x00], &patterns));
+        assert!(!is_denied_content(b"", &patterns));
+    }
+
+    #[tokio::test]
+    async fn store_is_transparent() {
+        let root = std::env::temp_dir().join(format!("sculptor-store-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).await.unwrap();
+        let store = AvatarStore::new(root.clone(), CompressionSettings::default());
+        let plain = vec![1u8; 2048];
+        let gzipped = compress(&plain, CompressionAlgorithm::Gzip, 6).unwrap();
+
+        for (uuid, data) in [(Uuid::from_u128(1), &plain), (Uuid::from_u128(2), &gzipped)] {
+            let path = store.avatar_path(&uuid);
+            store.put(&path, data).await.unwrap();
+            assert_eq!(&store.get(&path).await.unwrap(), data);
+            assert_eq!(store.hash(&path).await.unwrap(), calculate_sha256(data));
+            let (mut reader, len) = store.open(&path).await.unwrap();
+            let mut opened = Vec::new();
+            reader.read_to_end(&mut opened).await.unwrap();
+            assert_eq!((&opened, len), (data, data.len() as u64));
+        }
+        // Already gzipped avatars are stored as is after the header
+        let stored = fs::read(store.avatar_path(&Uuid::from_u128(2))).await.unwrap();
+        assert_eq!(stored[HEADER_LEN..], gzipped);
+        assert_eq!(parse_header(&stored, stored.len() as u64), Some((Stored::Raw, gzipped.len() as u64)));
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_never_torn() {
+        let root = std::env::temp_dir().join(format!("sculptor-atomic-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).await.unwrap();
+        let store = AvatarStore::uncompressed(root.clone());
+        let path = store.avatar_path(&Uuid::from_u128(1));
+        let versions = [vec![1u8; 1 << 20], vec![2u8; 1 << 19]];
+        store.put(&path, &versions[0]).await.unwrap();
+
+        let writer = {
+            let (store, path, versions) = (store.clone(), path.clone(), versions.clone());
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    store.put(&path, &versions[i % 2]).await.unwrap();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let data = store.get(&path).await.unwrap();
+            assert!(versions.contains(&data), "torn read of {} bytes", data.len());
+        }
+        writer.await.unwrap();
+        // No temporary files are left
+        let mut entries = fs::read_dir(&root).await.unwrap();
+        let mut count = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trash_restored_and_purged() {
+        let root = std::env::temp_dir().join(format!("sculptor-trash-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).await.unwrap();
+        let store = AvatarStore::uncompressed(root.clone());
+        let uuid = Uuid::from_u128(1);
+        let retention = Duration::from_secs(60);
+
+        store.put(&store.avatar_path(&uuid), b"first").await.unwrap();
+        store.delete(&uuid, true).await.unwrap();
+        assert!(!store.avatar_path(&uuid).exists());
+        assert!(!store.restore(&Uuid::from_u128(2), retention).await.unwrap());
+        assert!(store.restore(&uuid, retention).await.unwrap());
+        assert_eq!(store.get(&store.avatar_path(&uuid)).await.unwrap(), b"first");
+        assert!(!store.restore(&uuid, retention).await.unwrap());
+
+        store.delete(&uuid, true).await.unwrap();
+        assert_eq!(store.purge_trash(retention).await.unwrap(), 0);
+        assert_eq!(store.purge_trash(Duration::ZERO).await.unwrap(), 1);
+        assert!(!store.restore(&uuid, retention).await.unwrap());
+
+        // Hard deletes don't go into the trash
+        store.put(&store.avatar_path(&uuid), b"second").await.unwrap();
+        store.delete(&uuid, false).await.unwrap();
+        assert!(!store.restore(&uuid, retention).await.unwrap());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_temp_files_purged() {
+        let root = std::env::temp_dir().join(format!("sculptor-temp-{}", rand::random::<u64>()));
+        let store = AvatarStore::uncompressed(root.clone());
+        let uuid = Uuid::from_u128(1);
+        store.put_meta(&uuid, "avatar", b"{}").await.unwrap();
+        store.put(&store.avatar_path(&uuid), b"avatar").await.unwrap();
+        let left = [root.join("avatar.moon.tmp-0123456789abcdef"), store.meta_path(&uuid, "avatar").with_extension("json.tmp-fedcba9876543210")];
+        for path in &left {
+            fs::write(path, b"partial").await.unwrap();
+        }
+        fs::write(root.join("notes.tmp-draft"), b"kept").await.unwrap();
+
+        // Might be still written
+        assert_eq!(store.purge_temp(Duration::from_secs(60)).await.unwrap(), 0);
+        assert_eq!(store.purge_temp(Duration::ZERO).await.unwrap(), 2);
+        assert!(left.iter().all(|path| !path.exists()));
+        assert!(root.join("notes.tmp-draft").exists() && store.avatar_path(&uuid).exists() && store.meta_path(&uuid, "avatar").exists());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_avatars_quarantined() {
+        let root = std::env::temp_dir().join(format!("sculptor-scan-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).await.unwrap();
+        let store = AvatarStore::uncompressed(root.clone());
+        let valid = compress(&[0x0a, 0, 0, 0], CompressionAlgorithm::Gzip, 6).unwrap();
+        let truncated = valid[..valid.len() - 4].to_vec();
+        let avatars = [(1, valid), (2, Vec::new()), (3, truncated), (4, b"<html>".to_vec())];
+        for (uuid, data) in &avatars {
+            store.put(&store.avatar_path(&Uuid::from_u128(*uuid)), data).await.unwrap();
+        }
+
+        let mut corrupt = store.scan(false).await.unwrap();
+        corrupt.sort();
+        let expected: Vec<_> = (2..=4).map(|uuid| store.avatar_path(&Uuid::from_u128(uuid))).collect();
+        assert_eq!(corrupt, expected);
+
+        // A directory in the way of one file doesn't stop the others
+        let blocked = root.join("corrupt").join(format!("{}.moon", format_uuid(&Uuid::from_u128(3))));
+        fs::create_dir_all(blocked.join("taken")).await.unwrap();
+        assert_eq!(store.scan(true).await.unwrap().len(), 3);
+        assert!(store.avatar_path(&Uuid::from_u128(1)).exists());
+        assert!(!store.avatar_path(&Uuid::from_u128(2)).exists() && !store.avatar_path(&Uuid::from_u128(4)).exists());
+        assert!(root.join("corrupt").join(format!("{}.moon", format_uuid(&Uuid::from_u128(2)))).exists());
+        assert_eq!(store.scan(false).await.unwrap(), [store.avatar_path(&Uuid::from_u128(3))]);
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -1,21 +1,22 @@
-use std::path::{self, PathBuf};
+use std::{collections::HashSet, io::{Read, Seek}, path::{self, Component, Path, PathBuf}, time::Duration};
 
 use anyhow::bail;
+use futures_util::{stream, StreamExt as _};
 use reqwest::Client;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use tokio::{fs::{self, File}, io::{AsyncReadExt as _, AsyncWriteExt as _}};
 
-use crate::{ASSETS_VAR, FIGURA_ASSETS_ZIP_URL, FIGURA_RELEASES_URL, TIMEOUT, USER_AGENT};
+use crate::{state::{AssetsDownload, HttpClient}, ASSETS_VAR, FIGURA_ASSETS_RAW_URL, FIGURA_ASSETS_TREE_URL, FIGURA_ASSETS_ZIP_URL, FIGURA_RELEASES_URL};
+use super::calculate_sha256;
 
 #[derive(Deserialize, Debug)]
 struct Tag {
     name: String
 }
 
-pub async fn get_latest_version(repo: &str) -> anyhow::Result<Version> {
+pub async fn get_latest_version(client: &Client, repo: &str) -> anyhow::Result<Version> {
     let url = format!("https://api.github.com/repos/{repo}/tags");
-    let client = Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
     let response = client.get(&url).send().await?;
 
     if response.status().is_success() {
@@ -47,8 +48,7 @@ struct Release {
     prerelease: bool
 }
 
-pub async fn get_figura_versions() -> anyhow::Result<FiguraVersions> {
-    let client = Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
+pub async fn get_figura_versions(client: &Client) -> anyhow::Result<FiguraVersions> {
     let response = client.get(FIGURA_RELEASES_URL).send().await?;
 
     let mut release_ver = Version::new(0, 0, 0);
@@ -96,15 +96,20 @@ pub fn get_path_to_assets_hash() -> PathBuf {
     path::PathBuf::from(&*ASSETS_VAR).join("..").join("assets_last_commit")
 }
 
-pub async fn get_commit_sha(url: &str) -> anyhow::Result<String> {
-    let client = Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
+/// Hashes of the downloaded asset files, one `<hash> <path>` per line
+pub fn get_path_to_assets_manifest() -> PathBuf {
+    path::PathBuf::from(&*ASSETS_VAR).join("..").join("assets_manifest")
+}
+
+pub async fn get_commit_sha(client: &Client, url: &str) -> anyhow::Result<String> {
     let response: reqwest::Response = client.get(url).send().await?;
     let commit: Commit = response.json().await?;
     Ok(commit.sha)
 }
 
-pub async fn is_assets_outdated(last_sha: &str) -> anyhow::Result<bool> {
-    let path = get_path_to_assets_hash();
+/// Checks the commit written in `hash_file`
+async fn is_assets_outdated(hash_file: &Path, last_sha: &str) -> anyhow::Result<bool> {
+    let path = hash_file.to_path_buf();
 
     match File::open(path.clone()).await {
         Ok(mut file) => {
@@ -129,30 +134,124 @@ pub async fn is_assets_outdated(last_sha: &str) -> anyhow::Result<bool> {
     }
 }
 
-pub fn download_assets() -> anyhow::Result<()> {
-    use std::{fs::{File, self}, io::Write as _};
+/// Downloads the whole archive and extracts it into `assets_folder`, returns the files with their hashes
+pub fn download_assets(client: &HttpClient, assets_folder: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let bytes = download_assets_zip(client)?;
+    extract_assets(std::io::Cursor::new(bytes), assets_folder, None)
+}
 
-    let assets_folder = ASSETS_VAR.clone();
+/// Waited before each retry of a file, multiplied by the attempt
+const ASSET_RETRY_DELAY: Duration = Duration::from_millis(250);
 
-    // Path to save the downloaded ZIP file
-    let mut zip_file_path = path::PathBuf::from(&*ASSETS_VAR);
-    zip_file_path.pop();
-    let zip_file_path = zip_file_path.join("assets.zip");
+#[derive(Deserialize, Debug)]
+struct Tree {
+    tree: Vec<TreeEntry>,
+    truncated: bool,
+}
 
-    // Download the ZIP file
-    let client = reqwest::blocking::Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
-    let response: reqwest::blocking::Response = client.get(FIGURA_ASSETS_ZIP_URL).send()?;
-    let bytes = response.bytes()?;
+#[derive(Deserialize, Debug)]
+struct TreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
 
-    // Save the downloaded ZIP file to disk
-    let mut file = File::create(&zip_file_path)?;
-    file.write_all(&bytes)?;
-    file.flush()?;
+/// Downloads the asset files of the commit one by one into `assets_folder`, `settings.concurrency` at a time
+async fn download_assets_concurrently(client: &Client, sha: &str, settings: &AssetsDownload, assets_folder: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let tree: Tree = client.get(format!("{FIGURA_ASSETS_TREE_URL}/{sha}?recursive=1")).send().await?.error_for_status()?.json().await?;
+    if tree.truncated {
+        bail!("The assets tree is too large to be listed");
+    }
+    let files = tree.tree.into_iter()
+        .filter(|entry| entry.kind == "blob")
+        .map(|entry| {
+            let url = format!("{FIGURA_ASSETS_RAW_URL}/{sha}/{}", entry.path);
+            (PathBuf::from(entry.path), url)
+        })
+        .collect();
+    download_files(client, files, assets_folder, settings).await
+}
 
-    // Open the downloaded ZIP file
-    let file = File::open(&zip_file_path)?;
+/// Downloads the files into `assets_folder` and returns them with their hashes.
+/// A failed file is retried without stopping the others, the result is an error if any of them failed every attempt
+async fn download_files(client: &Client, files: Vec<(PathBuf, String)>, assets_folder: &Path, settings: &AssetsDownload) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let total = files.len();
+    let results: Vec<_> = stream::iter(files)
+        .map(|(path, url)| async move {
+            let result = download_file(client, &path, &url, assets_folder, settings.retries).await;
+            (path, result)
+        })
+        .buffer_unordered(settings.concurrency.max(1))
+        .collect()
+        .await;
+    let mut downloaded = Vec::with_capacity(total);
+    let mut failed = 0;
+    for (path, result) in results {
+        match result {
+            Ok(hash) => downloaded.push((path, hash)),
+            Err(e) => {
+                tracing::warn!("Can't download the asset file {} due: {:?}", path.display(), e);
+                failed += 1;
+            },
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {total} asset files can't be downloaded");
+    }
+    tracing::debug!("Downloaded {total} asset files");
+    Ok(downloaded)
+}
+
+async fn download_file(client: &Client, path: &Path, url: &str, assets_folder: &Path, retries: u32) -> anyhow::Result<String> {
+    if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        bail!("unexpected path");
+    }
+    let mut attempt = 0;
+    let data = loop {
+        let response = client.get(url).send().await.and_then(|response| response.error_for_status());
+        match response {
+            Ok(response) => match response.bytes().await {
+                Ok(data) => break data,
+                Err(e) if attempt >= retries => return Err(e.into()),
+                Err(_) => (),
+            },
+            Err(e) if attempt >= retries => return Err(e.without_url().into()),
+            Err(_) => (),
+        }
+        attempt += 1;
+        tokio::time::sleep(ASSET_RETRY_DELAY * attempt).await;
+    };
+    let outpath = assets_folder.join(path);
+    if let Some(parent) = outpath.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&outpath, &data).await?;
+    Ok(calculate_sha256(&data))
+}
 
-    let mut archive = zip::ZipArchive::new(file)?;
+/// Downloads and extracts only the given asset files, keeping the others
+pub fn repair_assets(client: &HttpClient, broken: &HashSet<PathBuf>) -> anyhow::Result<()> {
+    let bytes = download_assets_zip(client)?;
+    let repaired = extract_assets(std::io::Cursor::new(bytes), Path::new(&*ASSETS_VAR), Some(broken))?;
+    if repaired.len() != broken.len() {
+        bail!("{} of {} broken files are not in the archive", broken.len() - repaired.len(), broken.len());
+    }
+    Ok(())
+}
+
+fn download_assets_zip(client: &HttpClient) -> anyhow::Result<axum::body::Bytes> {
+    let client = client.build_blocking()?;
+    let response: reqwest::blocking::Response = client.get(FIGURA_ASSETS_ZIP_URL).send()?;
+    Ok(response.bytes()?)
+}
+
+/// Extracts the archive into `assets_folder`, or only the files in `only`.
+/// Returns the extracted files with their hashes.
+fn extract_assets<R: Read + Seek>(archive: R, assets_folder: &Path, only: Option<&HashSet<PathBuf>>) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    use std::fs;
+
+    let mut archive = zip::ZipArchive::new(archive)?;
+    let mut extracted = Vec::new();
     let mut extraction_info = String::from("Extraction complete! More info:\n");
     let mut first_folder = String::new();
 
@@ -171,10 +270,14 @@ pub fn download_assets() -> anyhow::Result<()> {
                 anyhow::bail!("0 index is not a folder!")
             }
         }
-        let mut outpath = path::PathBuf::from(&assets_folder);
-        outpath.push(zipoutpath.strip_prefix(first_folder.clone())?);
+        let relative = zipoutpath.strip_prefix(first_folder.clone())?.to_path_buf();
+        let outpath = assets_folder.join(&relative);
         // Spoof end
 
+        if only.is_some_and(|only| !only.contains(&relative)) {
+            continue;
+        }
+
         {
             let comment = file.comment();
             if !comment.is_empty() {
@@ -196,25 +299,263 @@ pub fn download_assets() -> anyhow::Result<()> {
                     fs::create_dir_all(p)?;
                 }
             }
-            let mut outfile = fs::File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
+            let mut data = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data)?;
+            fs::write(&outpath, &data)?;
+            extracted.push((relative, calculate_sha256(&data)));
         }
     }
     extraction_info.pop(); // Removes \n from end
     tracing::debug!("{extraction_info}");
+    Ok(extracted)
+}
+
+pub fn write_manifest(path: &Path, files: &[(PathBuf, String)]) -> anyhow::Result<()> {
+    let manifest: String = files.iter()
+        .map(|(path, hash)| format!("{hash} {}\n", path.to_string_lossy().replace('\\', "/")))
+        .collect();
+    std::fs::write(path, manifest)?;
     Ok(())
 }
 
-pub async fn write_sha_to_file(sha: &str) -> anyhow::Result<()> {
-    let path = get_path_to_assets_hash();
+/// Returns the asset files that are missing or don't match the manifest
+pub async fn verify_assets(assets_folder: &Path, manifest: &Path) -> anyhow::Result<HashSet<PathBuf>> {
+    let manifest = fs::read_to_string(manifest).await?;
+    let mut broken = HashSet::new();
+    for line in manifest.lines().filter(|line| !line.is_empty()) {
+        let Some((hash, path)) = line.split_once(' ') else {
+            bail!("Invalid manifest line: {line}");
+        };
+        let path = PathBuf::from(path);
+        let valid = match fs::read(assets_folder.join(&path)).await {
+            Ok(data) => calculate_sha256(&data) == hash,
+            Err(_) => false,
+        };
+        if !valid {
+            tracing::warn!("Asset file {} is missing or corrupted", path.display());
+            broken.insert(path);
+        }
+    }
+    Ok(broken)
+}
 
-    let mut file = File::create(path).await?;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AssetsUpdate {
+    UpToDate,
+    Updated,
+}
+
+impl AssetsUpdate {
+    pub fn status(&self) -> &'static str {
+        match self {
+            AssetsUpdate::UpToDate => "up to date",
+            AssetsUpdate::Updated => "updated",
+        }
+    }
+}
+
+/// Downloads the assets of the commit unless `hash_file` says they are downloaded already.
+/// The commit is written only after every file is downloaded
+pub async fn update_assets(client: &Client, settings: &AssetsDownload, sha: &str, hash_file: &Path) -> anyhow::Result<AssetsUpdate> {
+    if !is_assets_outdated(hash_file, sha).await? {
+        return Ok(AssetsUpdate::UpToDate);
+    }
+    let files = stage_assets(Path::new(&*ASSETS_VAR), hash_file, |staging| async move {
+        if settings.concurrency == 0 {
+            let bytes = client.get(FIGURA_ASSETS_ZIP_URL).send().await?.error_for_status()?.bytes().await?;
+            tokio::task::spawn_blocking(move || extract_assets(std::io::Cursor::new(bytes), &staging, None)).await?
+        } else {
+            download_assets_concurrently(client, sha, settings, &staging).await
+        }
+    }).await?;
+    write_manifest(&get_path_to_assets_manifest(), &files)?;
+    let mut file = File::create(hash_file).await?;
     file.write_all(sha.as_bytes()).await?;
     file.flush().await?;
-    Ok(())
+    Ok(AssetsUpdate::Updated)
+}
+
+/// Folder next to `folder` with the suffix added to its name
+fn sibling(folder: &Path, suffix: &str) -> PathBuf {
+    // Without a trailing separator, so it isn't put inside
+    let mut name = folder.components().collect::<PathBuf>().into_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Downloads into a staging folder next to `assets_folder` and swaps it in only if the download succeeds,
+/// so a failed update keeps the assets being served. The commit in `hash_file` is forgotten before the swap
+async fn stage_assets<F, Fut>(assets_folder: &Path, hash_file: &Path, download: F) -> anyhow::Result<Vec<(PathBuf, String)>>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Vec<(PathBuf, String)>>>,
+{
+    let staging = sibling(assets_folder, ".staging");
+    let _ = fs::remove_dir_all(&staging).await;
+    fs::create_dir_all(&staging).await?;
+    let files = match download(staging.clone()).await {
+        Ok(files) => files,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging).await;
+            return Err(e);
+        },
+    };
+    match fs::remove_file(hash_file).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    let old = sibling(assets_folder, ".old");
+    let _ = fs::remove_dir_all(&old).await;
+    match fs::rename(assets_folder, &old).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    fs::rename(&staging, assets_folder).await?;
+    let _ = fs::remove_dir_all(&old).await;
+    Ok(files)
 }
 
 pub async fn remove_assets() {
     fs::remove_dir_all(&*ASSETS_VAR).await.unwrap_or_else(|err| tracing::debug!("Assets dir remove failed due {err:?}"));
     fs::remove_file(get_path_to_assets_hash()).await.unwrap_or_else(|err| tracing::debug!("Assets hash file remove failed due {err:?}"));
-}
\ No newline at end of file
+    fs::remove_file(get_path_to_assets_manifest()).await.unwrap_or_else(|err| tracing::debug!("Assets manifest remove failed due {err:?}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn current_assets_up_to_date() {
+        let hash_file = std::env::temp_dir().join(format!("sculptor-assets-commit-{}", rand::random::<u64>()));
+        fs::write(&hash_file, "abc123").await.unwrap();
+        // Nothing is downloaded
+        let update = update_assets(&Client::new(), &AssetsDownload::default(), "abc123", &hash_file).await.unwrap();
+        assert_eq!(update, AssetsUpdate::UpToDate);
+        assert_eq!(update.status(), "up to date");
+        fs::remove_file(&hash_file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_asset_repaired() {
+        let root = std::env::temp_dir().join(format!("sculptor-assets-{}", rand::random::<u64>()));
+        let files = [("a.json", &b"{}"[..]), ("dir/b.png", &b"png"[..]), ("c.txt", &b"text"[..])];
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        archive.add_directory("assets-main/", zip::write::SimpleFileOptions::default()).unwrap();
+        for (name, data) in files {
+            archive.start_file(format!("assets-main/{name}"), zip::write::SimpleFileOptions::default()).unwrap();
+            archive.write_all(data).unwrap();
+        }
+        let archive = archive.finish().unwrap().into_inner();
+
+        let assets = root.join("assets");
+        let manifest = root.join("assets_manifest");
+        let extracted = extract_assets(std::io::Cursor::new(&archive), &assets, None).unwrap();
+        assert_eq!(extracted.len(), files.len());
+        write_manifest(&manifest, &extracted).unwrap();
+        assert!(verify_assets(&assets, &manifest).await.unwrap().is_empty());
+
+        fs::write(assets.join("dir/b.png"), b"broken").await.unwrap();
+        fs::write(assets.join("c.txt"), b"edited").await.unwrap();
+        let broken = verify_assets(&assets, &manifest).await.unwrap();
+        assert_eq!(broken, HashSet::from([PathBuf::from("dir/b.png"), PathBuf::from("c.txt")]));
+
+        // Only the broken files are extracted again
+        fs::remove_file(assets.join("a.json")).await.unwrap();
+        let repaired = extract_assets(std::io::Cursor::new(&archive), &assets, Some(&broken)).unwrap();
+        assert_eq!(repaired.len(), 2);
+        assert!(!assets.join("a.json").exists());
+        assert_eq!(fs::read(assets.join("dir/b.png")).await.unwrap(), b"png");
+        assert_eq!(verify_assets(&assets, &manifest).await.unwrap(), HashSet::from([PathBuf::from("a.json")]));
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn assets_downloaded_concurrently() {
+        use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+        use axum::{extract::{Path as UrlPath, State}, http::StatusCode, routing::get, Router};
+
+        #[derive(Clone, Default)]
+        struct Source {
+            running: Arc<AtomicUsize>,
+            max_running: Arc<AtomicUsize>,
+            flaky_hits: Arc<AtomicUsize>,
+        }
+        async fn serve(UrlPath(name): UrlPath<String>, State(source): State<Source>) -> Result<String, StatusCode> {
+            if name == "broken" {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            // Fails the first time
+            if name == "flaky" && source.flaky_hits.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            let running = source.running.fetch_add(1, Ordering::SeqCst) + 1;
+            source.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            source.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(format!("content of {name}"))
+        }
+        let source = Source::default();
+        let app = Router::new().route("/:name", get(serve)).with_state(source.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let root = std::env::temp_dir().join(format!("sculptor-assets-{}", rand::random::<u64>()));
+        let files = |names: &[&str]| names.iter().map(|name| (PathBuf::from("dir").join(name), format!("{base}/{name}"))).collect::<Vec<_>>();
+        let mut names: Vec<String> = (0..11).map(|i| format!("file{i}")).collect();
+        names.push("flaky".to_string());
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let settings = AssetsDownload { concurrency: 3, retries: 1 };
+        let client = Client::new();
+
+        let downloaded = download_files(&client, files(&names), &root, &settings).await.unwrap();
+        assert_eq!(downloaded.len(), names.len());
+        assert_eq!(source.max_running.load(Ordering::SeqCst), 3);
+        assert_eq!(fs::read_to_string(root.join("dir/flaky")).await.unwrap(), "content of flaky");
+        assert!(downloaded.contains(&(PathBuf::from("dir/file3"), calculate_sha256(b"content of file3"))));
+
+        // The other files are still downloaded
+        fs::remove_dir_all(&root).await.unwrap();
+        assert!(download_files(&client, files(&["broken", "file0", "file1"]), &root, &settings).await.is_err());
+        assert!(root.join("dir/file1").exists());
+        assert!(download_files(&client, vec![(PathBuf::from("../outside"), format!("{base}/file0"))], &root, &settings).await.is_err());
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_update_keeps_assets() {
+        let root = std::env::temp_dir().join(format!("sculptor-assets-{}", rand::random::<u64>()));
+        let assets = root.join("assets");
+        let hash_file = root.join("assets_last_commit");
+        fs::create_dir_all(&assets).await.unwrap();
+        fs::write(assets.join("old.json"), b"old").await.unwrap();
+        fs::write(&hash_file, "old-commit").await.unwrap();
+
+        let failed = stage_assets(&assets, &hash_file, |staging| async move {
+            fs::write(staging.join("half.json"), b"half").await?;
+            bail!("connection lost")
+        }).await;
+        assert!(failed.is_err());
+        assert_eq!(fs::read(assets.join("old.json")).await.unwrap(), b"old");
+        assert!(!assets.join("half.json").exists());
+        assert!(!sibling(&assets, ".staging").exists());
+        assert_eq!(fs::read_to_string(&hash_file).await.unwrap(), "old-commit");
+
+        let files = stage_assets(&assets, &hash_file, |staging| async move {
+            fs::write(staging.join("new.json"), b"new").await?;
+            Ok(vec![(PathBuf::from("new.json"), calculate_sha256(b"new"))])
+        }).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(fs::read(assets.join("new.json")).await.unwrap(), b"new");
+        assert!(!assets.join("old.json").exists());
+        assert!(!hash_file.exists());
+        assert!(!sibling(&assets, ".old").exists());
+        assert_eq!(sibling(Path::new("data/assets/"), ".staging"), PathBuf::from("data/assets.staging"));
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::{sync::Mutex, time::Instant};
+use uuid::Uuid;
+
+/// Resumable uploads in progress by their id, the chunks are kept by `AvatarStore`
+#[derive(Debug, Clone, Default)]
+pub struct ChunkedUploads(Arc<DashMap<String, ChunkedUpload>>);
+
+#[derive(Debug, Clone)]
+pub struct ChunkedUpload {
+    pub owner: Uuid,
+    /// Declared total size
+    pub size: u64,
+    /// Declared hash, in the `user_info` format
+    pub hash: Option<String>,
+    /// Chunks received so far, the next one must have this number
+    pub chunks: u32,
+    pub received: u64,
+    touched: Instant,
+    /// Held while a chunk is checked and appended, or the upload is completed
+    pub lock: Arc<Mutex<()>>,
+}
+
+impl ChunkedUploads {
+    /// Starts an upload of the user, returns its id and the id of the upload it replaced
+    pub fn start(&self, owner: Uuid, size: u64, hash: Option<String>) -> (String, Option<String>) {
+        let replaced = self.0.iter().find(|upload| upload.owner == owner).map(|upload| upload.key().clone());
+        if let Some(id) = &replaced {
+            self.0.remove(id);
+        }
+        let id = format!("{:032x}", rand::random::<u128>());
+        self.0.insert(id.clone(), ChunkedUpload { owner, size, hash, chunks: 0, received: 0, touched: Instant::now(), lock: Arc::default() });
+        (id, replaced)
+    }
+    /// The upload if it belongs to the user
+    pub fn get(&self, id: &str, owner: &Uuid) -> Option<ChunkedUpload> {
+        self.0.get(id).filter(|upload| &upload.owner == owner).map(|upload| upload.clone())
+    }
+    /// Counts the appended chunk
+    pub fn received(&self, id: &str, len: u64) {
+        if let Some(mut upload) = self.0.get_mut(id) {
+            upload.chunks += 1;
+            upload.received += len;
+            upload.touched = Instant::now();
+        }
+    }
+    pub fn remove(&self, id: &str) -> Option<ChunkedUpload> {
+        self.0.remove(id).map(|(_, upload)| upload)
+    }
+    /// Forgets the uploads of the user, returns their ids
+    pub fn remove_owner(&self, owner: &Uuid) -> Vec<String> {
+        let ids: Vec<String> = self.0.iter().filter(|upload| &upload.owner == owner).map(|upload| upload.key().clone()).collect();
+        for id in &ids {
+            self.0.remove(id);
+        }
+        ids
+    }
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.contains_key(id)
+    }
+    /// Forgets the uploads without a chunk for `ttl`, returns their ids
+    pub fn expire(&self, ttl: Duration) -> Vec<String> {
+        let expired: Vec<String> = self.0.iter()
+            .filter(|upload| upload.touched.elapsed() >= ttl)
+            .map(|upload| upload.key().clone())
+            .collect();
+        for id in &expired {
+            self.0.remove(id);
+        }
+        expired
+    }
+}
+
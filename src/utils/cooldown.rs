@@ -0,0 +1,49 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Remembers when each user last performed an action
+#[derive(Debug, Clone, Default)]
+pub struct Cooldown(Arc<DashMap<Uuid, Instant>>);
+
+impl Cooldown {
+    /// Returns false if the user has performed the action less than `interval` ago
+    pub fn try_acquire(&self, uuid: Uuid, interval: Duration) -> bool {
+        let now = Instant::now();
+        // The users past their cooldown are forgotten
+        self.0.retain(|_, last| now.duration_since(*last) < interval);
+        let mut allowed = true;
+        self.0.entry(uuid)
+            .and_modify(|last| {
+                if now.duration_since(*last) < interval {
+                    allowed = false;
+                } else {
+                    *last = now;
+                }
+            })
+            .or_insert(now);
+        allowed
+    }
+    pub fn remove(&self, uuid: &Uuid) {
+        self.0.remove(uuid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_entries_removed() {
+        let cooldown = Cooldown::default();
+        let interval = Duration::from_secs(60);
+        assert!(cooldown.try_acquire(Uuid::from_u128(1), interval));
+        assert!(!cooldown.try_acquire(Uuid::from_u128(1), interval));
+        tokio::time::advance(interval).await;
+        assert!(cooldown.try_acquire(Uuid::from_u128(2), interval));
+        assert_eq!(cooldown.0.len(), 1);
+        assert!(cooldown.try_acquire(Uuid::from_u128(1), interval));
+    }
+}
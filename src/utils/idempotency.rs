@@ -0,0 +1,44 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// Keys of the requests that succeeded recently, per token
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyKeys(Arc<DashMap<(String, String), Instant>>);
+
+impl IdempotencyKeys {
+    /// The request with the key succeeded less than `ttl` ago
+    pub fn succeeded(&self, token: &str, key: &str, ttl: Duration) -> bool {
+        self.0.get(&(token.to_string(), key.to_string())).is_some_and(|at| at.elapsed() < ttl)
+    }
+    /// Remembers the successful request, forgetting the expired ones
+    pub fn remember(&self, token: &str, key: &str, ttl: Duration) {
+        self.0.retain(|_, at| at.elapsed() < ttl);
+        self.0.insert((token.to_string(), key.to_string()), Instant::now());
+    }
+    /// Forgets the keys of the tokens
+    pub fn forget(&self, tokens: &[String]) {
+        self.0.retain(|(token, _), _| !tokens.contains(token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_expire() {
+        let keys = IdempotencyKeys::default();
+        let ttl = Duration::from_secs(60);
+        keys.remember("token", "key", ttl);
+        assert!(keys.succeeded("token", "key", ttl));
+        // Keys of other tokens are separate
+        assert!(!keys.succeeded("other", "key", ttl));
+
+        tokio::time::advance(ttl).await;
+        assert!(!keys.succeeded("token", "key", ttl));
+        keys.remember("token", "another", ttl);
+        assert_eq!(keys.0.len(), 1);
+    }
+}
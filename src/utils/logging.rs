@@ -0,0 +1,77 @@
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::{self, time::ChronoLocal, MakeWriter}, registry::LookupSpan, Layer};
+
+use crate::state::LogFormat;
+
+pub fn log_timer() -> ChronoLocal {
+    ChronoLocal::new(String::from("%Y-%m-%dT%H:%M:%S%.3f%:z"))
+}
+
+/// Layer of the log file, JSON lines with the spans for log aggregators or the same format as the terminal
+pub fn file_log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_ansi(false) // Disable ANSI colors for file logs
+        .with_timer(log_timer())
+        .with_writer(writer);
+    match format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(true).boxed(),
+    }
+}
+
+/// Log writer keeping everything in memory, for the tests checking the log lines
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    pub(crate) fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::*;
+
+    #[test]
+    fn json_log_lines() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(file_log_layer(LogFormat::Json, move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("request", id = "abc").entered();
+            tracing::warn!(duration_ms = 1500, "Slow request");
+            tracing::info!("Second line");
+        });
+
+        let output = captured.output();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "WARN");
+        assert_eq!(lines[0]["fields"]["message"], "Slow request");
+        assert_eq!(lines[0]["fields"]["duration_ms"], 1500);
+        assert_eq!(lines[0]["span"]["id"], "abc");
+        assert_eq!(lines[0]["spans"][0]["name"], "request");
+        assert!(lines[0]["timestamp"].is_string());
+        assert!(lines[0]["target"].is_string());
+    }
+}
@@ -1,7 +1,29 @@
 mod auxiliary;
+mod avatars;
+mod chunked;
+mod cooldown;
 mod check_updates;
+mod idempotency;
+mod logging;
 mod motd;
+mod origins;
+mod share;
+mod snapshot;
+mod surge;
+mod throttle;
+mod webhooks;
 
 pub use auxiliary::*;
+pub use avatars::*;
+pub use chunked::*;
+pub use cooldown::*;
+pub use idempotency::*;
+pub use logging::*;
 pub use motd::*;
+pub use origins::*;
+pub use share::*;
+pub use snapshot::*;
+pub use surge::*;
+pub use throttle::*;
+pub use webhooks::*;
 pub use check_updates::*;
\ No newline at end of file
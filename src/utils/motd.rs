@@ -1,8 +1,14 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::bail;
 use chrono::Duration;
+use rand::distributions::{Distribution as _, WeightedIndex};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 use tracing::error;
 
-use crate::AppState;
+use crate::{state::{CMotdRotation, RotationMode}, AppState};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,12 +29,29 @@ pub struct ClickEvent {
     pub value: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct RemoteMotd {
+    fetched: Instant,
+    /// None if the last fetch failed
+    motd: Option<Vec<Motd>>,
+}
+
 pub async fn get_motd(state: AppState) -> Vec<Motd> {
-    let motd_settings = &state.config.read().await.motd;
-    
-    let custom: Result<Vec<Motd>, serde_json::Error> = serde_json::from_str(&motd_settings.custom_text).map_err(|e| { error!("Can't parse custom MOTD!\n{e:?}"); e});
+    let motd_settings = state.config.read().await.motd.clone();
+
+    let remote = match &motd_settings.remote_url {
+        Some(url) => get_remote_motd(&state, url, motd_settings.remote_ttl).await,
+        None => None,
+    };
+    let custom: Result<Vec<Motd>, serde_json::Error> = match remote {
+        Some(remote) => Ok(remote),
+        None => serde_json::from_str(&motd_settings.custom_text).map_err(|e| { error!("Can't parse custom MOTD!\n{e:?}"); e}),
+    };
+    let rotated = rotate_motd(&motd_settings.rotation, &state.motd_rotation)
+        .map(|entry| entry.to_vec())
+        .unwrap_or_default();
     if !motd_settings.display_server_info {
-        return custom.unwrap();
+        return [custom.unwrap(), rotated].concat();
     }
 
     // let time = Local::now().format("%H:%M");
@@ -62,8 +85,120 @@ pub async fn get_motd(state: AppState) -> Vec<Motd> {
     }
 
     if let Ok(custom) = custom {
-        [ser_info, custom].concat()
+        [ser_info, custom, rotated].concat()
     } else {
-        ser_info
+        [ser_info, rotated].concat()
+    }
+}
+
+async fn get_remote_motd(state: &AppState, url: &str, ttl: u64) -> Option<Vec<Motd>> {
+    if let Some(cached) = &*state.remote_motd.read().await {
+        if cached.fetched.elapsed().as_secs() < ttl {
+            return cached.motd.clone();
+        }
+    }
+    // Failures are cached too, so an unreachable source doesn't slow down every request
+    let motd = fetch_remote_motd(&state.http, url).await
+        .inspect_err(|e| error!("Can't fetch remote MOTD, using local one. Reason: {e:?}"))
+        .ok();
+    *state.remote_motd.write().await = Some(RemoteMotd { fetched: Instant::now(), motd: motd.clone() });
+    motd
+}
+
+async fn fetch_remote_motd(client: &Client, url: &str) -> anyhow::Result<Vec<Motd>> {
+    let response = client.get(url).send().await?;
+
+    if response.status().is_success() {
+        // Deserializing validates the shape of the MOTD
+        let motd: Vec<Motd> = response.json().await?;
+        if motd.is_empty() {
+            bail!("Remote MOTD is empty")
+        }
+        Ok(motd)
+    } else {
+        bail!("Response status code: {}", response.status().as_u16())
+    }
+}
+
+/// Picks the next rotation entry, None if there are no entries or none can be picked by the weights
+pub fn rotate_motd<'a>(rotation: &'a CMotdRotation, position: &AtomicUsize) -> Option<&'a [Motd]> {
+    if rotation.entries.is_empty() {
+        return None;
+    }
+    let index = match rotation.mode {
+        RotationMode::RoundRobin => position.fetch_add(1, Ordering::Relaxed) % rotation.entries.len(),
+        RotationMode::Random => {
+            let weights = WeightedIndex::new(rotation.entries.iter().map(|entry| entry.weight)).ok()?;
+            weights.sample(&mut rand::thread_rng())
+        },
+    };
+    Some(&rotation.entries[index].components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::CMotdEntry;
+
+    fn rotation(mode: RotationMode, texts: &[&str]) -> CMotdRotation {
+        CMotdRotation {
+            mode,
+            entries: texts.iter().map(|text| CMotdEntry {
+                components: vec![Motd { text: text.to_string(), ..Default::default() }],
+                weight: 1.0,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn round_robin_rotation() {
+        let rotation = rotation(RotationMode::RoundRobin, &["a", "b", "c"]);
+        let position = AtomicUsize::new(0);
+        let texts: Vec<String> = (0..4)
+            .map(|_| rotate_motd(&rotation, &position).unwrap()[0].text.clone())
+            .collect();
+        assert_eq!(texts, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn random_rotation() {
+        let rotation = rotation(RotationMode::Random, &["a", "b"]);
+        let position = AtomicUsize::new(0);
+        for _ in 0..32 {
+            let text = &rotate_motd(&rotation, &position).unwrap()[0].text;
+            assert!(text == "a" || text == "b");
+        }
+        assert_eq!(position.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn weighted_rotation() {
+        let mut rotation = rotation(RotationMode::Random, &["common", "rare", "never"]);
+        for (entry, weight) in rotation.entries.iter_mut().zip([3.0, 1.0, 0.0]) {
+            entry.weight = weight;
+        }
+        let position = AtomicUsize::new(0);
+        let picks: Vec<String> = (0..4000).map(|_| rotate_motd(&rotation, &position).unwrap()[0].text.clone()).collect();
+        let count = |text: &str| picks.iter().filter(|pick| *pick == text).count();
+        assert!((2700..3300).contains(&count("common")), "{}", count("common"));
+        assert!((700..1300).contains(&count("rare")), "{}", count("rare"));
+        assert_eq!(count("never"), 0);
+    }
+
+    #[test]
+    fn negative_weight_rejected() {
+        let mut config = crate::AppState::for_tests().config.blocking_read().clone();
+        config.motd.rotation = rotation(RotationMode::Random, &["a", "b"]);
+        assert!(config.validate().is_ok());
+        config.motd.rotation.entries[1].weight = -1.0;
+        assert!(config.validate().is_err());
+        config.motd.rotation.entries.iter_mut().for_each(|entry| entry.weight = 0.0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn empty_rotation() {
+        let rotation = rotation(RotationMode::Random, &[]);
+        assert!(rotate_motd(&rotation, &AtomicUsize::new(0)).is_none());
     }
 }
\ No newline at end of file
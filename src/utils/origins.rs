@@ -0,0 +1,104 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use ipnet::IpNet;
+use tokio::time::Instant;
+
+const UPLOAD_WINDOW: Duration = Duration::from_secs(60);
+
+/// Connections and uploads of each origin, see `Origins` in the config
+#[derive(Debug, Clone, Default)]
+pub struct OriginLimiter {
+    connections: Arc<DashMap<IpNet, usize>>,
+    uploads: Arc<DashMap<IpNet, (Instant, u32)>>,
+}
+
+/// Counts the connection until it's dropped
+#[derive(Debug)]
+pub struct OriginGuard {
+    connections: Arc<DashMap<IpNet, usize>>,
+    origin: IpNet,
+}
+
+impl Drop for OriginGuard {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.connections.get_mut(&self.origin) {
+            *count = count.saturating_sub(1);
+        }
+        self.connections.remove_if(&self.origin, |_, count| *count == 0);
+    }
+}
+
+impl OriginLimiter {
+    /// Returns None if the origin already has `max` connections
+    pub fn connect(&self, origin: IpNet, max: Option<usize>) -> Option<OriginGuard> {
+        let mut count = self.connections.entry(origin).or_default();
+        if max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(OriginGuard { connections: Arc::clone(&self.connections), origin })
+    }
+    /// Returns false if the origin already uploaded `per_minute` avatars in the current minute
+    pub fn try_upload(&self, origin: IpNet, per_minute: Option<u32>) -> bool {
+        let Some(per_minute) = per_minute else { return true };
+        let now = Instant::now();
+        // The windows of the other origins expire here too
+        self.uploads.retain(|_, window| now.duration_since(window.0) < UPLOAD_WINDOW);
+        let mut window = self.uploads.entry(origin).or_insert((now, 0));
+        if window.1 >= per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+    /// Gives back the upload counted by `try_upload` if it wasn't accepted
+    pub fn refund_upload(&self, origin: IpNet) {
+        if let Some(mut window) = self.uploads.get_mut(&origin) {
+            window.1 = window.1.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::state::Origins;
+
+    #[test]
+    fn subnet_shares_bucket() {
+        let origins = Origins { buckets: vec!["203.0.113.0/24".parse().unwrap()], max_connections: Some(2), ..Default::default() };
+        let origin = |ip: &str| origins.origin(ip.parse::<IpAddr>().unwrap());
+        let limiter = OriginLimiter::default();
+
+        let first = limiter.connect(origin("203.0.113.1"), origins.max_connections).unwrap();
+        let _second = limiter.connect(origin("203.0.113.2"), origins.max_connections).unwrap();
+        assert!(limiter.connect(origin("203.0.113.3"), origins.max_connections).is_none());
+        // Addresses outside of the buckets are limited alone
+        assert!(limiter.connect(origin("198.51.100.1"), origins.max_connections).is_some());
+        assert_eq!(origin("198.51.100.1"), "198.51.100.1/32".parse::<IpNet>().unwrap());
+
+        drop(first);
+        assert!(limiter.connect(origin("203.0.113.3"), origins.max_connections).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn uploads_limited_per_minute() {
+        let limiter = OriginLimiter::default();
+        let origin: IpNet = "203.0.113.0/24".parse().unwrap();
+        assert!(limiter.try_upload(origin, Some(2)));
+        assert!(limiter.try_upload(origin, Some(2)));
+        assert!(!limiter.try_upload(origin, Some(2)));
+        assert!(limiter.try_upload(origin, None));
+        limiter.refund_upload(origin);
+        assert!(limiter.try_upload(origin, Some(2)));
+        assert!(!limiter.try_upload(origin, Some(2)));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.try_upload("198.51.100.1/32".parse().unwrap(), Some(2)));
+        // The expired window is dropped
+        assert_eq!(limiter.uploads.len(), 1);
+        assert!(limiter.try_upload(origin, Some(2)));
+    }
+}
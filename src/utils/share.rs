@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use ring::{hmac, rand::SystemRandom};
+use uuid::Uuid;
+
+/// Signs and checks the avatar share tokens `<uuid>.<avatar id>.<issued>.<expires>.<signature>`
+#[derive(Debug, Clone)]
+pub struct ShareLinks {
+    /// Used if `sharing.key` isn't set, so the links are valid until the restart
+    fallback: hmac::Key,
+    /// Links of these users issued up to the time are rejected, kept until the restart
+    revoked: Arc<DashMap<Uuid, i64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareError {
+    Invalid,
+    Expired,
+    Revoked,
+}
+
+impl Default for ShareLinks {
+    fn default() -> Self {
+        Self {
+            fallback: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).expect("can't generate the share key"),
+            revoked: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+impl ShareLinks {
+    fn key(&self, secret: Option<&str>) -> hmac::Key {
+        match secret {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => self.fallback.clone(),
+        }
+    }
+    /// Token of the avatar issued at the `issued` unix time and valid until the `expires` one
+    pub fn sign(&self, secret: Option<&str>, uuid: &Uuid, id: &str, issued: i64, expires: i64) -> String {
+        let payload = format!("{}.{id}.{issued}.{expires}", uuid.simple());
+        let tag = hmac::sign(&self.key(secret), payload.as_bytes());
+        format!("{payload}.{}", faster_hex::hex_string(tag.as_ref()))
+    }
+    /// Owner and avatar id of the token, if it was signed by us and hasn't expired at `now`
+    pub fn verify(&self, secret: Option<&str>, token: &str, now: i64) -> Result<(Uuid, String), ShareError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(ShareError::Invalid)?;
+        let mut tag = vec![0; signature.len() / 2];
+        faster_hex::hex_decode(signature.as_bytes(), &mut tag).map_err(|_| ShareError::Invalid)?;
+        hmac::verify(&self.key(secret), payload.as_bytes(), &tag).map_err(|_| ShareError::Invalid)?;
+
+        let mut parts = payload.splitn(4, '.');
+        let (Some(uuid), Some(id), Some(issued), Some(expires)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(ShareError::Invalid);
+        };
+        let uuid = Uuid::try_parse(uuid).map_err(|_| ShareError::Invalid)?;
+        let issued: i64 = issued.parse().map_err(|_| ShareError::Invalid)?;
+        let expires: i64 = expires.parse().map_err(|_| ShareError::Invalid)?;
+        if now >= expires {
+            return Err(ShareError::Expired);
+        }
+        if self.revoked.get(&uuid).is_some_and(|revoked| issued <= *revoked) {
+            return Err(ShareError::Revoked);
+        }
+        Ok((uuid, id.to_string()))
+    }
+    /// Rejects the links of the user issued up to `now`, so they don't resolve to a new avatar
+    pub fn revoke(&self, uuid: &Uuid, now: i64) {
+        self.revoked.insert(*uuid, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_tokens_checked() {
+        let links = ShareLinks::default();
+        let uuid = Uuid::from_u128(1);
+        let token = links.sign(None, &uuid, "avatar", 0, 100);
+        assert_eq!(links.verify(None, &token, 99), Ok((uuid, "avatar".to_string())));
+        assert_eq!(links.verify(None, &token, 100), Err(ShareError::Expired));
+
+        // Another expiry, owner or key
+        assert_eq!(links.verify(None, &token.replacen(".100.", ".200.", 1), 99), Err(ShareError::Invalid));
+        let other = token.replacen(&uuid.simple().to_string(), &Uuid::from_u128(2).simple().to_string(), 1);
+        assert_eq!(links.verify(None, &other, 99), Err(ShareError::Invalid));
+        assert_eq!(links.verify(Some("secret"), &token, 99), Err(ShareError::Invalid));
+        assert_eq!(ShareLinks::default().verify(None, &token, 99), Err(ShareError::Invalid));
+        for broken in ["", "abc", "a.b.c.zz"] {
+            assert_eq!(links.verify(None, broken, 0), Err(ShareError::Invalid));
+        }
+
+        // The configured secret survives restarts
+        let token = links.sign(Some("secret"), &uuid, "avatar", 0, 100);
+        assert!(ShareLinks::default().verify(Some("secret"), &token, 99).is_ok());
+
+        // Only the links issued before the revocation
+        links.revoke(&uuid, 10);
+        assert_eq!(links.verify(Some("secret"), &token, 99), Err(ShareError::Revoked));
+        let token = links.sign(None, &uuid, "avatar", 11, 100);
+        assert!(links.verify(None, &token, 99).is_ok());
+    }
+}
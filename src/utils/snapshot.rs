@@ -0,0 +1,116 @@
+use std::{ffi::OsString, io, path::{Path, PathBuf}, time::Duration};
+
+use tokio::{fs, io::AsyncWriteExt as _};
+
+use crate::{auth::UManagerSnapshot, AppState};
+
+/// The snapshot written before the latest one
+fn previous(path: &Path) -> PathBuf {
+    let mut previous = OsString::from(path.as_os_str());
+    previous.push(".prev");
+    PathBuf::from(previous)
+}
+
+/// Writes the snapshot next to the target and renames it into place, keeping the older one as `.prev`
+pub async fn write_snapshot(path: &Path, snapshot: &UManagerSnapshot) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let data = serde_json::to_vec(snapshot)?;
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(format!(".tmp-{:016x}", rand::random::<u64>()));
+    let temp = PathBuf::from(temp);
+    if let Err(e) = write_private(&temp, &data).await {
+        let _ = fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    match fs::rename(path, previous(path)).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => tracing::warn!("Can't keep the previous snapshot due: {e:?}"),
+        _ => (),
+    }
+    fs::rename(&temp, path).await
+}
+
+/// The snapshot holds the live tokens, so only the owner may read it
+async fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+/// The latest snapshot that can be read
+pub async fn read_snapshot(path: &Path) -> Option<UManagerSnapshot> {
+    for path in [path.to_path_buf(), previous(path)] {
+        match fs::read(&path).await {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(snapshot) => return Some(snapshot),
+                Err(e) => tracing::warn!("Snapshot {} is broken: {e}", path.display()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => tracing::warn!("Can't read snapshot {} due: {e:?}", path.display()),
+        }
+    }
+    None
+}
+
+/// Writes the snapshot of the users every `snapshots.interval` seconds while it's set
+pub async fn snapshot_users(state: AppState) {
+    loop {
+        let snapshots = state.config.read().await.snapshots.clone();
+        let Some(interval) = snapshots.interval else {
+            // Checked again in case it gets enabled
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue
+        };
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        match write_snapshot(&snapshots.path, &state.user_manager.snapshot()).await {
+            Ok(()) => tracing::trace!("Snapshot written"),
+            Err(e) => tracing::error!("Can't write snapshot due: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{auth::{BanInfo, BanSource, Userinfo}, UManager};
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("sculptor-snapshot-{}", rand::random::<u64>())).join("snapshot.json");
+        let users = UManager::new();
+        let (uuid, banned) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        users.insert(uuid, "token".to_string(), Userinfo { uuid, nickname: "Steve".to_string(), ..Default::default() }).unwrap();
+        users.put_upload_state(uuid, false);
+        users.put_request_temp_state(uuid, true);
+        users.ban(&Userinfo { uuid: banned, ..Default::default() }, BanInfo { reason: Some("griefing".to_string()), ..Default::default() });
+        // Rebuilt by its own sync
+        users.ban(&Userinfo { uuid: Uuid::from_u128(3), ..Default::default() }, BanInfo { source: BanSource::Minecraft, ..Default::default() });
+        write_snapshot(&path, &users.snapshot()).await.unwrap();
+
+        let restored = UManager::new();
+        restored.restore(read_snapshot(&path).await.unwrap());
+        assert_eq!(restored.get(&"token".to_string()).unwrap().nickname, "Steve");
+        assert!(!restored.upload_state(uuid, true));
+        assert!(restored.request_temp_state(uuid, false));
+        assert_eq!(restored.bans().len(), 1);
+        assert_eq!(restored.bans()[0].1.reason.as_deref(), Some("griefing"));
+
+        // A broken snapshot falls back to the previous one
+        write_snapshot(&path, &UManager::new().snapshot()).await.unwrap();
+        fs::write(&path, b"{\"authenticated\":").await.unwrap();
+        assert_eq!(read_snapshot(&path).await.unwrap().registered.len(), 3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            assert_eq!(fs::metadata(&path).await.unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(path.parent().unwrap()).await.unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use tokio::time::Instant;
+
+/// Authentications and WebSocket upgrades of the current second, see `ReconnectSurge` in the config
+#[derive(Debug, Clone)]
+pub struct SurgeLimiter {
+    window: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl Default for SurgeLimiter {
+    fn default() -> Self {
+        Self { window: Arc::new(Mutex::new((Instant::now(), 0))) }
+    }
+}
+
+impl SurgeLimiter {
+    /// Returns false if `per_second` clients were already let in during the current second
+    pub fn admit(&self, per_second: Option<u32>) -> bool {
+        let Some(per_second) = per_second else { return true };
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 >= per_second {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn surge_reset_every_second() {
+        let limiter = SurgeLimiter::default();
+        assert!((0..100).all(|_| limiter.admit(None)));
+        assert_eq!((0..10).filter(|_| limiter.admit(Some(4))).count(), 4);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.admit(Some(4)));
+    }
+}
@@ -0,0 +1,42 @@
+use std::{io, time::Duration};
+
+use axum::body::Bytes;
+use futures_util::{stream, Stream, StreamExt as _};
+use tokio::{io::AsyncRead, time::Instant};
+use tokio_util::io::ReaderStream;
+
+/// Reads in chunks of at most this size, so the buffered part of a download stays small
+pub const DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+/// Streams the reader in chunks, if `bytes_per_sec` is set it takes about `len / bytes_per_sec` seconds
+pub fn throttle<R: AsyncRead>(reader: R, bytes_per_sec: Option<u64>) -> impl Stream<Item = io::Result<Bytes>> {
+    let bytes_per_sec = bytes_per_sec.map(|rate| rate.max(1));
+    let chunk = bytes_per_sec.map_or(DOWNLOAD_CHUNK, |rate| (rate / 10).clamp(1, DOWNLOAD_CHUNK as u64) as usize);
+    let start = Instant::now();
+    stream::unfold((Box::pin(ReaderStream::with_capacity(reader, chunk)), 0u64), move |(mut chunks, sent)| async move {
+        if let Some(rate) = bytes_per_sec {
+            tokio::time::sleep_until(start + Duration::from_secs_f64(sent as f64 / rate as f64)).await;
+        }
+        let chunk = chunks.next().await?;
+        let sent = sent + chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+        Some((chunk, (chunks, sent)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn download_respects_rate() {
+        let data = vec![1u8; 100_000];
+        let start = Instant::now();
+        let body = Body::from_stream(throttle(io::Cursor::new(data.clone()), Some(10_000)));
+        assert_eq!(axum::body::to_bytes(body, usize::MAX).await.unwrap(), data);
+        // 100 KB at 10 KB/s, the first chunk is sent right away
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((9.5..=10.5).contains(&elapsed), "took {elapsed}s");
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{sync::{mpsc, RwLock}, time::{Duration, Instant}};
+
+use crate::state::Config;
+
+/// Events waiting for the dispatcher, further ones are dropped
+const QUEUE_SIZE: usize = 64;
+/// Failed authentications per minute reported as a surge
+pub const AUTH_FAILURE_SURGE: u32 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    UserBanned,
+    /// A temporary ban expired
+    UserUnbanned,
+    AvatarUploaded,
+    AuthFailureSurge,
+    AssetsUpdated,
+    /// An upload failed because the disk is full
+    StorageFull,
+    /// The assets couldn't be updated to the latest commit
+    AssetsUpdateFailed,
+    /// banned-players.json couldn't be parsed, the previous bans are kept
+    BanListInvalid,
+}
+
+impl WebhookEvent {
+    fn describe(&self, data: &Value) -> String {
+        let field = |name: &str| data.get(name).and_then(Value::as_str).unwrap_or("unknown").to_string();
+        match self {
+            WebhookEvent::UserBanned => format!("{} ({}) was banned", field("nickname"), field("uuid")),
+            WebhookEvent::UserUnbanned => format!("The ban of {} ({}) expired", field("nickname"), field("uuid")),
+            WebhookEvent::AvatarUploaded => format!("{} ({}) uploaded an avatar", field("nickname"), field("uuid")),
+            WebhookEvent::AuthFailureSurge => format!("{} failed authentications in the last minute", data["failures"]),
+            WebhookEvent::AssetsUpdated => format!("Assets updated to {}", field("commit")),
+            WebhookEvent::StorageFull => format!("The avatar storage is full, an upload of {} was rejected", field("uuid")),
+            WebhookEvent::AssetsUpdateFailed => format!("Assets can't be updated: {}", field("error")),
+            WebhookEvent::BanListInvalid => format!("banned-players.json can't be parsed: {}", field("error")),
+        }
+    }
+}
+
+/// Queue of the events for `dispatch_webhooks`
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    tx: mpsc::Sender<(WebhookEvent, Value)>,
+    auth_failures: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl Webhooks {
+    pub fn new() -> (Self, mpsc::Receiver<(WebhookEvent, Value)>) {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        (Self { tx, auth_failures: Arc::new(Mutex::new((Instant::now(), 0))) }, rx)
+    }
+    /// Queues the event without waiting, it's dropped if the queue is full
+    pub fn notify(&self, event: WebhookEvent, data: Value) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send((event, data)) {
+            tracing::warn!("[Webhooks] Queue is full, {:?} dropped", event);
+        }
+    }
+    /// Counts a failed authentication, the surge is reported once per minute
+    pub fn auth_failed(&self) {
+        let failures = {
+            let mut window = self.auth_failures.lock().unwrap();
+            let now = Instant::now();
+            if now.duration_since(window.0) >= Duration::from_secs(60) {
+                *window = (now, 0);
+            }
+            window.1 += 1;
+            window.1
+        };
+        if failures == AUTH_FAILURE_SURGE {
+            self.notify(WebhookEvent::AuthFailureSurge, json!({ "failures": failures }));
+        }
+    }
+}
+
+/// Posts the queued events to the webhooks from the config that accept them
+pub async fn dispatch_webhooks(mut rx: mpsc::Receiver<(WebhookEvent, Value)>, config: Arc<RwLock<Config>>, client: Client) {
+    while let Some((event, data)) = rx.recv().await {
+        let urls: Vec<String> = config.read().await.webhooks.iter()
+            .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&event))
+            .map(|webhook| webhook.url.clone())
+            .collect();
+        if urls.is_empty() {
+            continue;
+        }
+        let message = event.describe(&data);
+        // "content" is shown by Discord, "text" by Slack
+        let payload = json!({
+            "event": event,
+            "time": chrono::Utc::now(),
+            "data": data,
+            "content": message,
+            "text": message,
+        });
+        for (index, url) in urls.into_iter().enumerate() {
+            // URLs contain secrets, so they aren't logged
+            match client.post(url).json(&payload).send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => tracing::debug!("[Webhooks] {:?} sent to webhook #{}", event, index),
+                Err(e) => tracing::warn!("[Webhooks] Can't send {:?} to webhook #{} due: {}", event, index, e.without_url()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::State, routing::post, Json, Router};
+
+    use super::*;
+    use crate::state::Webhook;
+
+    #[tokio::test]
+    async fn events_posted_to_webhooks() {
+        let (received_tx, mut received) = mpsc::channel::<Value>(4);
+        let app = Router::new()
+            .route("/hook", post(|State(tx): State<mpsc::Sender<Value>>, Json(body): Json<Value>| async move { tx.send(body).await.unwrap() }))
+            .with_state(received_tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = crate::AppState::for_tests().config.read().await.clone();
+        config.webhooks = vec![Webhook { url, events: vec![WebhookEvent::UserBanned] }];
+        let (webhooks, rx) = Webhooks::new();
+        tokio::spawn(dispatch_webhooks(rx, Arc::new(RwLock::new(config)), reqwest::Client::new()));
+
+        webhooks.notify(WebhookEvent::AvatarUploaded, json!({ "uuid": "1", "nickname": "Tester" }));
+        webhooks.notify(WebhookEvent::UserBanned, json!({ "uuid": "2", "nickname": "Griefer" }));
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        assert_eq!(body["event"], "user-banned");
+        assert_eq!(body["data"]["nickname"], "Griefer");
+        assert_eq!(body["content"], "Griefer (2) was banned");
+        // The filtered event was never sent
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn auth_failure_surge_reported_once() {
+        let (webhooks, mut rx) = Webhooks::new();
+        for _ in 0..AUTH_FAILURE_SURGE * 2 {
+            webhooks.auth_failed();
+        }
+        assert!(matches!(rx.try_recv(), Ok((WebhookEvent::AuthFailureSurge, _))));
+        assert!(rx.try_recv().is_err());
+    }
+}